@@ -1,30 +1,77 @@
+#[cfg(feature = "serde")]
 use crate::rete::{
-    item::{Condition, Production, Wme},
+    explain::ConditionExplanation,
+    item::Predicate,
+    node::Node,
+    snapshot::{RestoreError, Snapshot},
+};
+use crate::rete::{
+    item::{validate_conditions, Activation, Condition, ConditionError, Production, Wme},
     Rete,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt::Display,
     hash::Hash,
     sync::mpsc::{channel, Receiver, Sender},
 };
 
+pub mod json;
+pub mod loader;
+
 pub type ProductionAction = Box<dyn Fn(&mut Engine, &[usize])>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EngineElement {
     /// Used for modifying WMEs
     rete_id: usize,
 
-    ///
-    fields: [usize; 3],
+    /// The fields of the WME as they were added to the rete
+    fields: Vec<usize>,
+}
+
+impl EngineElement {
+    pub fn rete_id(&self) -> usize {
+        self.rete_id
+    }
+
+    pub fn fields(&self) -> &[usize] {
+        &self.fields
+    }
+}
+
+/// A WME asserted logically from a production action. It stays in the rete as long as
+/// at least one of the matches that asserted it still holds.
+#[derive(Debug)]
+pub struct LogicalFact {
+    pub rete_id: usize,
+
+    /// IDs of the production tokens justifying this fact
+    pub support: HashSet<usize>,
 }
 
 pub struct Engine {
     pub rete: Rete,
     pub elements: HashMap<usize, Vec<EngineElement>>,
-    pub prod_sender: Sender<usize>,
-    pub production_queue: Receiver<usize>,
+    pub prod_sender: Sender<Activation>,
+    pub production_queue: Receiver<Activation>,
     pub production_map: HashMap<usize, Rule>,
+
+    /// Maps the names of named rules to their production IDs
+    pub rule_names: HashMap<String, usize>,
+
+    /// Maps the fields of logically asserted WMEs to their support
+    pub logical_facts: HashMap<Vec<usize>, LogicalFact>,
+
+    /// Maps production tokens to the fields of the logical facts they support
+    pub token_support: HashMap<usize, Vec<Vec<usize>>>,
+
+    /// The activation whose action is currently being executed
+    current_activation: Option<Activation>,
+
+    /// The elements as they were when the transaction in progress began
+    transaction_elements: Option<HashMap<usize, Vec<EngineElement>>>,
 }
 
 impl Default for Engine {
@@ -36,6 +83,11 @@ impl Default for Engine {
             prod_sender: tx,
             production_queue: rx,
             production_map: HashMap::new(),
+            rule_names: HashMap::new(),
+            logical_facts: HashMap::new(),
+            token_support: HashMap::new(),
+            current_activation: None,
+            transaction_elements: None,
         }
     }
 }
@@ -45,33 +97,494 @@ impl Engine {
         let engine_id = element.id();
 
         for wme in element.to_wmes() {
-            let fields = wme.fields;
+            let fields = wme.fields.clone();
             let rete_id = self.rete.add_wme(wme);
 
             self.elements
                 .entry(engine_id)
-                .and_modify(|e| e.push(EngineElement { rete_id, fields }))
-                .or_insert_with(|| vec![EngineElement { rete_id, fields }]);
+                .or_default()
+                .push(EngineElement { rete_id, fields });
+        }
+    }
+
+    /// Returns the activation whose action is currently being executed, which can be
+    /// used to [explain][Rete::explain] the match from within the action.
+    pub fn current_activation(&self) -> Option<Activation> {
+        self.current_activation.clone()
+    }
+
+    /// Removes all WMEs of the element with the given ID from the rete.
+    pub fn remove_element(&mut self, engine_id: usize) {
+        let Some(elements) = self.elements.remove(&engine_id) else {
+            return;
+        };
+
+        for element in elements {
+            self.rete.remove_wme(element.rete_id);
+        }
+    }
+
+    /// Adds a WME supported by the match of the production whose action is currently
+    /// executing. Once every match that asserted it is retracted, so is the WME.
+    /// Asserting the same fields from multiple matches only adds a single WME.
+    ///
+    /// Returns the rete ID of the WME. Panics when called outside of a production action.
+    pub fn add_logical_wme(&mut self, wme: Wme) -> usize {
+        let Some(ref activation) = self.current_activation else {
+            panic!("Logical WMEs can only be added from production actions")
+        };
+        let token = activation.token();
+
+        let fields = wme.fields.clone();
+
+        let fact = match self.logical_facts.get_mut(&fields) {
+            Some(fact) => fact,
+            None => {
+                let rete_id = self.rete.add_wme(wme);
+                self.logical_facts
+                    .entry(fields.clone())
+                    .or_insert(LogicalFact {
+                        rete_id,
+                        support: HashSet::new(),
+                    })
+            }
+        };
+
+        if fact.support.insert(token) {
+            self.token_support.entry(token).or_default().push(fields);
+        }
+
+        fact.rete_id
+    }
+
+    /// Starts a transaction. Elements added and removed until the transaction is committed
+    /// are applied to the rete at once, see [Rete::begin_transaction].
+    ///
+    /// Production actions should not be run while a transaction is in progress.
+    pub fn begin_transaction(&mut self) {
+        self.rete.begin_transaction();
+        self.transaction_elements = Some(self.elements.clone());
+    }
+
+    /// Applies the elements added and removed during the transaction to the rete.
+    /// Returns `false` if no transaction was in progress.
+    pub fn commit_transaction(&mut self) -> bool {
+        self.transaction_elements = None;
+        self.rete.commit_transaction()
+    }
+
+    /// Discards the elements added and removed during the transaction.
+    /// Returns `false` if no transaction was in progress.
+    pub fn rollback_transaction(&mut self) -> bool {
+        if let Some(elements) = self.transaction_elements.take() {
+            self.elements = elements;
+        }
+        self.rete.rollback_transaction()
+    }
+
+    /// Adds a rule to the engine. Returns the ID of the rule's production, or an error leaving
+    /// the engine unchanged if a rule with the same name already exists or the rule's conditions
+    /// are invalid.
+    pub fn add_rule(&mut self, rule: Rule) -> Result<usize, RuleError> {
+        let mut rete_prod = Production::new(&rule.conditions, self.prod_sender.clone());
+
+        if let Some(ref name) = rule.metadata.name {
+            if self.rule_names.contains_key(name) {
+                return Err(RuleError::DuplicateName(name.clone()));
+            }
+            rete_prod = rete_prod.with_name(name.as_str());
+        }
+
+        let id = self
+            .rete
+            .add_production(rete_prod)
+            .map_err(RuleError::InvalidConditions)?;
+
+        if let Some(ref name) = rule.metadata.name {
+            self.rule_names.insert(name.clone(), id);
         }
+        self.production_map.insert(id, rule);
+
+        Ok(id)
+    }
+
+    /// Removes the rule with the given production ID from the engine and returns it.
+    /// The logical facts asserted by its matches get retracted on the next
+    /// [activation][Engine::activate_productions].
+    pub fn remove_rule(&mut self, id: usize) -> Option<Rule> {
+        let rule = self.production_map.remove(&id)?;
+
+        if let Some(ref name) = rule.metadata.name {
+            self.rule_names.remove(name);
+        }
+
+        self.rete.remove_production(id);
+
+        Some(rule)
+    }
+
+    pub fn remove_rule_by_name(&mut self, name: &str) -> Option<Rule> {
+        let id = self.rule_id(name)?;
+        self.remove_rule(id)
     }
 
-    pub fn add_rule(&mut self, rule: Rule) {
-        let rete_prod = Production::new(&rule.conditions, self.prod_sender.clone());
+    /// Returns the production ID of the rule with the given name.
+    pub fn rule_id(&self, name: &str) -> Option<usize> {
+        self.rule_names.get(name).copied()
+    }
+
+    pub fn rule_by_name(&self, name: &str) -> Option<&Rule> {
+        self.production_map.get(&self.rule_id(name)?)
+    }
+
+    /// Returns the production IDs and rules having the given tag.
+    pub fn rules_with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = (usize, &'a Rule)> {
+        self.production_map
+            .iter()
+            .filter(move |(_, rule)| rule.metadata.tags.iter().any(|t| t == tag))
+            .map(|(id, rule)| (*id, rule))
+    }
+
+    /// Replaces the engine's rules with the given ones, touching the network as little as possible.
+    ///
+    /// Rules are matched to existing ones by name, unnamed rules by their conditions. Matched rules
+    /// whose conditions and bindings did not change keep their production, including its matches and
+    /// pending activations, and only get their action and metadata replaced. All other existing rules
+    /// get removed and all other given rules get added. New productions are added before old ones get
+    /// removed so the nodes they share are kept alive.
+    ///
+    /// Returns an error leaving the engine unchanged if two of the given rules have the same name
+    /// or a rule's conditions are invalid.
+    pub fn replace_rules(&mut self, rules: Vec<Rule>) -> Result<RuleReload, RuleError> {
+        let mut names = HashSet::new();
+        for rule in rules.iter() {
+            if let Some(ref name) = rule.metadata.name {
+                if !names.insert(name) {
+                    return Err(RuleError::DuplicateName(name.clone()));
+                }
+            }
+            validate_conditions(&rule.conditions).map_err(RuleError::InvalidConditions)?;
+        }
+
+        let mut reload = RuleReload::default();
+        let mut matched = HashSet::new();
+        let mut added = vec![];
+
+        for rule in rules {
+            let existing = match rule.metadata.name {
+                Some(ref name) => self.rule_id(name),
+                None => self
+                    .production_map
+                    .iter()
+                    .find(|(id, existing)| {
+                        !matched.contains(*id)
+                            && existing.metadata.name.is_none()
+                            && existing.conditions == rule.conditions
+                    })
+                    .map(|(id, _)| *id),
+            };
+
+            let Some(id) = existing else {
+                added.push(rule);
+                continue;
+            };
+
+            matched.insert(id);
+
+            let old = &self.production_map[&id];
+            if old.conditions == rule.conditions && old.bindings == rule.bindings {
+                trace!("Keeping rule {id}");
+                self.production_map.insert(id, rule);
+                reload.unchanged.push(id);
+            } else {
+                reload.changed.push(id);
+                added.push(rule);
+            }
+        }
+
+        let mut removed = self
+            .production_map
+            .keys()
+            .filter(|id| !matched.contains(*id))
+            .copied()
+            .collect::<Vec<_>>();
+        removed.sort();
 
-        self.production_map.insert(rete_prod.id, rule);
+        let outdated = reload
+            .changed
+            .iter()
+            .chain(removed.iter())
+            .copied()
+            .collect::<Vec<_>>();
 
-        self.rete.add_production(rete_prod);
+        // Free the names of the outdated rules for their replacements, their productions stay
+        // in the network until the new ones are added
+        for id in outdated.iter() {
+            let rule = self.production_map.remove(id).unwrap();
+            if let Some(ref name) = rule.metadata.name {
+                self.rule_names.remove(name);
+            }
+        }
+
+        for rule in added {
+            let id = self
+                .add_rule(rule)
+                .expect("Rules were checked before replacing");
+            reload.added.push(id);
+        }
+
+        for id in outdated {
+            self.rete.remove_production(id);
+        }
+
+        reload.removed = removed;
+        Ok(reload)
     }
 
+    /// Retracts expired WMEs, then executes the actions of fired productions and retracts
+    /// the logical facts of retracted matches until no activations are left.
     pub fn activate_productions(&mut self) {
-        while let Ok(id) = self.production_queue.try_recv() {
-            let Some(rule) = self.production_map.remove(&id) else {
+        self.rete.expire();
+
+        while let Ok(activation) = self.production_queue.try_recv() {
+            self.execute(activation);
+        }
+    }
+
+    /// Executes the action of a fired production, or retracts the logical facts of a
+    /// retracted match. Used to step through activations taken from the production queue
+    /// one at a time instead of [activating][Engine::activate_productions] all of them.
+    pub fn execute(&mut self, activation: Activation) {
+        match activation {
+            Activation::Fired { production, .. } => {
+                let Some(rule) = self.production_map.remove(&production) else {
+                    return;
+                };
+                self.current_activation = Some(activation);
+                let bindings = &rule.bindings;
+                (rule.production)(self, bindings);
+                self.current_activation = None;
+                self.production_map.insert(production, rule);
+            }
+            Activation::Retracted { token, .. } => self.retract_support(token),
+        }
+    }
+
+    /// Removes the token's support from the logical facts it asserted, retracting
+    /// facts left without any.
+    fn retract_support(&mut self, token: usize) {
+        let Some(facts) = self.token_support.remove(&token) else {
+            return;
+        };
+
+        for fields in facts {
+            let Some(fact) = self.logical_facts.get_mut(&fields) else {
                 continue;
             };
-            let bindings = &rule.bindings;
-            (rule.production)(self, bindings);
-            self.production_map.insert(id, rule);
+
+            fact.support.remove(&token);
+
+            if fact.support.is_empty() {
+                let fact = self.logical_facts.remove(&fields).unwrap();
+                self.rete.remove_wme(fact.rete_id);
+            }
+        }
+    }
+}
+
+/// The persistable state of an [Engine].
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EngineSnapshot {
+    pub rete: Snapshot,
+    pub elements: HashMap<usize, Vec<EngineElement>>,
+
+    /// Production IDs of the rules mapped to their bindings
+    pub bindings: HashMap<usize, Vec<usize>>,
+
+    /// Production IDs of the rules mapped to their metadata
+    #[serde(default)]
+    pub metadata: HashMap<usize, RuleMetadata>,
+
+    #[serde(default)]
+    pub logical_facts: Vec<LogicalFactSnapshot>,
+}
+
+/// A logically asserted WME along with the matches supporting it. Tokens get new IDs when
+/// restoring, so each match is stored as its production ID and the IDs of the WMEs matching
+/// the production's positive conditions.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LogicalFactSnapshot {
+    pub rete_id: usize,
+    pub fields: Vec<usize>,
+    pub support: Vec<(usize, Vec<usize>)>,
+}
+
+#[cfg(feature = "serde")]
+impl Engine {
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let matches = self.match_wmes();
+
+        let mut logical_facts = self
+            .logical_facts
+            .iter()
+            .map(|(fields, fact)| {
+                let mut support = fact
+                    .support
+                    .iter()
+                    .filter_map(|token| matches.get(token).cloned())
+                    .collect::<Vec<_>>();
+                support.sort();
+                LogicalFactSnapshot {
+                    rete_id: fact.rete_id,
+                    fields: fields.clone(),
+                    support,
+                }
+            })
+            .collect::<Vec<_>>();
+        logical_facts.sort_by_key(|fact| fact.rete_id);
+
+        EngineSnapshot {
+            rete: self.rete.snapshot(),
+            logical_facts,
+            elements: self.elements.clone(),
+            bindings: self
+                .production_map
+                .iter()
+                .map(|(id, rule)| (*id, rule.bindings.clone()))
+                .collect(),
+            metadata: self
+                .production_map
+                .iter()
+                .map(|(id, rule)| (*id, rule.metadata.clone()))
+                .collect(),
+        }
+    }
+
+    /// Restores an engine from the snapshot. Since actions cannot be persisted, `actions` must
+    /// contain the action of every rule keyed by the rule's production ID. Predicates of test
+    /// conditions are resolved from `predicates`, see [Rete::restore].
+    ///
+    /// The matches existing at the time of the snapshot do not fire again, their actions already
+    /// ran. Logically asserted WMEs are restored along with the matches supporting them.
+    pub fn restore(
+        snapshot: EngineSnapshot,
+        mut actions: HashMap<usize, ProductionAction>,
+        predicates: &[Predicate],
+    ) -> Result<Self, RestoreError> {
+        let EngineSnapshot {
+            rete,
+            elements,
+            mut bindings,
+            mut metadata,
+            logical_facts,
+        } = snapshot;
+
+        if let Some(production) = rete
+            .productions
+            .iter()
+            .find(|production| !actions.contains_key(&production.id))
+        {
+            return Err(RestoreError::MissingAction {
+                production: production.id,
+            });
+        }
+
+        let mut engine = Engine::default();
+
+        engine.rete = Rete::restore(rete, engine.prod_sender.clone(), predicates)?;
+        engine.elements = elements;
+
+        for (id, node) in engine.rete.productions.iter() {
+            let Node::Production(ref p_node) = *node.borrow() else {
+                unreachable!("Production map contains a non production node")
+            };
+            let rule = Rule {
+                conditions: p_node.production.conditions.clone(),
+                production: actions.remove(id).unwrap(),
+                bindings: bindings.remove(id).unwrap_or_default(),
+                metadata: metadata.remove(id).unwrap_or_default(),
+            };
+            if let Some(ref name) = rule.metadata.name {
+                engine.rule_names.insert(name.clone(), *id);
+            }
+            engine.production_map.insert(*id, rule);
+        }
+
+        let tokens = engine
+            .match_wmes()
+            .into_iter()
+            .map(|(token, matched)| (matched, token))
+            .collect::<HashMap<_, _>>();
+
+        for fact in logical_facts {
+            let support = fact
+                .support
+                .iter()
+                .filter_map(|matched| tokens.get(matched).copied())
+                .collect::<HashSet<_>>();
+
+            if support.is_empty() {
+                trace!("Logical fact {:?} lost its support", fact.fields);
+                engine.rete.remove_wme(fact.rete_id);
+                continue;
+            }
+
+            for token in support.iter() {
+                engine
+                    .token_support
+                    .entry(*token)
+                    .or_default()
+                    .push(fact.fields.clone());
+            }
+            engine.logical_facts.insert(
+                fact.fields,
+                LogicalFact {
+                    rete_id: fact.rete_id,
+                    support,
+                },
+            );
         }
+
+        Ok(engine)
+    }
+
+    /// Maps the tokens of the rules' matches to their production ID and the IDs of the WMEs
+    /// matching the production's positive conditions.
+    fn match_wmes(&self) -> HashMap<usize, (usize, Vec<usize>)> {
+        let mut matches = HashMap::new();
+
+        for (production, node) in self.rete.productions.iter() {
+            let tokens = node
+                .borrow()
+                .tokens()
+                .iter()
+                .map(|token| token.borrow().id())
+                .collect::<Vec<_>>();
+
+            for token in tokens {
+                let activation = Activation::Fired {
+                    production: *production,
+                    token,
+                    name: None,
+                };
+                let Some(explanation) = self.rete.explain(&activation) else {
+                    continue;
+                };
+                let wmes = explanation
+                    .conditions
+                    .into_iter()
+                    .filter_map(|condition| match condition {
+                        ConditionExplanation::Matched { wme_id, .. } => Some(wme_id),
+                        _ => None,
+                    })
+                    .collect();
+                matches.insert(token, (*production, wmes));
+            }
+        }
+
+        matches
     }
 }
 
@@ -85,6 +598,63 @@ pub struct Rule {
 
     pub production: ProductionAction,
     pub bindings: Vec<usize>,
+
+    pub metadata: RuleMetadata,
+}
+
+/// Reasons a rule cannot be added to an [Engine].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleError {
+    /// A rule with the name already exists, or is given more than once.
+    DuplicateName(String),
+
+    InvalidConditions(ConditionError),
+}
+
+impl std::error::Error for RuleError {}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleError::DuplicateName(name) => write!(f, "rule {name} already exists"),
+            RuleError::InvalidConditions(error) => write!(f, "invalid conditions: {error}"),
+        }
+    }
+}
+
+/// The outcome of [Engine::replace_rules], as production IDs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleReload {
+    /// Productions of the rules that were new or changed
+    pub added: Vec<usize>,
+
+    /// Removed productions of changed rules
+    pub changed: Vec<usize>,
+
+    /// Productions of rules missing from the new set
+    pub removed: Vec<usize>,
+
+    /// Productions kept as they were
+    pub unchanged: Vec<usize>,
+}
+
+/// Describes a rule to the humans maintaining it. The name, if any, identifies the rule
+/// within an [Engine] and is attached to its production's activations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RuleMetadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl RuleMetadata {
+    pub fn named(name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..Default::default()
+        }
+    }
 }
 
 /* rule! {
@@ -0,0 +1,135 @@
+use super::IntoWmes;
+use crate::{
+    parser::{SymbolTable, FIRST_SYMBOL},
+    rete::item::Wme,
+};
+use serde_json::{Map, Value};
+use std::fmt::Display;
+
+/// The WMEs of a JSON object, added to an [Engine][super::Engine] with
+/// [add_element][super::Engine::add_element].
+///
+/// Every object gets an identifier generated through the [SymbolTable] and each of its
+/// attributes becomes an `(identifier attribute value)` WME. Nested objects are linked through
+/// their identifier and arrays result in one WME per element. Attributes that are `null` are
+/// left out.
+///
+/// ```text
+/// {"name": "b1", "on": {"name": "b2"}, "tags": ["x", "y"]}
+///
+/// (#gen1 name b1) (#gen1 on #gen2) (#gen2 name b2) (#gen1 tags x) (#gen1 tags y)
+/// ```
+///
+/// Strings, attribute names and booleans are symbols, integers below [FIRST_SYMBOL] are used as
+/// they are and all other numbers are symbols of their textual representation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct JsonElement {
+    /// The identifier of the outermost object, used as the element's ID
+    pub id: usize,
+
+    /// The fields of the WMEs, in the order they were generated
+    pub facts: Vec<[usize; 3]>,
+}
+
+#[derive(Debug)]
+pub enum JsonError {
+    /// Only objects can be turned into WMEs, arrays and other values have no attributes.
+    NotAnObject,
+
+    Serialize(serde_json::Error),
+}
+
+impl std::error::Error for JsonError {}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::NotAnObject => write!(f, "Only JSON objects can be turned into WMEs"),
+            JsonError::Serialize(e) => write!(f, "Could not serialize to JSON: {e}"),
+        }
+    }
+}
+
+impl IntoWmes for JsonElement {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn to_wmes(&self) -> Vec<Wme> {
+        self.facts.iter().map(|fields| Wme::new(*fields)).collect()
+    }
+}
+
+impl JsonElement {
+    /// Generates the WMEs of the JSON object.
+    pub fn from_json(value: &Value, symbols: &mut SymbolTable) -> Result<Self, JsonError> {
+        let Value::Object(object) = value else {
+            return Err(JsonError::NotAnObject);
+        };
+
+        let mut facts = vec![];
+        let id = object_facts(object, symbols, &mut facts);
+
+        Ok(Self { id, facts })
+    }
+
+    /// Generates the WMEs of anything serializing to a JSON object.
+    #[cfg(feature = "serde")]
+    pub fn from_serialize<T: serde::Serialize>(
+        value: &T,
+        symbols: &mut SymbolTable,
+    ) -> Result<Self, JsonError> {
+        let value = serde_json::to_value(value).map_err(JsonError::Serialize)?;
+        Self::from_json(&value, symbols)
+    }
+}
+
+/// Appends the facts of the object to `facts` and returns its identifier.
+fn object_facts(
+    object: &Map<String, Value>,
+    symbols: &mut SymbolTable,
+    facts: &mut Vec<[usize; 3]>,
+) -> usize {
+    let id = symbols.gensym();
+
+    for (attribute, value) in object {
+        let attribute = symbols.intern(attribute);
+        attribute_facts(id, attribute, value, symbols, facts);
+    }
+
+    id
+}
+
+fn attribute_facts(
+    id: usize,
+    attribute: usize,
+    value: &Value,
+    symbols: &mut SymbolTable,
+    facts: &mut Vec<[usize; 3]>,
+) {
+    let value = match value {
+        Value::Null => return,
+        Value::Bool(value) => symbols.intern(&value.to_string()),
+        Value::Number(number) => match number.as_u64() {
+            Some(value) if value < FIRST_SYMBOL as u64 => value as usize,
+            _ => symbols.intern(&number.to_string()),
+        },
+        Value::String(value) => symbols.intern(value),
+        Value::Array(values) => {
+            // Nested arrays add their elements to the same attribute
+            for value in values {
+                attribute_facts(id, attribute, value, symbols, facts);
+            }
+            return;
+        }
+        Value::Object(object) => {
+            // Reserve the position so the link comes before the nested object's facts
+            let index = facts.len();
+            facts.push([id, attribute, 0]);
+            facts[index][2] = object_facts(object, symbols, facts);
+            return;
+        }
+    };
+
+    facts.push([id, attribute, value]);
+}
@@ -0,0 +1,398 @@
+use super::{Engine, Rule, RuleMetadata};
+use crate::{
+    parser::{parse_rules_partially, ActionCall, ParseError, RuleDefinition, Span, SymbolTable},
+    rete::item::ConditionTest,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// Extension of the files loaded when loading rules from a directory.
+pub const RULE_FILE_EXTENSION: &str = "rules";
+
+/// An action callable from rules by name. Receives the values of the call's arguments.
+pub type NamedAction = Rc<dyn Fn(&mut Engine, &[usize])>;
+
+/// The Rust functions rule files can call in their actions.
+#[derive(Default, Clone)]
+pub struct ActionRegistry {
+    actions: HashMap<String, NamedAction>,
+}
+
+impl ActionRegistry {
+    /// Registers the action under the name, replacing any action previously registered with it.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        action: impl Fn(&mut Engine, &[usize]) + 'static,
+    ) -> &mut Self {
+        self.actions.insert(name.into(), Rc::new(action));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&NamedAction> {
+        self.actions.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.actions.keys().map(String::as_str)
+    }
+}
+
+/// A compiled rule along with where it was defined.
+pub struct LoadedRule {
+    pub rule: Rule,
+
+    /// The names of the rule's variables, indexed by their IDs
+    pub variables: Vec<String>,
+
+    pub path: Option<PathBuf>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+
+    Parse {
+        path: Option<PathBuf>,
+        error: ParseError,
+    },
+
+    /// An action of a rule is not in the [ActionRegistry].
+    UnknownAction {
+        path: Option<PathBuf>,
+        rule: String,
+        action: String,
+        span: Span,
+    },
+
+    /// The rule is defined more than once, or already exists in the engine.
+    DuplicateRule {
+        path: Option<PathBuf>,
+        rule: String,
+        span: Span,
+    },
+}
+
+impl std::error::Error for LoadError {}
+
+impl LoadError {
+    /// Where in its source the error is, if anywhere.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            LoadError::Io { .. } => None,
+            LoadError::Parse { error, .. } => Some(error.span),
+            LoadError::UnknownAction { span, .. } | LoadError::DuplicateRule { span, .. } => {
+                Some(*span)
+            }
+        }
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let location = |f: &mut std::fmt::Formatter<'_>, path: &Option<PathBuf>, span: &Span| {
+            if let Some(path) = path {
+                write!(f, "{}:", path.display())?;
+            }
+            write!(f, "{}:{}: ", span.start.line, span.start.column)
+        };
+
+        match self {
+            LoadError::Io { path, error } => write!(f, "{}: {error}", path.display()),
+            LoadError::Parse { path, error } => {
+                location(f, path, &error.span)?;
+                write!(f, "{}", error.message)
+            }
+            LoadError::UnknownAction {
+                path,
+                rule,
+                action,
+                span,
+            } => {
+                location(f, path, span)?;
+                write!(f, "rule {rule} calls unknown action `{action}`")
+            }
+            LoadError::DuplicateRule { path, rule, span } => {
+                location(f, path, span)?;
+                write!(f, "rule {rule} is already defined")
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// Reads the rules from a rule file, or from all files with the [RULE_FILE_EXTENSION] in a
+    /// directory, and adds them to the engine. Returns the production IDs of the added rules.
+    ///
+    /// Actions are resolved by name from `actions`, symbols through `symbols`. No rules are added
+    /// if any file cannot be read or contains errors, all errors found get returned instead.
+    pub fn load_rules_from_path(
+        &mut self,
+        path: impl AsRef<Path>,
+        actions: &ActionRegistry,
+        symbols: &mut SymbolTable,
+    ) -> Result<Vec<usize>, Vec<LoadError>> {
+        let rules = read_rules_from_path(path, actions, symbols)?;
+        self.add_loaded_rules(rules)
+    }
+
+    /// Same as [Engine::load_rules_from_path], for rules given as text.
+    pub fn load_rules(
+        &mut self,
+        source: &str,
+        actions: &ActionRegistry,
+        symbols: &mut SymbolTable,
+    ) -> Result<Vec<usize>, Vec<LoadError>> {
+        let rules = compile_rules(source, None, actions, symbols)?;
+        self.add_loaded_rules(rules)
+    }
+
+    fn add_loaded_rules(&mut self, rules: Vec<LoadedRule>) -> Result<Vec<usize>, Vec<LoadError>> {
+        let errors = rules
+            .iter()
+            .filter_map(|loaded| {
+                let name = loaded.rule.metadata.name.as_ref()?;
+                self.rule_names
+                    .contains_key(name)
+                    .then(|| LoadError::DuplicateRule {
+                        path: loaded.path.clone(),
+                        rule: name.clone(),
+                        span: loaded.span,
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(rules
+            .into_iter()
+            .map(|loaded| {
+                self.add_rule(loaded.rule)
+                    .expect("Parsed rules have valid conditions and new names")
+            })
+            .collect())
+    }
+}
+
+/// Reads and compiles the rules at the path without adding them to an engine, e.g. for
+/// reloading them with [Engine::replace_rules]. See [Engine::load_rules_from_path].
+pub fn read_rules_from_path(
+    path: impl AsRef<Path>,
+    actions: &ActionRegistry,
+    symbols: &mut SymbolTable,
+) -> Result<Vec<LoadedRule>, Vec<LoadError>> {
+    let path = path.as_ref();
+
+    let files = if path.is_dir() {
+        let entries = std::fs::read_dir(path).map_err(|error| {
+            vec![LoadError::Io {
+                path: path.to_path_buf(),
+                error,
+            }]
+        })?;
+
+        let mut files = vec![];
+        for entry in entries {
+            let entry = entry.map_err(|error| {
+                vec![LoadError::Io {
+                    path: path.to_path_buf(),
+                    error,
+                }]
+            })?;
+            let file = entry.path();
+            if file.is_file()
+                && file
+                    .extension()
+                    .is_some_and(|extension| extension == RULE_FILE_EXTENSION)
+            {
+                files.push(file);
+            }
+        }
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut rules = vec![];
+    let mut errors = vec![];
+    let mut names = HashSet::new();
+
+    for file in files {
+        trace!("Loading rules from {}", file.display());
+
+        let source = match std::fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(error) => {
+                errors.push(LoadError::Io { path: file, error });
+                continue;
+            }
+        };
+
+        match compile_rules(&source, Some(&file), actions, symbols) {
+            Ok(compiled) => {
+                for loaded in compiled {
+                    let name = loaded.rule.metadata.name.clone().unwrap();
+                    if !names.insert(name.clone()) {
+                        errors.push(LoadError::DuplicateRule {
+                            path: loaded.path,
+                            rule: name,
+                            span: loaded.span,
+                        });
+                        continue;
+                    }
+                    rules.push(loaded);
+                }
+            }
+            Err(e) => errors.extend(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(rules)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses the source and turns its definitions into rules. `path` is the file the source was
+/// read from, if any.
+///
+/// Actions get resolved for every rule that parsed, so parse errors and unknown actions are
+/// reported together.
+pub fn compile_rules(
+    source: &str,
+    path: Option<&Path>,
+    actions: &ActionRegistry,
+    symbols: &mut SymbolTable,
+) -> Result<Vec<LoadedRule>, Vec<LoadError>> {
+    let path = path.map(Path::to_path_buf);
+
+    let (definitions, parse_errors) = parse_rules_partially(source, symbols);
+
+    let mut rules = vec![];
+    let mut errors = parse_errors
+        .into_iter()
+        .map(|error| LoadError::Parse {
+            path: path.clone(),
+            error,
+        })
+        .collect::<Vec<_>>();
+    let mut names = HashSet::new();
+
+    for definition in definitions {
+        if !names.insert(definition.name.clone()) {
+            errors.push(LoadError::DuplicateRule {
+                path: path.clone(),
+                rule: definition.name,
+                span: definition.span,
+            });
+            continue;
+        }
+
+        match compile_rule(definition, actions, path.as_deref()) {
+            Ok(rule) => rules.push(rule),
+            Err(e) => errors.extend(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(rules
+            .into_iter()
+            .map(|(rule, variables, span)| LoadedRule {
+                rule,
+                variables,
+                path: path.clone(),
+                span,
+            })
+            .collect())
+    } else {
+        errors.sort_by_key(|error| error.span().map(|span| span.start));
+        Err(errors)
+    }
+}
+
+fn compile_rule(
+    definition: RuleDefinition,
+    actions: &ActionRegistry,
+    path: Option<&Path>,
+) -> Result<(Rule, Vec<String>, Span), Vec<LoadError>> {
+    let RuleDefinition {
+        name,
+        description,
+        conditions,
+        actions: calls,
+        variables,
+        span,
+    } = definition;
+
+    let mut resolved = vec![];
+    let mut errors = vec![];
+
+    for ActionCall {
+        name: action,
+        arguments,
+        span,
+    } in calls
+    {
+        match actions.get(&action) {
+            Some(function) => resolved.push((Rc::clone(function), arguments)),
+            None => errors.push(LoadError::UnknownAction {
+                path: path.map(Path::to_path_buf),
+                rule: name.clone(),
+                action,
+                span,
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let production = Box::new(move |engine: &mut Engine, _: &[usize]| {
+        // The match may have been retracted since the activation was queued
+        let Some(explanation) = engine
+            .current_activation()
+            .and_then(|activation| engine.rete.explain(&activation))
+        else {
+            trace!("Match no longer exists, skipping the actions");
+            return;
+        };
+        let bindings = explanation.bindings;
+
+        for (function, arguments) in resolved.iter() {
+            let values = arguments
+                .iter()
+                .map(|argument| match argument {
+                    ConditionTest::Constant(value) => *value,
+                    ConditionTest::Variable(id) => bindings[id],
+                })
+                .collect::<Vec<_>>();
+            function(engine, &values);
+        }
+    });
+
+    let rule = Rule {
+        conditions,
+        production,
+        bindings: vec![],
+        metadata: RuleMetadata {
+            name: Some(name),
+            description,
+            tags: vec![],
+        },
+    };
+
+    Ok((rule, variables, span))
+}
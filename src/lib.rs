@@ -1,2 +1,24 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static TRACING: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables printing the trace of network operations to stdout. Enabled by default.
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::tracing() {
+            println!($($arg)*)
+        }
+    };
+}
+
 pub mod engine;
+pub mod parser;
 pub mod rete;
@@ -0,0 +1,773 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt::Display,
+    io::{BufRead, Write},
+    rc::Rc,
+};
+use threte::{
+    engine::{loader::ActionRegistry, Engine},
+    parser::{parse_facts, SymbolTable},
+    rete::item::{Activation, Wme},
+};
+
+const HELP: &str = "\
+Facts and rules
+  (b1 ^on b2) ...          assert facts
+  retract <id> ...         retract WMEs by ID
+  (defrule name ...)       define rules, their actions can call print, assert and retract
+  undefrule <name>         remove a rule
+  load <path>              load rules from a file or directory
+  load-facts <path>        assert the facts in a file
+
+Running
+  run [n]                  fire activations until none are left, or at most n
+  agenda                   activations waiting to be executed
+
+Inspecting
+  facts                    working memory using symbols
+  rules                    defined rules
+  wmes                     working memory with tokens
+  alpha                    alpha memories
+  beta                     beta network
+  tokens                   token tree
+  productions              production nodes and their conditions
+  why-not <rule>           why a rule does not match
+
+  trace on|off             print the trace of network operations
+  help                     this text
+  quit                     exit";
+
+struct Repl {
+    engine: Engine,
+    symbols: Rc<RefCell<SymbolTable>>,
+    actions: ActionRegistry,
+
+    /// Activations taken from the engine's queue but not executed yet
+    agenda: VecDeque<Activation>,
+
+    /// Receives the results of commands and the lines printed by rules
+    output: Rc<RefCell<dyn Write>>,
+
+    /// Lines of an input whose parentheses are not closed yet, see [Repl::feed]
+    pending: String,
+    depth: isize,
+}
+
+/// The actions available to rules, `print` writes its arguments using `output`.
+fn builtin_actions(
+    symbols: &Rc<RefCell<SymbolTable>>,
+    output: impl Fn(&str) + 'static,
+) -> ActionRegistry {
+    let mut actions = ActionRegistry::default();
+
+    let print_symbols = Rc::clone(symbols);
+    actions.register("print", move |_, values| {
+        output(&format_fields(&print_symbols.borrow(), values));
+    });
+    actions.register("assert", |engine, values| {
+        engine.rete.add_wme(Wme::new(values));
+    });
+    actions.register("retract", |engine, values| {
+        let ids = engine
+            .rete
+            .working_memory
+            .iter()
+            .filter(|(_, wme)| wme.borrow().fields == values)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            engine.rete.remove_wme(id);
+        }
+    });
+
+    actions
+}
+
+impl Repl {
+    fn new(output: Rc<RefCell<dyn Write>>) -> Self {
+        let symbols = Rc::new(RefCell::new(SymbolTable::default()));
+        let print_output = Rc::clone(&output);
+        let actions = builtin_actions(&symbols, move |line| {
+            let _ = writeln!(print_output.borrow_mut(), "{line}");
+        });
+
+        Self {
+            engine: Engine::default(),
+            symbols,
+            actions,
+            agenda: VecDeque::new(),
+            output,
+            pending: String::new(),
+            depth: 0,
+        }
+    }
+
+    /// Writes a line of output. A closed output only loses the line, the REPL keeps going.
+    fn say(&self, line: impl Display) {
+        let _ = writeln!(self.output.borrow_mut(), "{line}");
+    }
+
+    /// Writes multi-line output which already ends with a newline.
+    fn say_all(&self, lines: impl Display) {
+        let _ = write!(self.output.borrow_mut(), "{lines}");
+    }
+
+    /// Whether the input read so far has unclosed parentheses.
+    fn is_pending(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Adds a line of input and executes the input once all of its parentheses are closed.
+    /// Returns `false` when the REPL should exit.
+    fn feed(&mut self, line: &str) -> bool {
+        self.depth += open_parens(line);
+        self.pending.push_str(line);
+
+        // Keep reading until all parentheses are closed
+        if self.depth > 0 {
+            return true;
+        }
+        self.depth = 0;
+
+        let input = std::mem::take(&mut self.pending);
+        self.execute(&input)
+    }
+
+    /// Moves the activations queued by the engine to the agenda.
+    fn collect_activations(&mut self) {
+        while let Ok(activation) = self.engine.production_queue.try_recv() {
+            self.agenda.push_back(activation);
+        }
+    }
+
+    /// Executes the input, returns `false` when the REPL should exit.
+    fn execute(&mut self, input: &str) -> bool {
+        let input = input.trim();
+        if input.is_empty() || input.starts_with(';') {
+            return true;
+        }
+
+        if input.starts_with("(defrule") {
+            self.define_rules(input);
+            return true;
+        }
+
+        if input.starts_with('(') {
+            self.assert_facts(input);
+            return true;
+        }
+
+        let (command, argument) = input
+            .split_once(char::is_whitespace)
+            .map_or((input, ""), |(command, argument)| {
+                (command, argument.trim())
+            });
+
+        match command {
+            "quit" | "exit" => return false,
+            "help" => self.say(HELP),
+            "retract" => {
+                for id in argument.split_whitespace() {
+                    match id.parse::<usize>() {
+                        Ok(id) if self.engine.rete.working_memory.contains_key(&id) => {
+                            self.engine.rete.remove_wme(id);
+                            self.say(format_args!("Retracted WME {id}"));
+                        }
+                        _ => self.say(format_args!("No WME with ID {id}")),
+                    }
+                }
+            }
+            "undefrule" => match self.engine.remove_rule_by_name(argument) {
+                Some(_) => self.say(format_args!("Removed rule {argument}")),
+                None => self.say(format_args!("No rule named {argument}")),
+            },
+            "load" => {
+                let result = self.engine.load_rules_from_path(
+                    argument,
+                    &self.actions,
+                    &mut self.symbols.borrow_mut(),
+                );
+                match result {
+                    Ok(ids) => self.say(format_args!("Loaded {} rules", ids.len())),
+                    Err(errors) => errors.iter().for_each(|e| self.say(e)),
+                }
+            }
+            "load-facts" => match std::fs::read_to_string(argument) {
+                Ok(source) => self.assert_facts(&source),
+                Err(e) => self.say(format_args!("{argument}: {e}")),
+            },
+            "run" => {
+                let limit = match argument {
+                    "" => usize::MAX,
+                    n => match n.parse() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            self.say("Expected the number of activations to fire");
+                            return true;
+                        }
+                    },
+                };
+                self.run(limit);
+            }
+            "agenda" => {
+                self.collect_activations();
+                if self.agenda.is_empty() {
+                    self.say("The agenda is empty");
+                }
+                for activation in self.agenda.iter() {
+                    self.say(activation);
+                }
+            }
+            "facts" => {
+                let mut wmes = self.engine.rete.working_memory.iter().collect::<Vec<_>>();
+                wmes.sort_by_key(|(id, _)| **id);
+                let symbols = self.symbols.borrow();
+                for (id, wme) in wmes {
+                    self.say(format_args!(
+                        "{id}: {}",
+                        format_fields(&symbols, &wme.borrow().fields)
+                    ));
+                }
+            }
+            "rules" => {
+                let mut rules = self.engine.rule_names.iter().collect::<Vec<_>>();
+                rules.sort_by_key(|(_, id)| **id);
+                for (name, id) in rules {
+                    let rule = &self.engine.production_map[id];
+                    match rule.metadata.description {
+                        Some(ref description) => {
+                            self.say(format_args!("{id}: {name} - {description}"))
+                        }
+                        None => self.say(format_args!("{id}: {name}")),
+                    }
+                }
+            }
+            "wmes" => self.say_all(self.engine.rete.wmes_to_string()),
+            "alpha" => self.say_all(self.engine.rete.alpha_memories_to_string()),
+            "beta" => self.say_all(self.engine.rete.beta_network_to_string()),
+            "tokens" => self.say_all(self.engine.rete.tokens_to_string()),
+            "productions" => self.say_all(self.engine.rete.productions_to_string()),
+            "why-not" => match self
+                .engine
+                .rule_id(argument)
+                .and_then(|id| self.engine.rete.why_not(id))
+            {
+                Some(why_not) => self.say(why_not),
+                None => self.say(format_args!("No rule named {argument}")),
+            },
+            "trace" => match argument {
+                "on" => threte::set_tracing(true),
+                "off" => threte::set_tracing(false),
+                _ => self.say("Expected on or off"),
+            },
+            _ => self.say(format_args!("Unknown command {command}, see help")),
+        }
+
+        true
+    }
+
+    fn define_rules(&mut self, source: &str) {
+        let result = self
+            .engine
+            .load_rules(source, &self.actions, &mut self.symbols.borrow_mut());
+        match result {
+            Ok(ids) => {
+                for id in ids {
+                    self.say(format_args!("Defined rule {id}"));
+                }
+            }
+            Err(errors) => errors.iter().for_each(|e| self.say(e)),
+        }
+    }
+
+    fn assert_facts(&mut self, source: &str) {
+        let facts = parse_facts(source, &mut self.symbols.borrow_mut());
+        match facts {
+            Ok(facts) => {
+                for fields in facts {
+                    let id = self.engine.rete.add_wme(Wme::new(fields));
+                    self.say(format_args!("Asserted WME {id}"));
+                }
+            }
+            Err(errors) => errors.iter().for_each(|e| self.say(e)),
+        }
+    }
+
+    fn run(&mut self, limit: usize) {
+        self.engine.rete.expire();
+
+        let mut fired = 0;
+        while fired < limit {
+            self.collect_activations();
+            let Some(activation) = self.agenda.pop_front() else {
+                break;
+            };
+            if matches!(activation, Activation::Fired { .. }) {
+                self.say(&activation);
+                fired += 1;
+            }
+            self.engine.execute(activation);
+        }
+
+        self.collect_activations();
+        self.say(format_args!(
+            "Fired {fired} activations, {} left on the agenda",
+            self.agenda.len()
+        ));
+    }
+}
+
+fn format_fields(symbols: &SymbolTable, fields: &[usize]) -> String {
+    let fields = fields
+        .iter()
+        .map(|field| symbols.display(*field))
+        .collect::<Vec<_>>();
+    format!("({})", fields.join(" "))
+}
+
+/// The difference between opening and closing parentheses outside of strings and comments.
+fn open_parens(line: &str) -> isize {
+    let mut depth = 0;
+    let mut in_string = false;
+    for c in line.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => break,
+            '(' | '{' if !in_string => depth += 1,
+            ')' | '}' if !in_string => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// How many activations `threte run` fires at most unless told otherwise, which stops rules
+/// that keep triggering each other.
+const DEFAULT_MAX_FIRES: usize = 10_000;
+
+/// Loads the facts and rules, runs the engine until no activations are left and prints
+/// the fired activations and the final working memory as JSON. Returns `false` on errors,
+/// including when more than `max_fires` activations would fire.
+fn run_batch(facts: &str, rules: &str, max_fires: usize) -> bool {
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use threte::engine::loader::read_rules_from_path;
+
+    let symbols = Rc::new(RefCell::new(SymbolTable::default()));
+    let actions = builtin_actions(&symbols, |line| eprintln!("{line}"));
+    let mut engine = Engine::default();
+
+    let loaded = match read_rules_from_path(rules, &actions, &mut symbols.borrow_mut()) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            errors.iter().for_each(|e| eprintln!("{e}"));
+            return false;
+        }
+    };
+
+    let mut variables = HashMap::new();
+    for rule in loaded {
+        let id = engine
+            .add_rule(rule.rule)
+            .expect("Loaded rules have valid conditions and unique names");
+        variables.insert(id, rule.variables);
+    }
+
+    let source = match std::fs::read_to_string(facts) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{facts}: {e}");
+            return false;
+        }
+    };
+    let facts = match parse_facts(&source, &mut symbols.borrow_mut()) {
+        Ok(facts) => facts,
+        Err(errors) => {
+            errors.iter().for_each(|e| eprintln!("{facts}:{e}"));
+            return false;
+        }
+    };
+    for fields in facts {
+        engine.rete.add_wme(Wme::new(fields));
+    }
+
+    let value = |value: usize| -> Value {
+        match symbols.borrow().name(value) {
+            Some(name) => json!(name),
+            None => json!(value),
+        }
+    };
+
+    let mut fired = vec![];
+    engine.rete.expire();
+    while let Ok(activation) = engine.production_queue.try_recv() {
+        if matches!(activation, Activation::Fired { .. }) {
+            // The match has to be explained before the action changes working memory
+            // Matches retracted before their turn don't run their actions
+            if let Some(explanation) = engine.rete.explain(&activation) {
+                if fired.len() == max_fires {
+                    eprintln!(
+                        "Stopped after firing {max_fires} activations, the rules keep activating \
+                         each other. Raise the limit with --max-fires if this is expected."
+                    );
+                    return false;
+                }
+
+                let names = &variables[&activation.production()];
+                let bindings = explanation
+                    .bindings
+                    .into_iter()
+                    .map(|(variable, bound)| (names[variable].clone(), value(bound)))
+                    .collect::<serde_json::Map<_, _>>();
+
+                fired.push(json!({
+                    "rule": activation.name(),
+                    "bindings": bindings,
+                }));
+            }
+        }
+        engine.execute(activation);
+    }
+
+    let mut wmes = engine.rete.working_memory.iter().collect::<Vec<_>>();
+    wmes.sort_by_key(|(id, _)| **id);
+    let working_memory = wmes
+        .into_iter()
+        .map(|(id, wme)| {
+            let fields = wme
+                .borrow()
+                .fields
+                .iter()
+                .map(|field| value(*field))
+                .collect::<Vec<_>>();
+            json!({ "id": id, "fields": fields })
+        })
+        .collect::<Vec<_>>();
+
+    let output = json!({
+        "fired": fired,
+        "working_memory": working_memory,
+    });
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+
+    true
+}
+
+fn main() {
+    threte::set_tracing(false);
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.as_slice() {
+        [] => {}
+        [command, facts, rules] if command == "run" => {
+            let success = run_batch(facts, rules, DEFAULT_MAX_FIRES);
+            std::process::exit(if success { 0 } else { 1 });
+        }
+        [command, option, max_fires, facts, rules]
+            if command == "run" && option == "--max-fires" =>
+        {
+            let Ok(max_fires) = max_fires.parse() else {
+                eprintln!("--max-fires expects a number, got {max_fires}");
+                std::process::exit(2);
+            };
+            let success = run_batch(facts, rules, max_fires);
+            std::process::exit(if success { 0 } else { 1 });
+        }
+        _ => {
+            eprintln!("Usage:\n  threte                                      start the REPL\n  threte run [--max-fires <n>] <facts> <rules> run the rules on the facts and print the results as JSON,\n                                              firing at most n activations ({DEFAULT_MAX_FIRES} by default)");
+            std::process::exit(2);
+        }
+    }
+
+    let mut repl = Repl::new(Rc::new(RefCell::new(std::io::stdout())));
+    let stdin = std::io::stdin();
+
+    println!("threte, type help for a list of commands");
+
+    loop {
+        print!(
+            "{}",
+            if repl.is_pending() {
+                "...> "
+            } else {
+                "threte> "
+            }
+        );
+        std::io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                println!("{e}");
+                break;
+            }
+        }
+
+        if !repl.feed(&line) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repl() -> (Repl, Rc<RefCell<Vec<u8>>>) {
+        threte::set_tracing(false);
+        let output = Rc::new(RefCell::new(vec![]));
+        (Repl::new(output.clone()), output)
+    }
+
+    /// Executes the input and returns what it wrote.
+    fn execute(repl: &mut Repl, output: &Rc<RefCell<Vec<u8>>>, input: &str) -> String {
+        assert!(repl.execute(input), "{input} exited the REPL");
+        String::from_utf8(output.take()).unwrap()
+    }
+
+    /// The ID of the WME with the fields, which are given as symbols.
+    fn wme_id(repl: &Repl, fields: &[&str]) -> usize {
+        let symbols = repl.symbols.borrow();
+        let fields = fields
+            .iter()
+            .map(|field| symbols.get(field).unwrap())
+            .collect::<Vec<_>>();
+        *repl
+            .engine
+            .rete
+            .working_memory
+            .iter()
+            .find(|(_, wme)| wme.borrow().fields == fields)
+            .unwrap()
+            .0
+    }
+
+    const STACKED: &str =
+        "(defrule stacked \"On a red block\" (?x ^on ?y) (?y ^color red) => (print ?x))";
+
+    #[test]
+    fn counts_open_parens() {
+        assert_eq!(open_parens("(b1 ^on b2)"), 0);
+        assert_eq!(open_parens("(defrule stack"), 1);
+        assert_eq!(open_parens("  -{ (?x ^on ?y)"), 1);
+        assert_eq!(open_parens("=> (log ?x)))"), -2);
+        assert_eq!(open_parens("(defrule a \"(not counted\""), 1);
+        assert_eq!(open_parens("(b1 ^on b2) ; (comment"), 0);
+        assert_eq!(open_parens("(b1 ^name \"a ; b\""), 1);
+    }
+
+    #[test]
+    fn feeds_lines_until_parens_close() {
+        let (mut repl, output) = repl();
+
+        assert!(repl.feed("(defrule stacked\n"));
+        assert!(repl.is_pending());
+        assert!(repl.feed("    (?x ^on ?y) ; a comment (\n"));
+        assert!(repl.is_pending());
+        assert!(output.borrow().is_empty());
+
+        assert!(repl.feed("    => (print ?x))\n"));
+        assert!(!repl.is_pending());
+        let id = repl.engine.rule_id("stacked").unwrap();
+        assert_eq!(
+            String::from_utf8(output.take()).unwrap(),
+            format!("Defined rule {id}\n")
+        );
+
+        // Extra closing parentheses do not leave the REPL waiting
+        assert!(repl.feed("(b1 ^on b2))\n"));
+        assert!(!repl.is_pending());
+        assert!(!repl.feed("quit\n"));
+    }
+
+    #[test]
+    fn asserts_and_retracts_facts() {
+        let (mut repl, output) = repl();
+
+        assert_eq!(execute(&mut repl, &output, ""), "");
+        assert_eq!(execute(&mut repl, &output, "; a comment"), "");
+
+        execute(&mut repl, &output, "(b1 ^on b2) (b2 ^color red)");
+        let on = wme_id(&repl, &["b1", "on", "b2"]);
+        let color = wme_id(&repl, &["b2", "color", "red"]);
+        assert_eq!(
+            execute(&mut repl, &output, "facts"),
+            format!("{on}: (b1 on b2)\n{color}: (b2 color red)\n")
+        );
+
+        assert_eq!(
+            execute(&mut repl, &output, "(b1 ^on"),
+            "1:8: expected a variable, symbol or number, found end of input\n"
+        );
+
+        let missing = on + color + 1000;
+        assert_eq!(
+            execute(&mut repl, &output, &format!("retract {on} {missing} b1")),
+            format!("Retracted WME {on}\nNo WME with ID {missing}\nNo WME with ID b1\n")
+        );
+        assert!(!repl.engine.rete.working_memory.contains_key(&on));
+        assert_eq!(
+            execute(&mut repl, &output, "facts"),
+            format!("{color}: (b2 color red)\n")
+        );
+    }
+
+    #[test]
+    fn defines_and_removes_rules() {
+        let (mut repl, output) = repl();
+
+        let defined = execute(&mut repl, &output, STACKED);
+        let id = repl.engine.rule_id("stacked").unwrap();
+        assert_eq!(defined, format!("Defined rule {id}\n"));
+        assert_eq!(
+            execute(&mut repl, &output, "rules"),
+            format!("{id}: stacked - On a red block\n")
+        );
+
+        assert_eq!(
+            execute(
+                &mut repl,
+                &output,
+                "(defrule broken (?x ^on) => (print ?y))"
+            ),
+            "1:36: variable `?y` is not bound by a positive condition of the rule\n"
+        );
+        assert!(repl.engine.rule_id("broken").is_none());
+
+        assert_eq!(
+            execute(&mut repl, &output, "undefrule stacked"),
+            "Removed rule stacked\n"
+        );
+        assert_eq!(
+            execute(&mut repl, &output, "undefrule stacked"),
+            "No rule named stacked\n"
+        );
+        assert_eq!(execute(&mut repl, &output, "rules"), "");
+    }
+
+    #[test]
+    fn runs_the_agenda() {
+        let (mut repl, output) = repl();
+
+        assert_eq!(
+            execute(&mut repl, &output, "agenda"),
+            "The agenda is empty\n"
+        );
+
+        execute(&mut repl, &output, STACKED);
+        execute(
+            &mut repl,
+            &output,
+            "(b1 ^on b2) (b3 ^on b2) (b2 ^color red)",
+        );
+
+        let agenda = execute(&mut repl, &output, "agenda");
+        assert_eq!(agenda.lines().count(), 2, "{agenda}");
+        assert!(agenda.lines().all(|line| line.starts_with("Fired stacked")));
+
+        assert_eq!(
+            execute(&mut repl, &output, "run x"),
+            "Expected the number of activations to fire\n"
+        );
+
+        let run = execute(&mut repl, &output, "run 1");
+        let lines = run.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{run}");
+        assert!(lines[0].starts_with("Fired stacked"));
+        assert!(lines[1] == "(b1)" || lines[1] == "(b3)");
+        assert_eq!(lines[2], "Fired 1 activations, 1 left on the agenda");
+
+        let run = execute(&mut repl, &output, "run");
+        assert!(run.ends_with("Fired 1 activations, 0 left on the agenda\n"));
+        assert_eq!(
+            execute(&mut repl, &output, "agenda"),
+            "The agenda is empty\n"
+        );
+    }
+
+    #[test]
+    fn loads_rules_and_facts_from_files() {
+        let (mut repl, output) = repl();
+
+        let dir = std::env::temp_dir().join(format!("threte_repl_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rules = dir.join("stacked.rules");
+        let facts = dir.join("facts");
+        std::fs::write(&rules, STACKED).unwrap();
+        std::fs::write(&facts, "(b1 ^on b2)\n(b2 ^color red)").unwrap();
+
+        assert_eq!(
+            execute(&mut repl, &output, &format!("load {}", rules.display())),
+            "Loaded 1 rules\n"
+        );
+        assert!(repl.engine.rule_id("stacked").is_some());
+
+        let loaded = execute(
+            &mut repl,
+            &output,
+            &format!("load-facts {}", facts.display()),
+        );
+        assert_eq!(loaded.lines().count(), 2, "{loaded}");
+        assert!(loaded.lines().all(|line| line.starts_with("Asserted WME")));
+        assert_eq!(repl.engine.rete.working_memory.len(), 2);
+
+        let missing = dir.join("missing");
+        assert!(!execute(&mut repl, &output, &format!("load {}", missing.display())).is_empty());
+        assert!(execute(
+            &mut repl,
+            &output,
+            &format!("load-facts {}", missing.display())
+        )
+        .starts_with(&format!("{}: ", missing.display())));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inspects_the_network() {
+        let (mut repl, output) = repl();
+
+        execute(&mut repl, &output, STACKED);
+        execute(&mut repl, &output, "(b1 ^on b2)");
+
+        let why_not = execute(&mut repl, &output, "why-not stacked");
+        let id = repl.engine.rule_id("stacked").unwrap();
+        assert!(
+            why_not.starts_with(&format!("Production {id} has no matches")),
+            "{why_not}"
+        );
+        assert_eq!(
+            execute(&mut repl, &output, "why-not missing"),
+            "No rule named missing\n"
+        );
+
+        for command in ["wmes", "alpha", "beta", "tokens", "productions"] {
+            let printed = execute(&mut repl, &output, command);
+            assert!(printed.ends_with('\n'), "{command}: {printed}");
+        }
+        assert!(execute(&mut repl, &output, "productions").contains("stacked"));
+        assert!(execute(&mut repl, &output, "wmes").starts_with("WME {"));
+    }
+
+    #[test]
+    fn handles_other_commands() {
+        let (mut repl, output) = repl();
+
+        assert_eq!(execute(&mut repl, &output, "help"), format!("{HELP}\n"));
+        assert_eq!(
+            execute(&mut repl, &output, "trace maybe"),
+            "Expected on or off\n"
+        );
+        assert_eq!(execute(&mut repl, &output, "trace off"), "");
+        assert_eq!(
+            execute(&mut repl, &output, "frobnicate now"),
+            "Unknown command frobnicate, see help\n"
+        );
+
+        assert!(!repl.execute("quit"));
+        assert!(!repl.execute("  exit  "));
+    }
+}
@@ -0,0 +1,844 @@
+//! A small OPS5/CLIPS flavoured language for defining rules as text.
+//!
+//! ```text
+//! ; A block on top of a block left of a red one
+//! (defrule stack-on-red "Optional description"
+//!     (?x ^on ?y)
+//!     (?y ^left-of ?z)
+//!     (?z ^color red)
+//!     -(?x ^color blue)
+//!     -{ (?z ^on ?w) -(?w ^color maize) }
+//!     =>
+//!     (log ?x ?z))
+//! ```
+//!
+//! Patterns are lists of constants and `?variables`. A `^` in front of a symbol is optional and
+//! only marks it as an attribute. Patterns prefixed with `-` are negated and `-{ ... }` negates a
+//! conjunction of conditions. Everything after `=>` are calls of actions with their arguments.
+//!
+//! Symbols are translated to WME values through a [SymbolTable], numbers are used as they are.
+
+use crate::rete::item::{validate_conditions, Condition, ConditionTest};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+/// Symbols not defined beforehand get values starting from here, which keeps them apart from
+/// numbers in rules and facts as long as those stay below it.
+pub const FIRST_SYMBOL: usize = 1 << (usize::BITS - 2);
+
+/// Maps symbolic names to the values used in WMEs and back.
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    symbols: HashMap<String, usize>,
+    names: HashMap<usize, String>,
+    next: usize,
+
+    /// Number of symbols created by [SymbolTable::gensym]
+    generated: usize,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self {
+            symbols: HashMap::new(),
+            names: HashMap::new(),
+            next: FIRST_SYMBOL,
+            generated: 0,
+        }
+    }
+}
+
+impl SymbolTable {
+    /// Binds the name to a fixed value, e.g. an attribute constant already used in Rust code.
+    pub fn define(&mut self, name: impl Into<String>, value: usize) {
+        let name = name.into();
+        self.names.insert(value, name.clone());
+        self.symbols.insert(name, value);
+    }
+
+    /// Returns the value of the symbol, assigning it a new one if it is not known yet.
+    pub fn intern(&mut self, name: &str) -> usize {
+        if let Some(value) = self.symbols.get(name) {
+            return *value;
+        }
+
+        let value = self.next;
+        self.next += 1;
+        self.define(name, value);
+        value
+    }
+
+    /// Creates a new symbol, e.g. to identify objects. Generated symbols only have a name for
+    /// display, looking it up or interning it never returns their value, so they cannot collide
+    /// with symbols interned from data.
+    pub fn gensym(&mut self) -> usize {
+        self.generated += 1;
+        let value = self.next;
+        self.next += 1;
+        self.names.insert(value, format!("#gen{}", self.generated));
+        value
+    }
+
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn name(&self, value: usize) -> Option<&str> {
+        self.names.get(&value).map(String::as_str)
+    }
+
+    /// The name of the value if it is a symbol, the number otherwise.
+    pub fn display(&self, value: usize) -> String {
+        self.name(value)
+            .map_or_else(|| value.to_string(), String::from)
+    }
+}
+
+/// A 1-based position in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The source range of a token or rule, `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::error::Error for ParseError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.start.line, self.span.start.column, self.message
+        )
+    }
+}
+
+/// A rule as defined in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleDefinition {
+    pub name: String,
+    pub description: Option<String>,
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<ActionCall>,
+
+    /// The names of the rule's variables, indexed by their IDs in the conditions
+    pub variables: Vec<String>,
+
+    pub span: Span,
+}
+
+/// An action on the right hand side of a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionCall {
+    pub name: String,
+    pub arguments: Vec<ConditionTest>,
+    pub span: Span,
+}
+
+/// Parses all rules in the source. Parsing continues with the next rule after an error, so all
+/// errors in the source get reported at once.
+pub fn parse_rules(
+    source: &str,
+    symbols: &mut SymbolTable,
+) -> Result<Vec<RuleDefinition>, Vec<ParseError>> {
+    let (rules, errors) = parse_rules_partially(source, symbols);
+
+    if errors.is_empty() {
+        Ok(rules)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Same as [parse_rules], but also returns the rules parsed without errors when other rules
+/// contain errors.
+pub fn parse_rules_partially(
+    source: &str,
+    symbols: &mut SymbolTable,
+) -> (Vec<RuleDefinition>, Vec<ParseError>) {
+    let tokens = match tokenize(source) {
+        Ok(tokens) => tokens,
+        Err(errors) => return (vec![], errors),
+    };
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        symbols,
+        variables: vec![],
+    };
+
+    let mut rules = vec![];
+    let mut errors = vec![];
+
+    while !parser.at_end() {
+        let start = parser.position;
+        match parser.rule() {
+            Ok(rule) => rules.push(rule),
+            Err(e) => {
+                errors.push(e);
+                parser.skip_rule(start);
+            }
+        }
+    }
+
+    (rules, errors)
+}
+
+/// Parses a list of facts like `(b1 ^on b2) (b2 ^color red)` into WME fields.
+pub fn parse_facts(
+    source: &str,
+    symbols: &mut SymbolTable,
+) -> Result<Vec<Vec<usize>>, Vec<ParseError>> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        symbols,
+        variables: vec![],
+    };
+
+    let mut facts = vec![];
+    let mut errors = vec![];
+
+    while !parser.at_end() {
+        let start = parser.position;
+        match parser.fact() {
+            Ok(fact) => facts.push(fact),
+            Err(e) => {
+                errors.push(e);
+                parser.skip_rule(start);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(facts)
+    } else {
+        Err(errors)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Negation,
+    Arrow,
+    Variable(String),
+    Symbol(String),
+    Number(usize),
+    String(String),
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::LeftParen => write!(f, "`(`"),
+            TokenKind::RightParen => write!(f, "`)`"),
+            TokenKind::LeftBrace => write!(f, "`{{`"),
+            TokenKind::RightBrace => write!(f, "`}}`"),
+            TokenKind::Negation => write!(f, "`-`"),
+            TokenKind::Arrow => write!(f, "`=>`"),
+            TokenKind::Variable(name) => write!(f, "variable `?{name}`"),
+            TokenKind::Symbol(name) => write!(f, "symbol `{name}`"),
+            TokenKind::Number(value) => write!(f, "number `{value}`"),
+            TokenKind::String(value) => write!(f, "string {value:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+fn is_symbol_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | '{' | '}' | '"' | ';')
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Vec<ParseError>> {
+    let mut tokens = vec![];
+    let mut errors = vec![];
+
+    let mut chars = source.chars().peekable();
+    let mut location = Location { line: 1, column: 1 };
+
+    let advance = |c: char, location: &mut Location| {
+        if c == '\n' {
+            location.line += 1;
+            location.column = 1;
+        } else {
+            location.column += 1;
+        }
+    };
+
+    while let Some(&c) = chars.peek() {
+        let start = location;
+
+        if c.is_whitespace() {
+            chars.next();
+            advance(c, &mut location);
+            continue;
+        }
+
+        if c == ';' {
+            while let Some(&c) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                chars.next();
+                advance(c, &mut location);
+            }
+            continue;
+        }
+
+        let kind = match c {
+            '(' | ')' | '{' | '}' => {
+                chars.next();
+                advance(c, &mut location);
+                match c {
+                    '(' => TokenKind::LeftParen,
+                    ')' => TokenKind::RightParen,
+                    '{' => TokenKind::LeftBrace,
+                    _ => TokenKind::RightBrace,
+                }
+            }
+            '"' => {
+                chars.next();
+                advance(c, &mut location);
+
+                let mut value = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    advance(c, &mut location);
+                    if c == '"' {
+                        closed = true;
+                        break;
+                    }
+                    value.push(c);
+                }
+
+                if !closed {
+                    errors.push(ParseError {
+                        message: "unterminated string".to_string(),
+                        span: Span {
+                            start,
+                            end: location,
+                        },
+                    });
+                    continue;
+                }
+
+                TokenKind::String(value)
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !is_symbol_char(c) {
+                        break;
+                    }
+                    // A leading minus directly followed by a pattern or conjunction negates it
+                    if text == "-" && matches!(c, '(' | '{') {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                    advance(c, &mut location);
+                    if text == "-" && matches!(chars.peek(), Some('(' | '{')) {
+                        break;
+                    }
+                }
+
+                let span = Span {
+                    start,
+                    end: location,
+                };
+
+                match lex_word(&text) {
+                    Ok(kind) => kind,
+                    Err(message) => {
+                        errors.push(ParseError { message, span });
+                        continue;
+                    }
+                }
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            span: Span {
+                start,
+                end: location,
+            },
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors)
+    }
+}
+
+fn lex_word(text: &str) -> Result<TokenKind, String> {
+    if text == "-" {
+        return Ok(TokenKind::Negation);
+    }
+
+    if text == "=>" {
+        return Ok(TokenKind::Arrow);
+    }
+
+    if let Some(name) = text.strip_prefix('?') {
+        if name.is_empty() {
+            return Err("expected a variable name after `?`".to_string());
+        }
+        return Ok(TokenKind::Variable(name.to_string()));
+    }
+
+    if let Some(name) = text.strip_prefix('^') {
+        if name.is_empty() {
+            return Err("expected an attribute name after `^`".to_string());
+        }
+        return Ok(TokenKind::Symbol(name.to_string()));
+    }
+
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        return match text.parse::<usize>() {
+            Ok(value) if value < FIRST_SYMBOL => Ok(TokenKind::Number(value)),
+            _ => Err(format!("invalid number `{text}`")),
+        };
+    }
+
+    Ok(TokenKind::Symbol(text.to_string()))
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    symbols: &'a mut SymbolTable,
+
+    /// The variables of the rule being parsed
+    variables: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    /// The span of the current token, or of the end of the source
+    fn span(&self) -> Span {
+        match self.tokens.get(self.position) {
+            Some(token) => token.span,
+            None => self.tokens.last().map_or(
+                Span {
+                    start: Location { line: 1, column: 1 },
+                    end: Location { line: 1, column: 1 },
+                },
+                |token| Span {
+                    start: token.span.end,
+                    end: token.span.end,
+                },
+            ),
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            message: message.into(),
+            span: self.span(),
+        })
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ParseError> {
+        match self.peek() {
+            Some(kind) => self.error(format!("expected {expected}, found {kind}")),
+            None => self.error(format!("expected {expected}, found end of input")),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Span, ParseError> {
+        if self.peek() != Some(&kind) {
+            return self.unexpected(&kind.to_string());
+        }
+        Ok(self.next().unwrap().span)
+    }
+
+    /// Skips to the next top level `(` after an error in the rule starting at token `start`.
+    fn skip_rule(&mut self, start: usize) {
+        let mut depth = 0usize;
+
+        // Tokens already consumed by the failing rule count towards its nesting
+        let end = self.position.min(self.tokens.len());
+        for token in &self.tokens[start.min(end)..end] {
+            match token.kind {
+                TokenKind::LeftParen | TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        while let Some(kind) = self.peek() {
+            match kind {
+                TokenKind::LeftParen if depth == 0 => return,
+                TokenKind::LeftParen | TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.position += 1;
+        }
+    }
+
+    fn rule(&mut self) -> Result<RuleDefinition, ParseError> {
+        self.variables.clear();
+
+        let start = self.expect(TokenKind::LeftParen)?.start;
+
+        match self.next().map(|token| token.kind) {
+            Some(TokenKind::Symbol(keyword)) if keyword == "defrule" => {}
+            _ => {
+                self.position -= 1;
+                return self.unexpected("`defrule`");
+            }
+        }
+
+        let name = match self.peek() {
+            Some(TokenKind::Symbol(name)) => name.clone(),
+            _ => return self.unexpected("a rule name"),
+        };
+        self.position += 1;
+
+        let description = match self.peek() {
+            Some(TokenKind::String(description)) => {
+                let description = description.clone();
+                self.position += 1;
+                Some(description)
+            }
+            _ => None,
+        };
+
+        let conditions_start = self.span();
+        let mut conditions = vec![];
+        while self.peek() != Some(&TokenKind::Arrow) {
+            if self.at_end() || self.peek() == Some(&TokenKind::RightParen) {
+                return self.unexpected("a condition or `=>`");
+            }
+            conditions.push(self.condition()?);
+        }
+        let conditions_end = self.span();
+        self.position += 1;
+
+        // Only positive conditions on the top level bind values for the actions
+        let bound = conditions
+            .iter()
+            .filter(|condition| matches!(condition, Condition::Positive { .. }))
+            .flat_map(|condition| condition.variables().map(|(_, id)| id))
+            .collect::<HashSet<_>>();
+
+        let mut actions = vec![];
+        while self.peek() == Some(&TokenKind::LeftParen) {
+            actions.push(self.action(&bound)?);
+        }
+
+        let end = self.expect(TokenKind::RightParen)?.end;
+
+        if let Err(e) = validate_conditions(&conditions) {
+            return Err(ParseError {
+                message: format!("invalid conditions in rule {name}: {e}"),
+                span: Span {
+                    start: conditions_start.start,
+                    end: conditions_end.start,
+                },
+            });
+        }
+
+        Ok(RuleDefinition {
+            name,
+            description,
+            conditions,
+            actions,
+            variables: std::mem::take(&mut self.variables),
+            span: Span { start, end },
+        })
+    }
+
+    fn condition(&mut self) -> Result<Condition, ParseError> {
+        match self.peek() {
+            Some(TokenKind::LeftParen) => Ok(Condition::new_positive(self.pattern()?)),
+            Some(TokenKind::Negation) => {
+                self.position += 1;
+                match self.peek() {
+                    Some(TokenKind::LeftParen) => Ok(Condition::new_negative(self.pattern()?)),
+                    Some(TokenKind::LeftBrace) => {
+                        self.position += 1;
+                        let mut subconditions = vec![];
+                        while self.peek() != Some(&TokenKind::RightBrace) {
+                            if self.at_end() {
+                                return self.unexpected("a condition or `}`");
+                            }
+                            subconditions.push(self.condition()?);
+                        }
+                        if subconditions.is_empty() {
+                            return self.error("negated conjunctions need at least one condition");
+                        }
+                        self.position += 1;
+                        Ok(Condition::new_ncc(subconditions))
+                    }
+                    _ => self.unexpected("a pattern or `{` after `-`"),
+                }
+            }
+            _ => self.unexpected("a condition"),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Vec<ConditionTest>, ParseError> {
+        self.expect(TokenKind::LeftParen)?;
+
+        let mut tests = vec![];
+        while self.peek() != Some(&TokenKind::RightParen) {
+            tests.push(self.term("a variable, symbol or number")?);
+        }
+
+        if tests.is_empty() {
+            return self.error("patterns cannot be empty");
+        }
+
+        self.position += 1;
+        Ok(tests)
+    }
+
+    fn term(&mut self, expected: &str) -> Result<ConditionTest, ParseError> {
+        let test = match self.peek() {
+            Some(TokenKind::Variable(name)) => {
+                let name = name.clone();
+                let id = match self.variables.iter().position(|var| *var == name) {
+                    Some(id) => id,
+                    None => {
+                        self.variables.push(name);
+                        self.variables.len() - 1
+                    }
+                };
+                ConditionTest::Variable(id)
+            }
+            Some(TokenKind::Symbol(name)) => {
+                let name = name.clone();
+                ConditionTest::Constant(self.symbols.intern(&name))
+            }
+            Some(TokenKind::Number(value)) => ConditionTest::Constant(*value),
+            _ => return self.unexpected(expected),
+        };
+        self.position += 1;
+        Ok(test)
+    }
+
+    /// Arguments can only use the `bound` variables, whose values are known for every match.
+    fn action(&mut self, bound: &HashSet<usize>) -> Result<ActionCall, ParseError> {
+        let start = self.expect(TokenKind::LeftParen)?.start;
+
+        let name = match self.peek() {
+            Some(TokenKind::Symbol(name)) => name.clone(),
+            _ => return self.unexpected("an action name"),
+        };
+        self.position += 1;
+
+        let mut arguments = vec![];
+        while self.peek() != Some(&TokenKind::RightParen) {
+            let argument = self.term("an argument or `)`")?;
+            if let ConditionTest::Variable(id) = argument {
+                if !bound.contains(&id) {
+                    self.position -= 1;
+                    let message = format!(
+                        "variable `?{}` is not bound by a positive condition of the rule",
+                        self.variables[id]
+                    );
+                    return self.error(message);
+                }
+            }
+            arguments.push(argument);
+        }
+
+        let end = self.expect(TokenKind::RightParen)?.end;
+
+        Ok(ActionCall {
+            name,
+            arguments,
+            span: Span { start, end },
+        })
+    }
+
+    fn fact(&mut self) -> Result<Vec<usize>, ParseError> {
+        let start = self.span();
+        let fields = self.pattern()?;
+
+        fields
+            .into_iter()
+            .map(|test| match test {
+                ConditionTest::Constant(value) => Ok(value),
+                ConditionTest::Variable(_) => Err(ParseError {
+                    message: "facts cannot contain variables".to_string(),
+                    span: start,
+                }),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConditionTest::*;
+
+    #[test]
+    fn parses_rules() {
+        let mut symbols = SymbolTable::default();
+        symbols.define("on", 10);
+
+        let source = r#"
+            ; Comment
+            (defrule stack "Stacks blocks"
+                (?x ^on ?y)
+                -(?y ^color red)
+                -{ (?y left-of ?z) -(?z ^size 3) }
+                =>
+                (log ?x ?y))
+        "#;
+
+        let rules = parse_rules(source, &mut symbols).unwrap();
+        assert_eq!(rules.len(), 1);
+
+        let rule = &rules[0];
+        let color = symbols.get("color").unwrap();
+        let red = symbols.get("red").unwrap();
+        let left_of = symbols.get("left-of").unwrap();
+        assert!(red >= FIRST_SYMBOL);
+
+        assert_eq!(rule.name, "stack");
+        assert_eq!(rule.description.as_deref(), Some("Stacks blocks"));
+        assert_eq!(rule.variables, ["x", "y", "z"]);
+        assert_eq!(
+            rule.conditions,
+            vec![
+                Condition::new_positive([Variable(0), Constant(10), Variable(1)]),
+                Condition::new_negative([Variable(1), Constant(color), Constant(red)]),
+                Condition::new_ncc(vec![
+                    Condition::new_positive([Variable(1), Constant(left_of), Variable(2)]),
+                    Condition::new_negative([
+                        Variable(2),
+                        Constant(symbols.get("size").unwrap()),
+                        Constant(3)
+                    ]),
+                ]),
+            ]
+        );
+        assert_eq!(rule.actions.len(), 1);
+        assert_eq!(rule.actions[0].name, "log");
+        assert_eq!(rule.actions[0].arguments, [Variable(0), Variable(1)]);
+        assert_eq!(
+            rule.span.start,
+            Location {
+                line: 3,
+                column: 13
+            }
+        );
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let mut symbols = SymbolTable::default();
+
+        let source = "(defrule a (?x on ?y) -(?y on ?z) => (log ?z))\n\
+                      (defrule b -(?x on ?y) (?x on b) => )\n\
+                      (defrule c (?x on ?y) =>)\n\
+                      (defrule d (?x on ?y)";
+
+        let errors = parse_rules(source, &mut symbols).unwrap_err();
+        assert_eq!(errors.len(), 3, "{errors:?}");
+
+        assert_eq!(
+            errors[0].span.start,
+            Location {
+                line: 1,
+                column: 43
+            }
+        );
+        assert!(errors[0].message.contains("?z"));
+
+        assert_eq!(errors[1].span.start.line, 2);
+        assert!(errors[1].message.contains("negated"), "{}", errors[1]);
+
+        assert_eq!(
+            errors[2].to_string(),
+            "4:22: expected a condition or `=>`, found end of input"
+        );
+
+        // Recovery only looks at the failing rule, not at what came before it
+        let source = "(defrule a (?x on ?y) => (log ?x)))\n\
+                      (defrule b (?x on ?y) => (log ?z))\n\
+                      (defrule c (?x on ?y) => (log ?x))\n\
+                      (defrule d (?x on ?y) => (log ?z))";
+        let errors = parse_rules(source, &mut symbols).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.span.start.line)
+                .collect::<Vec<_>>(),
+            [1, 2, 4],
+            "{errors:?}"
+        );
+
+        let (rules, errors) = parse_rules_partially(source, &mut symbols);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            rules
+                .iter()
+                .map(|rule| rule.name.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "c"]
+        );
+
+        let errors = parse_rules("(defrule \"a)", &mut symbols).unwrap_err();
+        assert_eq!(errors[0].message, "unterminated string");
+    }
+
+    #[test]
+    fn parses_facts() {
+        let mut symbols = SymbolTable::default();
+        let facts = parse_facts("(b1 ^on b2) (b2 size 3)", &mut symbols).unwrap();
+        let b2 = symbols.get("b2").unwrap();
+        assert_eq!(facts[0][2], b2);
+        assert_eq!(facts[1], [b2, symbols.get("size").unwrap(), 3]);
+        assert_eq!(symbols.display(b2), "b2");
+        assert_eq!(symbols.display(3), "3");
+
+        assert!(parse_facts("(b1 on ?x)", &mut symbols).is_err());
+    }
+}
@@ -1,13 +1,32 @@
 pub mod display;
+pub mod explain;
+pub mod fork;
 pub mod id;
+pub mod index;
 pub mod item;
 pub mod node;
-
-use item::{Condition, ConstantTest, JoinTest, NegativeJoinResult, Production, Token, Wme};
-use node::{AlphaMemoryNode, NegativeNode, Node};
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+pub mod query;
+pub mod reference;
+#[cfg(feature = "serde")]
+pub mod snapshot;
+pub mod time;
+pub mod transaction;
+pub mod validate;
+#[cfg(feature = "serde")]
+pub mod wal;
+
+use item::{
+    validate_conditions, Activation, AlphaTest, Condition, ConditionError, ConstantTest, JoinTest,
+    NegativeJoinResult, Predicate, Production, Token, VariableLocation, Wme,
+};
+use node::{AlphaMemoryNode, AlphaTestNode, FilterNode, NegativeNode, Node};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    rc::Rc,
+};
 use {
-    item::{conditions_to_constant_tests, AlphaMemoryItem, DUMMY_TOKEN_ID},
+    item::{AlphaMemoryItem, DUMMY_TOKEN_ID},
     node::{BetaMemoryNode, JoinNode, NccNode, NccPartnerNode, ProductionNode},
 };
 
@@ -25,113 +44,256 @@ trait IntoNodeCell: Sized {
     fn to_node_cell(self) -> ReteNode;
 }
 
+/// Options affecting how the Rete treats its working memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReteConfig {
+    /// When enabled, working memory is a set of WMEs. Adding a WME whose fields equal
+    /// those of an existing one returns the existing ID and increments its reference count
+    /// instead of inserting a duplicate. Removing it only retracts it once the count reaches zero.
+    pub set_semantics: bool,
+}
+
 #[derive(Debug)]
 pub struct Rete {
+    pub config: ReteConfig,
+
     /// Token tree root
     pub dummy_top_token: ReteToken,
 
     /// Beta network root
     pub dummy_top_node: ReteNode,
 
-    /// Since every WME is represented as a triple, we only need to do 8 hash table look ups whenever one is added to the
-    /// network to find possibly matching constant tests for it. This removes the need for a constant test network.
+    /// Maps constant tests to the memories of conditions without alpha tests. Whenever a WME is added, we only need
+    /// one hash table look up per existing [shape][Rete::shapes] of its arity to find possibly matching constant tests for it.
+    /// This removes the need for a constant test network.
     pub constant_tests: HashMap<ConstantTest, RcCell<AlphaMemoryNode>>,
 
+    /// The alpha discrimination network. Conditions with [alpha tests][item::AlphaTest] get their
+    /// memories from here instead of `constant_tests`. WMEs get routed to the test nodes
+    /// rooted at each constant test they pass.
+    pub alpha_network: HashMap<ConstantTest, Vec<AlphaTestNode>>,
+
+    /// Counts the keys of `constant_tests` and `alpha_network` by their [shape][ConstantTest::shape].
+    /// Only these shapes are looked up when adding WMEs, which keeps the amount of look ups independent
+    /// of the WME arity.
+    pub shapes: BTreeMap<Vec<bool>, usize>,
+
     /// Maps WME IDs to their corresponding elements for quick removal of tokens
     pub working_memory: HashMap<usize, RcCell<Wme>>,
 
+    /// Maps WME fields to the ID of the WME holding them and the amount of times it was added.
+    /// Only populated when [set semantics][ReteConfig::set_semantics] are enabled.
+    pub wme_set: HashMap<Vec<usize>, (usize, usize)>,
+
     /// Maps WME IDs to Alpha Nodes that contain items which hold the WME
     pub wme_alphas: HashMap<usize, Vec<RcCell<AlphaMemoryNode>>>,
 
+    /// Maps field positions and values to the IDs of the WMEs holding them, see [Rete::find]
+    pub wme_index: HashMap<(usize, usize), HashSet<usize>>,
+
     /// Maps production IDs to their corresponding production nodes
     pub productions: HashMap<usize, ReteNode>,
+
+    /// Maps the names of queries to their production nodes, see [Rete::add_query]
+    pub queries: HashMap<String, ReteNode>,
+
+    /// When set, every change to the Rete gets recorded here before it is applied.
+    /// See [Rete::replay].
+    #[cfg(feature = "serde")]
+    pub log: Option<wal::FactLog>,
+
+    /// The changes buffered by the transaction in progress, if any
+    pub transaction: Option<transaction::Transaction>,
+
+    /// Timestamps added WMEs, see [time::Clock]
+    pub clock: Rc<dyn time::Clock>,
+
+    /// IDs of WMEs with a lifetime ordered by the time they expire at, counting the additions
+    /// of each WME expiring at that time. Entries of removed WMEs are skipped once they are due.
+    pub expirations: BTreeMap<(u64, usize), usize>,
+
+    /// Maps the times WMEs leave the memories of [windows][item::AlphaTest::Within]
+    /// and the WME IDs to the memories they leave. Like `expirations`, entries of removed WMEs
+    /// are skipped once they are due.
+    pub window_exits: BTreeMap<(u64, usize), Vec<RcCell<AlphaMemoryNode>>>,
 }
 
 impl Default for Rete {
     fn default() -> Self {
-        Self::new()
+        Self::new(ReteConfig::default())
     }
 }
 
 impl Rete {
-    fn new() -> Self {
+    pub fn new(config: ReteConfig) -> Self {
         let dummy_top_node = BetaMemoryNode::dummy();
         let dummy_top_token = Token::dummy(&dummy_top_node);
 
         dummy_top_node.borrow_mut().add_token(&dummy_top_token);
 
         Self {
+            config,
             constant_tests: HashMap::new(),
+            alpha_network: HashMap::new(),
+            shapes: BTreeMap::new(),
             wme_alphas: HashMap::new(),
+            wme_index: HashMap::new(),
             working_memory: HashMap::new(),
+            wme_set: HashMap::new(),
             productions: HashMap::new(),
+            queries: HashMap::new(),
+            #[cfg(feature = "serde")]
+            log: None,
+            transaction: None,
+            clock: Rc::new(time::SystemClock),
+            expirations: BTreeMap::new(),
+            window_exits: BTreeMap::new(),
             dummy_top_node,
             dummy_top_token,
         }
     }
 
-    pub fn add_wme(&mut self, wme: Wme) -> usize {
+    /// Adds a WME to the Rete and returns its ID. With set semantics enabled, the ID of
+    /// an existing equal WME gets returned instead if there is one.
+    ///
+    /// During a [transaction][Rete::begin_transaction], the WME only gets added on commit.
+    ///
+    /// WMEs without a timestamp get the current time of the Rete's [clock][time::Clock].
+    /// Expired WMEs are retracted before the new one is added, see [Rete::expire].
+    pub fn add_wme(&mut self, mut wme: Wme) -> usize {
+        let now = self.clock.now();
+        wme.timestamp.get_or_insert(now);
+
+        if self.transaction.is_some() {
+            return self.buffer_add_wme(wme);
+        }
+
+        self.expire();
+        self.insert_wme(wme)
+    }
+
+    /// Adds the timestamped WME without retracting expired WMEs first.
+    pub(in crate::rete) fn insert_wme(&mut self, wme: Wme) -> usize {
+        let now = self.clock.now();
         let id = wme.id;
 
-        println!("-----------\nAdding WME {:?}\n-----------", wme);
+        trace!("-----------\nAdding WME {:?}\n-----------", wme);
+
+        #[cfg(feature = "serde")]
+        self.write_log(wal::LogEntry::AddWme {
+            id,
+            fields: wme.fields.clone(),
+            timestamp: wme.timestamp,
+            lifetime: wme.lifetime,
+        });
+
+        if self.config.set_semantics {
+            let (existing, count) = self.wme_set.entry(wme.fields.clone()).or_insert((id, 0));
+            *count += 1;
+            if *count > 1 {
+                trace!("WME {wme:?} already exists as {existing}, references: {count}");
+                // Every addition expires on its own
+                let existing = *existing;
+                if let Some(at) = wme.expires_at() {
+                    *self.expirations.entry((at, existing)).or_default() += 1;
+                }
+                return existing;
+            }
+        }
 
-        for element in wme.permutations() {
-            let Some(memory) = self.constant_tests.get(&element) else {
-                continue;
-            };
+        if let Some(at) = wme.expires_at() {
+            *self.expirations.entry((at, id)).or_default() += 1;
+        }
 
-            // Index the memories that will hold this WME by its ID
-            if let Some(memories) = self.wme_alphas.get_mut(&id) {
+        let mut memories = vec![];
+
+        let tests = self
+            .shapes
+            .keys()
+            .filter(|shape| shape.len() == wme.arity())
+            .map(|shape| wme.constant_test(shape));
+
+        for element in tests {
+            if let Some(memory) = self.constant_tests.get(&element) {
+                trace!(
+                    "Found existing memory {} for element {:?}",
+                    memory.borrow().id,
+                    element
+                );
                 memories.push(Rc::clone(memory));
-            } else {
-                self.wme_alphas.insert(id, vec![Rc::clone(memory)]);
             }
 
-            let wme = wme.to_cell();
-            self.working_memory.insert(id, Rc::clone(&wme));
+            if let Some(nodes) = self.alpha_network.get(&element) {
+                for node in nodes {
+                    node.collect_memories(&wme, now, &mut memories);
+                }
+            }
+        }
 
-            println!(
-                "Found existing memory {} for element {:?}",
-                memory.borrow().id,
-                element
-            );
+        self.index_wme(&wme);
 
-            activate_alpha_memory(memory, &wme);
+        let timestamp = wme.timestamp;
+        let wme = wme.to_cell();
+        self.working_memory.insert(id, Rc::clone(&wme));
 
+        if memories.is_empty() {
+            trace!("No memory found for WME {id}, inserted to working memory");
             return id;
         }
 
-        println!("No memory found for WME {wme:?}, inserting to working memory");
+        // Index the memories that will hold this WME by its ID
+        self.wme_alphas.insert(id, memories.clone());
 
-        let wme = wme.to_cell();
-        self.working_memory.insert(id, Rc::clone(&wme));
+        for memory in memories.iter() {
+            self.schedule_window_exit(id, timestamp, memory);
+        }
+
+        for memory in memories.iter() {
+            activate_alpha_memory(memory, &wme);
+        }
 
         id
     }
 
+    /// Retracts a WME from the Rete. With set semantics enabled, the WME only gets retracted
+    /// once it has been removed as many times as it was added.
+    ///
+    /// During a [transaction][Rete::begin_transaction], the WME only gets removed on commit.
     pub fn remove_wme(&mut self, id: usize) {
-        println!("Removing WME {id}");
+        if self.transaction.is_some() {
+            return self.buffer_remove_wme(id);
+        }
+
+        if !self.working_memory.contains_key(&id) {
+            trace!("WME {id} does not exist");
+            return;
+        }
+
+        trace!("Removing WME {id}");
+
+        #[cfg(feature = "serde")]
+        self.write_log(wal::LogEntry::RemoveWme { id });
+
+        if self.config.set_semantics {
+            let Some(wme) = self.working_memory.get(&id) else {
+                return;
+            };
+            let fields = wme.borrow().fields.clone();
+            if let Some((_, count)) = self.wme_set.get_mut(&fields) {
+                *count -= 1;
+                if *count > 0 {
+                    trace!("WME {id} still referenced {count} times");
+                    return;
+                }
+                self.wme_set.remove(&fields);
+            }
+        }
 
         // Remove all items representing the wme from the alpha network
         if let Some(memories) = self.wme_alphas.remove(&id) {
             for memory in memories {
-                println!("Removing WME {id} from alpha memory {}", memory.borrow().id);
-                memory
-                    .borrow_mut()
-                    .items
-                    .retain(|item| item.borrow().wme.borrow().id != id);
-
-                // The alpha memory just became empty, left unlink the corresponding
-                // join node
-                if !memory.borrow().items.is_empty() {
-                    continue;
-                }
-
-                for successor in memory.borrow().successors.iter() {
-                    if let Node::Join(join) = &*successor.borrow() {
-                        join.parent.borrow_mut().remove_child(join.id)
-                    }
-                }
+                remove_from_alpha_memory(&memory, id);
             }
         }
 
@@ -139,37 +301,29 @@ impl Rete {
             return;
         };
 
-        println!("Removing WME {} from working memory", wme.borrow());
+        trace!("Removing WME {} from working memory", wme.borrow());
 
-        let mut wme = wme.borrow_mut();
-        let tokens = std::mem::take(&mut wme.tokens);
-        let n_join_results = std::mem::take(&mut wme.negative_join_results);
+        self.unindex_wme(&wme.borrow());
 
-        drop(wme);
+        let tokens = std::mem::take(&mut wme.borrow_mut().tokens);
 
-        // Remove all tokens representing the wme
-        for token in tokens.into_iter() {
+        // Remove all tokens representing the wme, newest first so NCC owners get deleted
+        // before the results in their subnetworks and are not spuriously reactivated
+        for token in tokens.into_iter().rev() {
             Token::delete_self_and_descendants(token)
         }
 
-        // Remove all associated negative join results from the result's owner
-        // and trigger left activation to test for new absence
-        for result in n_join_results {
-            let result = result.borrow();
-
-            let is_empty = result.owner.borrow_mut().remove_join_result(result.id);
+        // Taken only now since deleted owners removed their results from the WME, retracting
+        // those would reactivate matches that no longer exist
+        let n_join_results = std::mem::take(&mut wme.borrow_mut().negative_join_results);
 
-            if is_empty {
-                for child in result.owner.borrow().node().borrow().children() {
-                    activate_left(child, &result.owner, None);
-                }
-            }
-        }
+        retract_negative_join_results(n_join_results);
     }
 
-    /// Adds a production to the Rete. Returns the production's ID for convenience.
-    pub fn add_production(&mut self, production: Production) -> usize {
-        println!(
+    /// Adds a production to the Rete. Returns the production's ID for convenience, or an error
+    /// if its conditions are not scoped in a way the network can evaluate.
+    pub fn add_production(&mut self, production: Production) -> Result<usize, ConditionError> {
+        trace!(
             "------------\nAdding production {}\n------------",
             production.id
         );
@@ -177,6 +331,15 @@ impl Rete {
         let id = production.id;
         let conditions = &production.conditions;
 
+        validate_conditions(conditions)?;
+
+        #[cfg(feature = "serde")]
+        self.write_log(wal::LogEntry::AddProduction {
+            id,
+            name: production.name.as_deref().map(String::from),
+            conditions: conditions.clone(),
+        });
+
         let current_node = self.build_or_share_network_for_conditions(
             &Rc::clone(&self.dummy_top_node),
             conditions,
@@ -191,7 +354,7 @@ impl Rete {
 
         update_new_node_with_matches_from_above(&production);
 
-        id
+        Ok(id)
     }
 
     pub fn remove_production(&mut self, id: usize) -> bool {
@@ -199,20 +362,15 @@ impl Rete {
             return false;
         };
 
-        println!(
+        #[cfg(feature = "serde")]
+        self.write_log(wal::LogEntry::RemoveProduction { id });
+
+        trace!(
             "------------\nRemoving production {}\n------------",
             production.borrow()
         );
 
-        let mut tests = vec![];
-        {
-            let Node::Production(prod) = &*production.borrow() else {
-                panic!("Rust no longer works")
-            };
-            conditions_to_constant_tests(&mut tests, &prod.production.conditions);
-        }
-
-        self.delete_node_and_unused_ancestors(production, &tests);
+        self.delete_node_and_unused_ancestors(production);
 
         true
     }
@@ -225,7 +383,7 @@ impl Rete {
     ) -> ReteNode {
         assert!(!conditions.is_empty(), "LHS of production cannot be empty");
 
-        println!(
+        trace!(
             "Building/sharing network for conditions with parent {}",
             parent.borrow()
         );
@@ -233,7 +391,7 @@ impl Rete {
         let mut current_node = Rc::clone(parent);
 
         for condition in conditions.iter() {
-            println!("Processing condition {:?}", condition);
+            trace!("Processing condition {:?}", condition);
 
             match condition {
                 Condition::Positive { .. } => {
@@ -252,6 +410,18 @@ impl Rete {
                     current_node =
                         self.build_or_share_ncc_nodes(&current_node, subconditions, earlier_conds)
                 }
+                Condition::Test {
+                    predicate,
+                    variables,
+                } => {
+                    let locations = get_variable_locations(variables, earlier_conds);
+                    current_node = build_or_share_filter_node(&current_node, predicate, locations);
+                }
+                Condition::Forall { .. } => {
+                    let subconditions = condition.forall_to_ncc_subconditions().unwrap();
+                    current_node =
+                        self.build_or_share_ncc_nodes(&current_node, &subconditions, earlier_conds)
+                }
             }
 
             earlier_conds.push(condition);
@@ -260,23 +430,25 @@ impl Rete {
         current_node
     }
 
-    fn build_or_share_ncc_nodes<'a>(
+    fn build_or_share_ncc_nodes(
         &mut self,
         parent: &ReteNode,
-        subconditions: &'a [Condition],
-        earlier_conds: &mut Vec<&'a Condition>,
+        subconditions: &[Condition],
+        earlier_conds: &[&Condition],
     ) -> ReteNode {
+        // Bindings from the subnetwork must not leak to the conditions following the NCC
+        let mut subnet_conds = earlier_conds.to_vec();
         let subnet_bottom =
-            self.build_or_share_network_for_conditions(parent, subconditions, earlier_conds);
+            self.build_or_share_network_for_conditions(parent, subconditions, &mut subnet_conds);
 
         let ncc_node = NccNode::new(parent).to_node_cell();
 
-        println!("Built {}", ncc_node.borrow());
+        trace!("Built {}", ncc_node.borrow());
 
         let partner =
             NccPartnerNode::new(&ncc_node, &subnet_bottom, subconditions.len()).to_node_cell();
 
-        println!("Built {}", partner.borrow());
+        trace!("Built {}", partner.borrow());
 
         if let Node::Ncc(ncc) = &mut *ncc_node.borrow_mut() {
             ncc.partner = Some(Rc::clone(&partner))
@@ -298,60 +470,135 @@ impl Rete {
         &mut self,
         condition: &Condition,
     ) -> RcCell<AlphaMemoryNode> {
-        let constant_test = ConstantTest::try_from(condition).unwrap();
+        let constant_test = ConstantTest::from(condition);
+        let alpha_tests = condition.alpha_tests();
 
-        println!("Searching for constant test {constant_test:?}");
+        trace!("Searching for constant test {constant_test:?} with alpha tests {alpha_tests:?}");
 
-        // Check whether an alpha memory like this exists
-        if let Some(alpha_mem) = self.constant_tests.get(&constant_test) {
-            println!("Shared {}", alpha_mem.borrow());
-            return Rc::clone(alpha_mem);
-        }
+        let am = if alpha_tests.is_empty() {
+            // Check whether an alpha memory like this exists
+            if let Some(alpha_mem) = self.constant_tests.get(&constant_test) {
+                trace!("Shared {}", alpha_mem.borrow());
+                return Rc::clone(alpha_mem);
+            }
 
-        // Alpha memory not found, create new one and insert into map
-        let am = AlphaMemoryNode::new().to_cell();
+            // Alpha memory not found, create new one and insert into map
+            let am = AlphaMemoryNode::new().to_cell();
 
-        self.constant_tests.insert(constant_test, Rc::clone(&am));
+            register_shape(&mut self.shapes, &constant_test);
+            self.constant_tests
+                .insert(constant_test.clone(), Rc::clone(&am));
 
-        println!(
-            "Indexing constant test {constant_test:?} to AM {}",
-            am.borrow()
-        );
+            trace!(
+                "Indexing constant test {constant_test:?} to AM {}",
+                am.borrow()
+            );
+
+            am
+        } else {
+            if !self.alpha_network.contains_key(&constant_test) {
+                register_shape(&mut self.shapes, &constant_test);
+            }
+
+            let nodes = self.alpha_network.entry(constant_test.clone()).or_default();
+
+            let (am, created) = AlphaTestNode::build_or_share(nodes, &alpha_tests);
+
+            if !created {
+                trace!("Shared {}", am.borrow());
+                return am;
+            }
+
+            trace!(
+                "Built discrimination path {alpha_tests:?} under {constant_test:?} to AM {}",
+                am.borrow()
+            );
 
-        println!("Searching for matching WMEs for {constant_test:?}");
+            am.borrow_mut().window = alpha_tests
+                .iter()
+                .filter_map(|test| match test {
+                    AlphaTest::Within { duration } => Some(*duration),
+                    _ => None,
+                })
+                .min();
+
+            am
+        };
+
+        trace!("Searching for matching WMEs for {constant_test:?}");
+
+        let now = self.clock.now();
+        let mut matches = vec![];
 
         for wme in self.working_memory.values() {
             let _wme = wme.borrow();
-            if constant_test.matches(&_wme) {
-                println!("Found match: {_wme} for constant test {constant_test:?}");
-                let a_mems = self
-                    .wme_alphas
+            if constant_test.matches(&_wme)
+                && alpha_tests.iter().all(|test| test.matches(&_wme, now))
+            {
+                trace!("Found match: {_wme} for constant test {constant_test:?}");
+                self.wme_alphas
                     .entry(_wme.id)
-                    .or_insert_with(|| vec![Rc::clone(&am)]);
-                for mem in a_mems.iter() {
-                    activate_alpha_memory(mem, wme)
-                }
+                    .or_default()
+                    .push(Rc::clone(&am));
+                matches.push((_wme.id, _wme.timestamp));
+                drop(_wme);
+                activate_alpha_memory(&am, wme)
             }
         }
 
+        for (id, timestamp) in matches {
+            self.schedule_window_exit(id, timestamp, &am);
+        }
+
         am
     }
 
-    fn delete_node_and_unused_ancestors(
-        &mut self,
-        node: ReteNode,
-        constant_tests: &[ConstantTest],
-    ) {
+    /// Removes an alpha memory no longer used by any node from the alpha network.
+    fn delete_alpha_memory(&mut self, alpha_mem: &RcCell<AlphaMemoryNode>) {
+        trace!("Deleting Alpha Mem {}", alpha_mem.borrow());
+
+        let items = std::mem::take(&mut alpha_mem.borrow_mut().items);
+        for item in items {
+            let id = item.borrow().wme.borrow().id;
+            if let Some(memories) = self.wme_alphas.get_mut(&id) {
+                memories.retain(|mem| !Rc::ptr_eq(mem, alpha_mem));
+                if memories.is_empty() {
+                    self.wme_alphas.remove(&id);
+                }
+            }
+        }
+
+        let shapes = &mut self.shapes;
+
+        self.constant_tests.retain(|test, mem| {
+            let retain = !Rc::ptr_eq(mem, alpha_mem);
+            if !retain {
+                unregister_shape(shapes, test);
+            }
+            retain
+        });
+
+        self.alpha_network.retain(|test, nodes| {
+            AlphaTestNode::remove_memory(nodes, alpha_mem);
+            let retain = !nodes.is_empty();
+            if !retain {
+                unregister_shape(shapes, test);
+            }
+            retain
+        });
+    }
+
+    fn delete_node_and_unused_ancestors(&mut self, node: ReteNode) {
         // Used to avoid a mutable reference when recursively deleting node references from tokens
         enum NodeRemove {
             Join {
                 id: usize,
                 parent: ReteNode,
-                left_linked: bool,
                 right_linked: bool,
                 alpha_mem: RcCell<AlphaMemoryNode>,
             },
             Beta(Vec<ReteToken>),
+            Filter(Vec<ReteToken>),
             Negative {
                 right_linked: bool,
                 alpha_mem: RcCell<AlphaMemoryNode>,
@@ -364,20 +611,22 @@ impl Rete {
             NccPartner {
                 ncc_partner_tokens: Vec<ReteToken>,
             },
-            Production,
+            Production(Vec<ReteToken>),
         }
-        println!("Deleting Node {}", node.borrow());
+        trace!("Deleting Node {}", node.borrow());
 
         let remove_result = match &mut *node.borrow_mut() {
             Node::Join(ref mut join) => NodeRemove::Join {
                 id: join.id,
                 parent: Rc::clone(&join.parent),
-                left_linked: join.left_linked,
                 right_linked: join.right_linked,
                 alpha_mem: Rc::clone(&join.alpha_mem),
             },
             Node::Beta(beta) => NodeRemove::Beta(std::mem::take(&mut beta.items)),
-            Node::Production(_) => NodeRemove::Production,
+            Node::Filter(filter) => NodeRemove::Filter(std::mem::take(&mut filter.items)),
+            Node::Production(production) => {
+                NodeRemove::Production(std::mem::take(&mut production.items))
+            }
             Node::Negative(negative) => NodeRemove::Negative {
                 right_linked: negative.right_linked,
                 alpha_mem: Rc::clone(&negative.alpha_mem),
@@ -400,41 +649,26 @@ impl Rete {
                 id,
                 parent,
                 alpha_mem,
-                left_linked,
                 right_linked,
             } => {
                 if right_linked {
-                    alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .retain(|child| child.borrow().id() != node.borrow().id());
+                    alpha_mem.borrow_mut().remove_successor(&node);
                 }
 
-                if left_linked {
-                    if let Node::Beta(ref mut beta) = *parent.borrow_mut() {
-                        beta.all_children.retain(|child| child.borrow().id() != id);
-                    }
+                // Left unlinked joins are only present in `all_children`, the parent
+                // gets deleted below if this was its last child
+                if let Node::Beta(ref mut beta) = *parent.borrow_mut() {
+                    beta.all_children.retain(|child| child.borrow().id() != id);
                 }
 
-                if parent.borrow().all_children().is_empty() {
-                    self.delete_node_and_unused_ancestors(parent, constant_tests)
-                }
-
-                if alpha_mem.borrow().successors.is_empty() {
-                    println!("Deleting Alpha Mem {}", alpha_mem.borrow());
-                    alpha_mem.borrow_mut().items.clear();
-
-                    for test in constant_tests {
-                        let Some(mem) = self.constant_tests.get(test) else {
-                            continue;
-                        };
-                        if mem.borrow().successors.is_empty() {
-                            self.constant_tests.remove(test);
-                        }
-                    }
+                alpha_mem.borrow_mut().reference_count -= 1;
+                if alpha_mem.borrow().reference_count == 0 {
+                    self.delete_alpha_memory(&alpha_mem);
                 }
             }
-            NodeRemove::Beta(tokens) => {
+            NodeRemove::Beta(tokens)
+            | NodeRemove::Filter(tokens)
+            | NodeRemove::Production(tokens) => {
                 for token in tokens.into_iter() {
                     Token::delete_self_and_descendants(token)
                 }
@@ -449,31 +683,19 @@ impl Rete {
                 }
 
                 if right_linked {
-                    alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .retain(|child| child.borrow().id() != node.borrow().id());
+                    alpha_mem.borrow_mut().remove_successor(&node);
                 }
 
-                if alpha_mem.borrow().successors.is_empty() {
-                    println!("Deleting Alpha Mem {}", alpha_mem.borrow());
-                    alpha_mem.borrow_mut().items.clear();
-
-                    for test in constant_tests {
-                        let Some(mem) = self.constant_tests.get(test) else {
-                            continue;
-                        };
-                        if mem.borrow().successors.is_empty() {
-                            self.constant_tests.remove(test);
-                        }
-                    }
+                alpha_mem.borrow_mut().reference_count -= 1;
+                if alpha_mem.borrow().reference_count == 0 {
+                    self.delete_alpha_memory(&alpha_mem);
                 }
             }
             NodeRemove::Ncc {
                 partner,
                 ncc_tokens,
             } => {
-                self.delete_node_and_unused_ancestors(partner, constant_tests);
+                self.delete_node_and_unused_ancestors(partner);
                 for token in ncc_tokens.into_iter() {
                     Token::delete_self_and_descendants(token)
                 }
@@ -483,17 +705,17 @@ impl Rete {
                     Token::delete_self_and_descendants(token)
                 }
             }
-            NodeRemove::Production => {}
         }
 
         // Remove this node from its parent and remove the parent if it
         // was the last child
-        if let Some(parent) = node.borrow().parent() {
+        let parent = node.borrow().parent();
+        if let Some(parent) = parent {
             {
                 let id = node.borrow().id();
                 parent.borrow_mut().remove_child(id);
             }
-            println!(
+            trace!(
                 "Parent node {} remaining children {:?}",
                 parent.borrow().id(),
                 parent
@@ -503,24 +725,45 @@ impl Rete {
                     .map(|n| n.borrow().id())
                     .collect::<Vec<_>>()
             );
-            if parent.borrow().children().is_empty() {
-                self.delete_node_and_unused_ancestors(parent, constant_tests);
+            let unused = {
+                let parent = parent.borrow();
+                !parent.is_dummy()
+                    && parent.children().is_empty()
+                    && parent.all_children().is_empty()
+            };
+            if unused {
+                self.delete_node_and_unused_ancestors(parent);
             }
         }
     }
 }
 
+fn register_shape(shapes: &mut BTreeMap<Vec<bool>, usize>, test: &ConstantTest) {
+    *shapes.entry(test.shape()).or_default() += 1;
+}
+
+fn unregister_shape(shapes: &mut BTreeMap<Vec<bool>, usize>, test: &ConstantTest) {
+    let shape = test.shape();
+    let Some(count) = shapes.get_mut(&shape) else {
+        return;
+    };
+    *count -= 1;
+    if *count == 0 {
+        shapes.remove(&shape);
+    }
+}
+
 /// This procedures is triggered whenever a new production enters the system and its job is to find
 /// potential existing matches for the newly created production by checking the parent node
 /// and propagating activations if matches are found.
 fn update_new_node_with_matches_from_above(node: &ReteNode) {
-    println!("Updating node {}", node.borrow());
+    trace!("Updating node {}", node.borrow());
 
     let Some(parent) = node.borrow().parent() else {
         return;
     };
 
-    println!("Updating parent {}", parent.borrow());
+    trace!("Updating parent {}", parent.borrow());
 
     // This avoids keeping the mutable borrow when recursively right activating the join node
     let children = match *parent.borrow_mut() {
@@ -546,6 +789,12 @@ fn update_new_node_with_matches_from_above(node: &ReteNode) {
             }
             return;
         }
+        Node::Filter(ref filter) => {
+            for token in filter.items.iter() {
+                activate_left(node, token, None);
+            }
+            return;
+        }
         Node::Join(ref mut join) => std::mem::replace(&mut join.children, vec![Rc::clone(node)]),
         Node::NccPartner(_) => panic!("NCC partner node cannot have children"),
         Node::Production(_) => panic!("Production node cannot have children"),
@@ -554,8 +803,9 @@ fn update_new_node_with_matches_from_above(node: &ReteNode) {
     // The rest is for the join node procedure since we cannot keep a mutable borrow when
     // activating
 
+    // The items stay in the memory, the new node may share it and has to see them
     let items = match *parent.borrow() {
-        Node::Join(ref join) => std::mem::take(&mut join.alpha_mem.borrow_mut().items),
+        Node::Join(ref join) => join.alpha_mem.borrow().items.clone(),
         _ => unreachable!("Rust no longer works"),
     };
 
@@ -567,8 +817,6 @@ fn update_new_node_with_matches_from_above(node: &ReteNode) {
         panic!("Rust no longer works")
     };
 
-    join.alpha_mem.borrow_mut().items = items;
-
     join.children = children;
 }
 
@@ -582,7 +830,7 @@ fn activate_alpha_memory(alpha_mem_node: &RcCell<AlphaMemoryNode>, wme: &RcCell<
     // having an active borrow when re-linking nodes
     let successors = {
         let mut alpha_mem = alpha_mem_node.borrow_mut();
-        println!("-> Activating Alpha Node: {alpha_mem}");
+        trace!("-> Activating Alpha Node: {alpha_mem}");
         alpha_mem.items.push(Rc::clone(&item));
         std::mem::take(&mut alpha_mem.successors)
     };
@@ -610,7 +858,7 @@ fn activate_alpha_memory(alpha_mem_node: &RcCell<AlphaMemoryNode>, wme: &RcCell<
 /// Right activations are caused by [AlphaMemoryNode]s when [WME][Wme]s are changed or
 /// when new [WME][Wme]s enter the network.
 fn activate_right(node: &ReteNode, wme: &RcCell<Wme>) {
-    println!(
+    trace!(
         "➡️  Right activating {} {}",
         node.borrow()._type(),
         node.borrow().id()
@@ -624,7 +872,7 @@ fn activate_right(node: &ReteNode, wme: &RcCell<Wme>) {
         // The activation comes from an alpha memory that just became non-empty so we need
         // to relink the join node to the beta network.
         if !join.left_linked {
-            println!(
+            trace!(
                 "🔗 Relinking {} to beta memory {}",
                 join.id,
                 join.parent.borrow().id()
@@ -633,15 +881,12 @@ fn activate_right(node: &ReteNode, wme: &RcCell<Wme>) {
             l_link = true;
             // Subsequently if the beta is empty, we need to right unlink the node
             if join.parent.borrow().tokens().is_empty() {
-                println!(
+                trace!(
                     "💥 Right unlinking {} from {}",
                     join.id,
                     join.alpha_mem.borrow().id
                 );
-                join.alpha_mem
-                    .borrow_mut()
-                    .successors
-                    .retain(|suc| suc.borrow().id() != join.id);
+                join.alpha_mem.borrow_mut().remove_successor(node);
                 r_link = false;
             }
         }
@@ -686,6 +931,51 @@ fn activate_right(node: &ReteNode, wme: &RcCell<Wme>) {
         Node::Production(_) => unreachable!("Production nodes are never right activated"),
         Node::Ncc(_) => unreachable!("NCC nodes are never right activated"),
         Node::NccPartner(_) => unreachable!("NCC Partner nodes are never right activated"),
+        Node::Filter(_) => unreachable!("Filter nodes are never right activated"),
+    }
+}
+
+/// Removes the WME's item from the alpha memory and left unlinks the memory's join nodes
+/// if it became empty.
+fn remove_from_alpha_memory(memory: &RcCell<AlphaMemoryNode>, id: usize) {
+    trace!("Removing WME {id} from alpha memory {}", memory.borrow().id);
+    memory
+        .borrow_mut()
+        .items
+        .retain(|item| item.borrow().wme.borrow().id != id);
+
+    // The alpha memory just became empty, left unlink the corresponding
+    // join node
+    if !memory.borrow().items.is_empty() {
+        return;
+    }
+
+    for successor in memory.borrow().successors.iter() {
+        if let Node::Join(join) = &*successor.borrow() {
+            join.parent.borrow_mut().remove_child(join.id)
+        }
+        if let Node::Join(join) = &mut *successor.borrow_mut() {
+            join.left_linked = false;
+        }
+    }
+}
+
+/// Removes the negative join results from their owners and triggers left activation
+/// to test for new absence.
+fn retract_negative_join_results(results: Vec<RcCell<NegativeJoinResult>>) {
+    for result in results {
+        let result = result.borrow();
+
+        let is_empty = result.owner.borrow_mut().remove_join_result(result.id);
+
+        if is_empty {
+            // The owner must not be borrowed while its children get activated
+            let node = Rc::clone(result.owner.borrow().node());
+            let children = node.borrow().children().to_vec();
+            for child in children.iter() {
+                activate_left(child, &result.owner, None);
+            }
+        }
     }
 }
 
@@ -732,7 +1022,7 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
 
     match &mut *node.borrow_mut() {
         Node::Beta(ref mut beta_node) => {
-            println!("⬅️  Left activating beta {}", beta_node.id,);
+            trace!("⬅️  Left activating beta {}", beta_node.id,);
 
             let new_token = Token::new_beta(node, parent_token, wme);
 
@@ -745,9 +1035,11 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
             true
         }
         Node::Join(ref mut join_node) => {
-            println!("⬅️  Left activating join {}", join_node.id);
+            trace!("⬅️  Left activating join {}", join_node.id);
 
-            for item in join_node.alpha_mem.borrow().items.iter() {
+            // Descendants sharing the alpha memory may relink to it while being activated
+            let items = join_node.alpha_mem.borrow().items.clone();
+            for item in items.iter() {
                 if join_test(&join_node.tests, parent_token, &item.borrow().wme.borrow()) {
                     for child in join_node.children.iter() {
                         activate_left(child, parent_token, Some(&item.borrow().wme));
@@ -760,7 +1052,7 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
         Node::Negative(negative_node) => {
             let new_token = Token::new_negative(node, parent_token, wme);
 
-            println!(
+            trace!(
                 "⬅️  Left activating negative {} and appending token {}",
                 negative_node.id,
                 new_token.borrow().id()
@@ -779,11 +1071,12 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
 
                     new_token.borrow_mut().add_join_result(&join_result);
 
-                    if let Some(wme) = wme {
-                        wme.borrow_mut()
-                            .negative_join_results
-                            .push(Rc::clone(&join_result));
-                    }
+                    // Registered with the blocking WME so its removal unblocks the token
+                    item.borrow()
+                        .wme
+                        .borrow_mut()
+                        .negative_join_results
+                        .push(Rc::clone(&join_result));
                 }
             }
 
@@ -797,7 +1090,7 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
             true
         }
         Node::Ncc(ncc_node) => {
-            println!("⬅️  Left activating ncc {}", ncc_node.id,);
+            trace!("⬅️  Left activating ncc {}", ncc_node.id,);
             let new_token = Token::new_ncc(node, parent_token, wme);
 
             ncc_node.items.push(Rc::clone(&new_token));
@@ -805,7 +1098,7 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
             if let Node::NccPartner(ncc_partner) =
                 &mut *ncc_node.partner.as_ref().unwrap().borrow_mut()
             {
-                println!(
+                trace!(
                     "Checking NCC partner {} for new results (len {})",
                     ncc_partner.id,
                     ncc_partner.new_results.len()
@@ -825,7 +1118,7 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
             true
         }
         Node::NccPartner(ncc_partner) => {
-            println!(
+            trace!(
                 "⬅️  Left activating ncc partner {} with parent token {}",
                 ncc_partner.id,
                 parent_token.borrow()
@@ -842,11 +1135,11 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
                 owners_token = parent;
             }
 
-            println!(
+            trace!(
                 "Current owner token {:?}",
                 owners_token.as_ref().map(|t| t.borrow().id())
             );
-            println!(
+            trace!(
                 "Current owner WME {:?}",
                 owners_wme.as_ref().map(|t| t.borrow().id)
             );
@@ -856,17 +1149,18 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
                     let token = token.borrow();
                     token.parent() == owners_token.as_ref() && token.wme() == owners_wme.as_ref()
                 }) {
-                    println!(
+                    trace!(
                         "Found existing owner token {}",
                         owners_token.as_ref().unwrap().borrow()
                     );
+                    new_result.borrow_mut().set_owner(owner);
                     let mut owner = owner.borrow_mut();
                     owner.add_ncc_result(&new_result);
                     let children = std::mem::take(owner.children_mut());
                     drop(owner);
                     Token::delete_descendants(children)
                 } else {
-                    println!("No owner token found for {}", new_result.borrow());
+                    trace!("No owner token found for {}", new_result.borrow());
                     // There was no appropriate owner token already in the NCC's memory. This means
                     // the subnetwork was activated for a new match for the preceding conditions,
                     // and `new_result` emerged from the bottom, but the NCC node hasn't been
@@ -877,16 +1171,42 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
 
             true
         }
+        Node::Filter(filter_node) => {
+            trace!("⬅️  Left activating filter {}", filter_node.id);
+
+            if !filter_test(
+                &filter_node.predicate,
+                &filter_node.locations,
+                parent_token,
+                wme,
+            ) {
+                return true;
+            }
+
+            let new_token = Token::new_beta(node, parent_token, wme);
+
+            filter_node.items.push(Rc::clone(&new_token));
+
+            filter_node
+                .children
+                .retain(|child| activate_left(child, &new_token, None));
+
+            true
+        }
         Node::Production(p_node) => {
-            println!(
+            trace!(
                 "====================\nProduction node activated! {p_node}\n===================="
             );
 
-            p_node
-                .production
-                .activation_channel
-                .send(p_node.production.id)
-                .expect("TODO");
+            let new_token = Token::new_beta(node, parent_token, wme);
+
+            p_node.items.push(Rc::clone(&new_token));
+
+            p_node.notify(Activation::Fired {
+                production: p_node.production.id,
+                token: new_token.borrow().id(),
+                name: p_node.production.name.clone(),
+            });
 
             true
         }
@@ -894,19 +1214,19 @@ fn activate_left(node: &ReteNode, parent_token: &ReteToken, wme: Option<&RcCell<
 }
 
 fn build_or_share_beta_memory_node(parent: &ReteNode) -> ReteNode {
-    println!("Building/sharing beta node with parent {}", parent.borrow());
+    trace!("Building/sharing beta node with parent {}", parent.borrow());
 
     // Look for an existing beta node to share
     for child in parent.borrow().children() {
         if let Node::Beta(ref beta) = *child.borrow() {
-            println!("Shared {beta}");
+            trace!("Shared {beta}");
             return Rc::clone(child);
         }
     }
 
     let new = BetaMemoryNode::new(Some(Rc::clone(parent))).to_node_cell();
 
-    println!("Built {}", new.borrow());
+    trace!("Built {}", new.borrow());
 
     parent.borrow_mut().add_child(&new);
 
@@ -923,9 +1243,8 @@ fn build_or_share_join_node(
     // Look for an existing join node to share
     for child in parent.borrow().all_children() {
         if let Node::Join(node) = &*child.borrow() {
-            if node.tests.as_slice() == tests && *node.alpha_mem.borrow() == *alpha_memory.borrow()
-            {
-                println!("Sharing {node}");
+            if *node.tests == *tests && *node.alpha_mem.borrow() == *alpha_memory.borrow() {
+                trace!("Sharing {node}");
                 return Rc::clone(child);
             }
         }
@@ -943,20 +1262,18 @@ fn build_or_share_join_node(
     }
 
     // Add the newly created node to the alpha memory successors
-    alpha_memory
-        .borrow_mut()
-        .successors
-        .push_back(Rc::clone(&new));
+    {
+        let mut alpha_memory = alpha_memory.borrow_mut();
+        alpha_memory.successors.push_back(Rc::clone(&new));
+        alpha_memory.reference_count += 1;
+    }
 
     // Right unlink if the parent beta memory is empty
     let parent = new.borrow().parent().unwrap();
     if parent.borrow().tokens().is_empty() {
-        alpha_memory
-            .borrow_mut()
-            .successors
-            .retain(|suc| suc.borrow().id() != new.borrow().id());
+        alpha_memory.borrow_mut().remove_successor(&new);
         if let Node::Join(join) = &mut *new.borrow_mut() {
-            println!(
+            trace!(
                 "💥 Right unlinking join {} from {}",
                 join.id,
                 alpha_memory.borrow().id
@@ -967,7 +1284,7 @@ fn build_or_share_join_node(
     } else if alpha_memory.borrow().items.is_empty() {
         parent.borrow_mut().remove_child(new.borrow().id());
         if let Node::Join(join) = &mut *new.borrow_mut() {
-            println!(
+            trace!(
                 "💥 Left unlinking join {} from {}",
                 join.id,
                 parent.borrow()
@@ -976,7 +1293,7 @@ fn build_or_share_join_node(
         }
     }
 
-    println!("Built {}", new.borrow());
+    trace!("Built {}", new.borrow());
 
     new
 }
@@ -1007,8 +1324,8 @@ fn build_or_share_negative_node(
 ) -> ReteNode {
     for child in parent.borrow().children() {
         if let Node::Negative(node) = &*child.borrow() {
-            if *node.alpha_mem.borrow() == *alpha_memory.borrow() && node.tests == tests {
-                println!("Sharing {node}");
+            if *node.alpha_mem.borrow() == *alpha_memory.borrow() && *node.tests == *tests {
+                trace!("Sharing {node}");
                 return Rc::clone(child);
             }
         }
@@ -1020,12 +1337,11 @@ fn build_or_share_negative_node(
 
     let new = new.to_node_cell();
 
-    println!("Built {}", new.borrow());
+    trace!("Built {}", new.borrow());
     {
-        alpha_memory
-            .borrow_mut()
-            .successors
-            .push_back(Rc::clone(&new));
+        let mut alpha_memory = alpha_memory.borrow_mut();
+        alpha_memory.successors.push_back(Rc::clone(&new));
+        alpha_memory.reference_count += 1;
     }
 
     parent.borrow_mut().add_child(&new);
@@ -1033,19 +1349,46 @@ fn build_or_share_negative_node(
     update_new_node_with_matches_from_above(&new);
 
     // The right unlink procedure checks whether the node has any items
-    new.borrow().right_unlink();
+    Node::right_unlink(&new);
     if let Node::Negative(ref mut neg) = *new.borrow_mut() {
-        neg.right_linked = false;
+        if neg.items.is_empty() {
+            neg.right_linked = false;
+        }
     }
 
     new
 }
 
+fn build_or_share_filter_node(
+    parent: &ReteNode,
+    predicate: &Predicate,
+    locations: Vec<VariableLocation>,
+) -> ReteNode {
+    for child in parent.borrow().children() {
+        if let Node::Filter(node) = &*child.borrow() {
+            if node.predicate == *predicate && *node.locations == *locations {
+                trace!("Sharing {node}");
+                return Rc::clone(child);
+            }
+        }
+    }
+
+    let new = FilterNode::new(parent, predicate, locations).to_node_cell();
+
+    trace!("Built {}", new.borrow());
+
+    parent.borrow_mut().add_child(&new);
+
+    update_new_node_with_matches_from_above(&new);
+
+    new
+}
+
 /// Perform variable binding consistency tests for each test in `tests` with the given `token` and `wme`.
 ///
 /// [Join tests][JoinTest] are stored by join and negative nodes and are executed whenever those node are activated.
 fn join_test(tests: &[JoinTest], token: &ReteToken, wme: &Wme) -> bool {
-    println!(
+    trace!(
         "Performing join tests on {tests:?} with WME {} {:?} and token {}",
         wme.id,
         wme.fields,
@@ -1057,7 +1400,7 @@ fn join_test(tests: &[JoinTest], token: &ReteToken, wme: &Wme) -> bool {
 
         // If the tokens are pointing to the dummy token they immediatelly get a pass
         if parent.borrow().id() == DUMMY_TOKEN_ID {
-            println!("Join test successful");
+            trace!("Join test successful");
             return true;
         }
 
@@ -1070,14 +1413,27 @@ fn join_test(tests: &[JoinTest], token: &ReteToken, wme: &Wme) -> bool {
         };
 
         let wme2 = wme2.borrow();
-        println!("Comparing WME {:?} from token {}", wme2.fields, parent.id());
+        trace!("Comparing WME {:?} from token {}", wme2.fields, parent.id());
+
+        if let Some(relation) = test.temporal {
+            if !relation.holds(wme, &wme2) {
+                trace!(
+                    "Temporal test {relation:?} failed for WME {} and {}",
+                    wme.id,
+                    wme2.id
+                );
+                return false;
+            }
+            continue;
+        }
 
         let current_value = wme[test.arg_one];
         let previous_value = wme2[test.arg_two];
 
-        println!(
+        trace!(
             "Testing Current WME {:?} with Previous {:?}, {current_value} != {previous_value}",
-            wme.id, wme2.id,
+            wme.id,
+            wme2.id,
         );
 
         if current_value != previous_value {
@@ -1085,44 +1441,107 @@ fn join_test(tests: &[JoinTest], token: &ReteToken, wme: &Wme) -> bool {
         }
     }
 
-    println!("Join test successful");
+    trace!("Join test successful");
     true
 }
 
+/// Collect the values of the variables at the given `locations` and evaluate the `predicate` with them.
+///
+/// The `token` and `wme` are the ones a filter node is left activated with, so a distance of 0
+/// denotes the `wme` and the rest are found in the token's ancestors.
+fn filter_test(
+    predicate: &Predicate,
+    locations: &[VariableLocation],
+    token: &ReteToken,
+    wme: Option<&RcCell<Wme>>,
+) -> bool {
+    let values = locations
+        .iter()
+        .map(|location| {
+            if location.distance_to_wme == 0 {
+                let wme = wme.expect("Filter node activated without the WME binding its variable");
+                return wme.borrow()[location.field];
+            }
+
+            let parent = Token::nth_parent(Rc::clone(token), location.distance_to_wme - 1);
+            let parent = parent.borrow();
+            let wme = parent
+                .wme()
+                .expect("Token does not hold the WME binding the filter's variable");
+            let value = wme.borrow()[location.field];
+            value
+        })
+        .collect::<Vec<_>>();
+
+    trace!(
+        "Performing filter test {} with values {values:?} and token {}",
+        predicate,
+        token.borrow().id()
+    );
+
+    predicate.test(&values)
+}
+
+/// Returns the location of the most recent binding of each variable in the positive
+/// conditions preceding the current one.
+fn get_variable_locations(
+    variables: &[usize],
+    earlier_conds: &[&Condition],
+) -> Vec<VariableLocation> {
+    let current_condition_num = earlier_conds.len();
+
+    variables
+        .iter()
+        .map(|var| {
+            let (distance, field) = find_variable_binding(*var, earlier_conds)
+                .unwrap_or_else(|| panic!("Variable {var} is not bound by an earlier condition"));
+            VariableLocation {
+                distance_to_wme: current_condition_num - distance,
+                field,
+            }
+        })
+        .collect()
+}
+
+/// Searches the earlier positive conditions for the most recent one binding `var`. Returns its
+/// position counted from the first condition, starting at 1, and the index of the variable in it.
+fn find_variable_binding(var: usize, earlier_conds: &[&Condition]) -> Option<(usize, usize)> {
+    earlier_conds
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(idx, cond)| {
+            // We do not care about variable bindings in previous negative conditions
+            let Condition::Positive { .. } = cond else {
+                trace!("Condition is negative, returning");
+                return None;
+            };
+            cond.variables().find_map(|(cond_idx, v)| {
+                if v == var {
+                    Some((idx + 1, cond_idx))
+                } else {
+                    None
+                }
+            })
+        })
+}
+
 fn get_join_tests_from_condition(
     condition: &Condition,
     earlier_conds: &[&Condition],
 ) -> Vec<JoinTest> {
     let mut result = vec![];
 
-    println!(
+    trace!(
         "Creating join tests from {:?} and earlier {:?}",
-        condition, earlier_conds
+        condition,
+        earlier_conds
     );
 
     let current_condition_num = earlier_conds.len();
 
     for (current_idx, var) in condition.variables() {
-        let Some((distance, prev_idx)) =
-            earlier_conds
-                .iter()
-                .enumerate()
-                .rev()
-                .find_map(|(idx, cond)| {
-                    // We do not care about variable bindings in previous negative conditions
-                    let Condition::Positive { .. } = cond else {
-                        println!("Condition is negative, returning");
-                        return None;
-                    };
-                    cond.variables().find_map(|(cond_idx, v)| {
-                        if v == var {
-                            Some((idx + 1, cond_idx))
-                        } else {
-                            None
-                        }
-                    })
-                })
-        else {
+        let Some((distance, prev_idx)) = find_variable_binding(var, earlier_conds) else {
             continue;
         };
 
@@ -1130,12 +1549,34 @@ fn get_join_tests_from_condition(
             arg_one: current_idx,
             distance_to_wme: current_condition_num - distance,
             arg_two: prev_idx,
+            temporal: None,
         };
 
         result.push(test)
     }
 
-    println!("Created join tests {:?}", result);
+    if let Condition::Positive { temporal_tests, .. } | Condition::Negative { temporal_tests, .. } =
+        condition
+    {
+        for temporal in temporal_tests {
+            let (distance, prev_idx) = find_variable_binding(temporal.variable, earlier_conds)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Variable {} is not bound by an earlier condition",
+                        temporal.variable
+                    )
+                });
+
+            result.push(JoinTest {
+                arg_one: 0,
+                distance_to_wme: current_condition_num - distance,
+                arg_two: prev_idx,
+                temporal: Some(temporal.relation),
+            })
+        }
+    }
+
+    trace!("Created join tests {:?}", result);
     result
 }
 
@@ -1146,7 +1587,7 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let mut rete = Rete::new();
+        let mut rete = Rete::default();
         let conditions = Vec::from([Condition::new_positive([
             ConditionTest::Variable(1),
             ConditionTest::Constant(2),
@@ -1155,10 +1596,40 @@ mod tests {
 
         let (tx, _rx) = std::sync::mpsc::channel();
 
-        rete.add_production(Production::new(&conditions, tx));
+        rete.add_production(Production::new(&conditions, tx))
+            .unwrap();
         rete.add_wme(Wme::new([1, 2, 3]));
     }
 
+    #[test]
+    fn set_semantics_with_reused_id() {
+        let mut rete = Rete::new(ReteConfig {
+            set_semantics: true,
+        });
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let conditions = [Condition::new_positive([
+            ConditionTest::Variable(1),
+            ConditionTest::Constant(2),
+            ConditionTest::Variable(3),
+        ])];
+        rete.add_production(Production::new(&conditions, tx))
+            .unwrap();
+
+        let id = rete.add_wme(Wme::new([1, 2, 3]));
+
+        // A WME added again under its own ID is a duplicate as well
+        let mut duplicate = Wme::new([1, 2, 3]);
+        duplicate.id = id;
+        assert_eq!(rete.add_wme(duplicate), id);
+        assert_eq!(rx.try_iter().count(), 1);
+
+        rete.remove_wme(id);
+        assert!(rete.working_memory.contains_key(&id));
+        rete.remove_wme(id);
+        assert!(rete.working_memory.is_empty());
+    }
+
     #[test]
     fn join_test_to_condition() {
         use ConditionTest::*;
@@ -1174,7 +1645,8 @@ mod tests {
             JoinTest {
                 arg_one: 0,
                 distance_to_wme: 1,
-                arg_two: 2
+                arg_two: 2,
+                temporal: None
             }
         );
 
@@ -1183,7 +1655,8 @@ mod tests {
             JoinTest {
                 arg_one: 1,
                 distance_to_wme: 0,
-                arg_two: 2
+                arg_two: 2,
+                temporal: None
             }
         );
 
@@ -1202,7 +1675,8 @@ mod tests {
             JoinTest {
                 arg_one: 0,
                 distance_to_wme: 1,
-                arg_two: 0
+                arg_two: 0,
+                temporal: None
             }
         );
 
@@ -1221,7 +1695,8 @@ mod tests {
             JoinTest {
                 arg_one: 0,
                 distance_to_wme: 0,
-                arg_two: 2
+                arg_two: 2,
+                temporal: None
             }
         );
         assert_eq!(
@@ -1229,11 +1704,119 @@ mod tests {
             JoinTest {
                 arg_one: 2,
                 distance_to_wme: 1,
-                arg_two: 0
+                arg_two: 0,
+                temporal: None
             }
         );
     }
 
+    #[test]
+    fn temporal_join_tests() {
+        use item::{ConditionError, TemporalRelation};
+        use ConditionTest::*;
+
+        let relation = TemporalRelation::Before { min: 0, max: 10 };
+        let earlier = [
+            &Condition::new_positive([Variable(0), Constant(1), Variable(1)]),
+            &Condition::new_positive([Variable(2), Constant(1), Variable(3)]),
+        ];
+        let condition = Condition::new_positive([Variable(4), Constant(2), Variable(1)])
+            .with_temporal_test(relation, 0);
+
+        assert_eq!(
+            get_join_tests_from_condition(&condition, &earlier),
+            [
+                JoinTest {
+                    arg_one: 2,
+                    distance_to_wme: 1,
+                    arg_two: 2,
+                    temporal: None
+                },
+                JoinTest {
+                    arg_one: 0,
+                    distance_to_wme: 1,
+                    arg_two: 0,
+                    temporal: Some(relation)
+                }
+            ]
+        );
+
+        let unbound = Condition::new_negative([Variable(0), Constant(2), Constant(3)])
+            .with_temporal_test(relation, 5);
+        assert_eq!(
+            validate_conditions(&[earlier[0].clone(), unbound]),
+            Err(ConditionError::UnboundTemporalVariable { variable: 5 })
+        );
+    }
+
+    #[test]
+    fn condition_scoping() {
+        use item::{AlphaTest, ConditionError};
+        use ConditionTest::*;
+
+        let red = Condition::new_positive([Variable(0), Constant(1), Constant(2)]);
+        let on = Condition::new_positive([Variable(1), Constant(3), Variable(0)]);
+        let not_on = Condition::new_negative([Variable(1), Constant(3), Variable(0)]);
+
+        assert_eq!(validate_conditions(&[]), Err(ConditionError::Empty));
+        assert_eq!(validate_conditions(&[red.clone(), not_on.clone()]), Ok(()));
+        assert_eq!(
+            validate_conditions(&[not_on.clone(), red.clone()]),
+            Err(ConditionError::NegatedBeforeBound { variable: 0 })
+        );
+
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let mut rete = Rete::default();
+        assert_eq!(
+            rete.add_production(Production::new(&[not_on.clone(), red.clone()], tx)),
+            Err(ConditionError::NegatedBeforeBound { variable: 0 })
+        );
+        assert!(rete.productions.is_empty());
+        assert_eq!(
+            validate_conditions(&[Condition::new_ncc(vec![on.clone()]), red.clone()]),
+            Err(ConditionError::NegatedBeforeBound { variable: 0 })
+        );
+
+        let forall = Condition::new_forall([Variable(0), Constant(1), Constant(2)], vec![on]);
+        assert_eq!(validate_conditions(std::slice::from_ref(&forall)), Ok(()));
+        assert_eq!(
+            validate_conditions(&[forall, red.clone()]),
+            Err(ConditionError::NegatedBeforeBound { variable: 0 })
+        );
+
+        let unconstrained = Condition::new_forall(
+            [Variable(0), Constant(1), Constant(2)],
+            vec![Condition::new_positive([
+                Variable(1),
+                Constant(3),
+                Constant(4),
+            ])],
+        );
+        assert_eq!(
+            validate_conditions(&[unconstrained]),
+            Err(ConditionError::UnconstrainedForall)
+        );
+
+        // The pattern may be constrained by a variable bound before the forall
+        let outer = Condition::new_forall([Variable(0), Constant(1), Constant(2)], vec![not_on]);
+        assert_eq!(validate_conditions(&[red.clone(), outer]), Ok(()));
+
+        let empty = Condition::new_forall([Variable(0), Constant(1), Constant(2)], vec![]);
+        assert_eq!(validate_conditions(&[empty]), Err(ConditionError::Empty));
+
+        let test = Condition::new_test(Predicate::new(|_| true), vec![0, 1]);
+        assert_eq!(
+            validate_conditions(&[red.clone(), test]),
+            Err(ConditionError::UnboundTestVariable { variable: 1 })
+        );
+
+        let out_of_bounds = red.with_alpha_test(AlphaTest::NotEqual { field: 3, value: 0 });
+        assert_eq!(
+            validate_conditions(&[out_of_bounds]),
+            Err(ConditionError::AlphaTestOutOfBounds { field: 3, arity: 3 })
+        );
+    }
+
     #[test]
     fn nth_parent_works() {
         let beta = BetaMemoryNode::new(None);
@@ -1,12 +1,14 @@
 use super::{
+    explain::{Blocking, ConditionExplanation, Explanation, NodeRef, WhyNot},
     item::{
-        AlphaMemoryItem, Condition, ConstantTest, NegativeJoinResult, Production, Token, TokenBase,
-        Wme,
+        Activation, AlphaMemoryItem, AlphaTest, Condition, ConditionError, ConstantTest,
+        NegativeJoinResult, Predicate, Production, TemporalRelation, Token, TokenBase, Wme,
     },
     node::{
-        AlphaMemoryNode, BetaMemoryNode, JoinNode, NccNode, NccPartnerNode, NegativeNode, Node,
-        ProductionNode, DUMMY_NODE_ID,
+        AlphaMemoryNode, AlphaTestNode, BetaMemoryNode, FilterNode, JoinNode, NccNode,
+        NccPartnerNode, NegativeNode, Node, ProductionNode, DUMMY_NODE_ID,
     },
+    validate::Violation,
     RcCell, Rete, ReteNode, ReteToken,
 };
 use std::{
@@ -75,6 +77,32 @@ impl Display for Node {
             Node::NccPartner(partner) => {
                 write!(f, "{}", partner)
             }
+            Node::Filter(filter) => {
+                write!(f, "{}", filter)
+            }
+        }
+    }
+}
+
+impl Display for Activation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let kind = match self {
+            Activation::Fired { .. } => "Fired",
+            Activation::Retracted { .. } => "Retracted",
+        };
+        match self.name() {
+            Some(name) => write!(
+                f,
+                "{kind} {name} (production {}, token {})",
+                self.production(),
+                self.token()
+            ),
+            None => write!(
+                f,
+                "{kind} production {}, token {}",
+                self.production(),
+                self.token()
+            ),
         }
     }
 }
@@ -97,8 +125,9 @@ impl Display for Production {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(
             f,
-            "Production {{ id: {}, conditions: {} }}",
+            "Production {{ id: {}, name: {:?}, conditions: {} }}",
             self.id,
+            self.name.as_deref().unwrap_or(""),
             self.conditions.iter().fold(String::new(), |mut acc, el| {
                 write!(acc, "{}", el).unwrap();
                 acc
@@ -111,26 +140,46 @@ impl Display for Condition {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         let mut buf = String::new();
         match self {
-            Condition::Positive { test } => {
+            Condition::Positive {
+                test,
+                alpha_tests,
+                temporal_tests,
+            } => {
                 write!(buf, "P[")?;
                 for (i, t) in test.iter().enumerate() {
-                    let delim = if i == 2 { "" } else { "-" };
+                    let delim = if i + 1 == test.len() { "" } else { "-" };
                     match t {
                         super::item::ConditionTest::Constant(id) => write!(buf, "C({id}){delim}")?,
                         super::item::ConditionTest::Variable(id) => write!(buf, "V({id}){delim}")?,
                     }
                 }
+                for t in alpha_tests {
+                    write!(buf, " {t}")?;
+                }
+                for t in temporal_tests {
+                    write!(buf, " {} V({})", t.relation, t.variable)?;
+                }
                 write!(buf, "], ")?;
             }
-            Condition::Negative { test } => {
+            Condition::Negative {
+                test,
+                alpha_tests,
+                temporal_tests,
+            } => {
                 write!(buf, "N[")?;
                 for (i, t) in test.iter().enumerate() {
-                    let delim = if i == 2 { "" } else { "-" };
+                    let delim = if i + 1 == test.len() { "" } else { "-" };
                     match t {
                         super::item::ConditionTest::Constant(id) => write!(buf, "C({id}){delim}")?,
                         super::item::ConditionTest::Variable(id) => write!(buf, "V({id}){delim}")?,
                     }
                 }
+                for t in alpha_tests {
+                    write!(buf, " {t}")?;
+                }
+                for t in temporal_tests {
+                    write!(buf, " {} V({})", t.relation, t.variable)?;
+                }
                 write!(buf, "], ")?;
             }
             Condition::NegativeConjunction { subconditions } => {
@@ -140,11 +189,343 @@ impl Display for Condition {
                 }
                 write!(buf, "}}, ")?;
             }
+            Condition::Test {
+                predicate,
+                variables,
+            } => {
+                write!(buf, "T{{{predicate}, {variables:?}}}, ")?;
+            }
+            Condition::Forall {
+                pattern,
+                constraints,
+            } => {
+                write!(buf, "FORALL{{{}", Condition::new_positive(pattern.clone()))?;
+                for t in constraints {
+                    write!(buf, "{t}")?
+                }
+                write!(buf, "}}, ")?;
+            }
         }
         write!(f, "{buf}")
     }
 }
 
+impl Display for AlphaTest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            AlphaTest::NotEqual { field, value } => write!(f, "F{field}!={value}"),
+            AlphaTest::Less { field, value } => write!(f, "F{field}<{value}"),
+            AlphaTest::Greater { field, value } => write!(f, "F{field}>{value}"),
+            AlphaTest::Range { field, min, max } => write!(f, "F{field} in {min}..={max}"),
+            AlphaTest::OneOf { field, values } => write!(f, "F{field} in {values:?}"),
+            AlphaTest::FieldsEqual {
+                field_one,
+                field_two,
+            } => write!(f, "F{field_one}==F{field_two}"),
+            AlphaTest::Within { duration } => write!(f, "within {duration}ms"),
+        }
+    }
+}
+
+impl Display for TemporalRelation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            TemporalRelation::Before { min, max } => write!(f, "before[{min}ms, {max}ms]"),
+            TemporalRelation::After { min, max } => write!(f, "after[{min}ms, {max}ms]"),
+            TemporalRelation::During => write!(f, "during"),
+            TemporalRelation::Includes => write!(f, "includes"),
+            TemporalRelation::Overlaps => write!(f, "overlaps"),
+            TemporalRelation::Coincides { tolerance } => write!(f, "coincides[{tolerance}ms]"),
+        }
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(
+            f,
+            "Production {} fired for token {}",
+            self.production, self.token
+        )?;
+        for condition in self.conditions.iter() {
+            writeln!(f, "  {condition}")?;
+        }
+        write!(f, "  bindings: {:?}", self.bindings)
+    }
+}
+
+/// The name of a named predicate, the ID of others.
+impl Display for Predicate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.name() {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+impl Display for NodeRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{} {}", self.kind, self.id)
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Violation::TokenNotInNode { token, node } => {
+                write!(f, "token {token} is not held by its node {node}")
+            }
+            Violation::TokenNodeMismatch { token, node } => {
+                write!(f, "{node} holds token {token} of another node")
+            }
+            Violation::UnreachableToken { token, node } => {
+                write!(
+                    f,
+                    "{node} holds token {token} which is not in the token tree"
+                )
+            }
+            Violation::TokenParentMismatch { token, parent } => {
+                write!(f, "token {token} is a child of {parent} but not its parent")
+            }
+            Violation::TokenNotInWme { token, wme } => {
+                write!(f, "token {token} is not held by its WME {wme}")
+            }
+            Violation::StaleWmeToken { wme, token } => {
+                write!(f, "WME {wme} holds stale token {token}")
+            }
+            Violation::WmeNotInWorkingMemory { wme } => {
+                write!(f, "WME {wme} is referenced but not in working memory")
+            }
+            Violation::JoinResultNotInWme { token, wme } => {
+                write!(
+                    f,
+                    "negative join result of token {token} is not held by WME {wme}"
+                )
+            }
+            Violation::StaleJoinResult { wme, token } => {
+                write!(
+                    f,
+                    "WME {wme} holds a negative join result not held by token {token}"
+                )
+            }
+            Violation::NccResultOwnerMismatch { token, owner } => {
+                write!(f, "NCC result {token} is not held by its owner {owner}")
+            }
+            Violation::PendingNccResults { node } => {
+                write!(f, "{node} holds undelivered results")
+            }
+            Violation::NccPartnerMismatch { node } => {
+                write!(f, "{node} is not paired with its partner")
+            }
+            Violation::RightLinkMismatch {
+                node,
+                alpha_memory,
+                right_linked,
+            } => {
+                if *right_linked {
+                    write!(
+                        f,
+                        "{node} is right linked but not a successor of alpha memory {alpha_memory}"
+                    )
+                } else {
+                    write!(
+                        f,
+                        "{node} is right unlinked but a successor of alpha memory {alpha_memory}"
+                    )
+                }
+            }
+            Violation::DuplicateSuccessor { node, alpha_memory } => {
+                write!(
+                    f,
+                    "{node} is a successor of alpha memory {alpha_memory} more than once"
+                )
+            }
+            Violation::LeftLinkMismatch {
+                node,
+                parent,
+                left_linked,
+            } => {
+                if *left_linked {
+                    write!(f, "{node} is left linked but not a child of {parent}")
+                } else {
+                    write!(f, "{node} is left unlinked but a child of {parent}")
+                }
+            }
+            Violation::ChildParentMismatch { node, parent } => {
+                write!(f, "{node} is a child of {parent} but not its parent")
+            }
+            Violation::UnregisteredAlphaItem { wme, alpha_memory } => {
+                write!(
+                    f,
+                    "alpha memory {alpha_memory} holds WME {wme} which does not list it"
+                )
+            }
+            Violation::MissingAlphaItem { wme, alpha_memory } => {
+                write!(
+                    f,
+                    "WME {wme} lists alpha memory {alpha_memory} which does not hold it"
+                )
+            }
+            Violation::IndexMismatch { wme } => {
+                write!(f, "field index disagrees with the fields of WME {wme}")
+            }
+        }
+    }
+}
+
+impl Display for ConditionExplanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            ConditionExplanation::Matched {
+                condition,
+                wme_id,
+                fields,
+            } => write!(f, "{condition}matched WME {wme_id} {fields:?}"),
+            ConditionExplanation::Absent { condition, pattern } => {
+                let pattern = pattern
+                    .iter()
+                    .map(|field| field.map_or("_".to_string(), |field| field.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "{condition}no WME matching [{pattern}]")
+            }
+            ConditionExplanation::NoConjunction { condition } => {
+                write!(f, "{condition}no match for the subconditions")
+            }
+            ConditionExplanation::Passed { condition, values } => {
+                write!(f, "{condition}held for {values:?}")
+            }
+        }
+    }
+}
+
+impl Display for WhyNot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match (self.fired, self.deepest) {
+            (true, _) => writeln!(f, "Production {} has matches", self.production)?,
+            (false, Some(node)) => writeln!(
+                f,
+                "Production {} has no matches, deepest node reached: {}({})",
+                self.production, node.kind, node.id
+            )?,
+            (false, None) => writeln!(
+                f,
+                "Production {} has no matches, no node on its path holds tokens",
+                self.production
+            )?,
+        }
+        for diagnostic in self.path.iter() {
+            write!(
+                f,
+                "  {}({}) tokens: {}",
+                diagnostic.node.kind, diagnostic.node.id, diagnostic.tokens
+            )?;
+            if let Some((id, items)) = diagnostic.alpha_memory {
+                write!(f, ", alpha memory {id}: {items} items")?;
+                if items == 0 {
+                    write!(f, " (empty)")?;
+                }
+            }
+            if !diagnostic.left_linked {
+                write!(f, ", left unlinked")?;
+            }
+            if !diagnostic.right_linked {
+                write!(f, ", right unlinked")?;
+            }
+            writeln!(f)?;
+            for blocking in diagnostic.blocking.iter() {
+                match blocking {
+                    Blocking::JoinResult {
+                        token,
+                        wme_id,
+                        fields,
+                    } => writeln!(f, "    token {token} blocked by WME {wme_id} {fields:?}")?,
+                    Blocking::NccResults { token, results } => {
+                        writeln!(f, "    token {token} blocked by NCC results {results:?}")?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Display for super::snapshot::RestoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            super::snapshot::RestoreError::UnresolvedPredicate {
+                production,
+                predicate,
+            } => write!(
+                f,
+                "predicate {predicate} used by production {production} was not supplied"
+            ),
+            super::snapshot::RestoreError::MissingAction { production } => {
+                write!(f, "no action was supplied for production {production}")
+            }
+            super::snapshot::RestoreError::InvalidConditions { production, error } => {
+                write!(f, "production {production} has invalid conditions: {error}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Display for super::wal::ReplayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            super::wal::ReplayError::Io(e) => write!(f, "could not read fact log: {e}"),
+            super::wal::ReplayError::Parse { line, message } => {
+                write!(f, "invalid fact log entry on line {line}: {message}")
+            }
+            super::wal::ReplayError::Restore(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Display for super::query::QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            super::query::QueryError::DuplicateName(name) => {
+                write!(f, "query {name} already exists")
+            }
+            super::query::QueryError::InvalidConditions(e) => {
+                write!(f, "invalid conditions: {e}")
+            }
+        }
+    }
+}
+
+impl Display for ConditionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            ConditionError::Empty => write!(f, "condition list cannot be empty"),
+            ConditionError::NegatedBeforeBound { variable } => write!(
+                f,
+                "variable {variable} is tested in a negated condition before it is bound"
+            ),
+            ConditionError::UnconstrainedForall => write!(
+                f,
+                "forall constraints do not reference any variable bound by its pattern"
+            ),
+            ConditionError::UnboundTestVariable { variable } => write!(
+                f,
+                "variable {variable} is used in a test before it is bound"
+            ),
+            ConditionError::AlphaTestOutOfBounds { field, arity } => write!(
+                f,
+                "alpha test accesses field {field} of a pattern with arity {arity}"
+            ),
+            ConditionError::UnboundTemporalVariable { variable } => write!(
+                f,
+                "variable {variable} is used in a temporal test before it is bound"
+            ),
+        }
+    }
+}
+
 impl Display for NegativeNode {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(
@@ -166,6 +547,27 @@ impl Display for NegativeNode {
     }
 }
 
+impl Display for FilterNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "Filter {{ id: {}, parent: {:?}, children: {:?}, items: {:?}, predicate: {}, locations: {:?} }}",
+            self.id,
+            self.parent.try_borrow().map(|p| p.id()),
+            self.children
+                .iter()
+                .map(|node| node.borrow().id())
+                .collect::<Vec<_>>(),
+            self.items
+                .iter()
+                .map(|item| item.borrow().id())
+                .collect::<Vec<_>>(),
+            self.predicate,
+            self.locations
+        )
+    }
+}
+
 impl Display for BetaMemoryNode {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(
@@ -245,7 +647,7 @@ impl Display for Token {
                         .try_borrow()
                         .map_or("borrowed".to_string(), |t| t.id().to_string()),
                     wme.as_ref()
-                        .map(|wme| wme.try_borrow().map_or([0, 0, 0], |wme| wme.fields)),
+                        .map(|wme| wme.try_borrow().map_or(vec![], |wme| wme.fields.clone())),
                     node.try_borrow()
                         .map_or("borrowed".to_string(), |n| n.id().to_string()),
                     children
@@ -285,7 +687,7 @@ impl Display for Token {
                         .try_borrow()
                         .map_or("borrowed".to_string(), |t| t.id().to_string()),
                     wme.as_ref()
-                        .map(|wme| wme.try_borrow().map_or([0, 0, 0], |wme| wme.fields)),
+                        .map(|wme| wme.try_borrow().map_or(vec![], |wme| wme.fields.clone())),
                     node.try_borrow()
                         .map_or("borrowed".to_string(), |n| n.id().to_string()),
                     children
@@ -329,7 +731,7 @@ impl Display for Token {
                         .map_or("borrowed".to_string(), |t| t.id().to_string()),
                     wme
                         .as_ref()
-                        .map(|wme| wme.try_borrow().map_or([0, 0, 0], |wme| wme.fields)),
+                        .map(|wme| wme.try_borrow().map_or(vec![], |wme| wme.fields.clone())),
                     node
                         .try_borrow()
                         .map_or("borrowed".to_string(), |n| n.id().to_string()),
@@ -456,6 +858,8 @@ impl Rete {
         write_beta_network(&mut buf, &self.dummy_top_node);
         writeln!(buf, "\nALPHA NETWORK\n").unwrap();
         write_alpha_network(&mut buf, &self.constant_tests);
+        writeln!(buf, "\nDISCRIMINATION NETWORK\n").unwrap();
+        write_discrimination_network(&mut buf, &self.alpha_network);
         writeln!(buf, "\nPRODUCTIONS\n").unwrap();
         write_productions(&mut buf, &self.productions);
         std::fs::write(path, buf)?;
@@ -463,6 +867,42 @@ impl Rete {
     }
 }
 
+impl Rete {
+    /// Working memory, one WME per line ordered by ID.
+    pub fn wmes_to_string(&self) -> String {
+        let mut buf = String::new();
+        write_wmes(&mut buf, &self.working_memory);
+        buf
+    }
+
+    /// The token tree, starting at the dummy top token.
+    pub fn tokens_to_string(&self) -> String {
+        let mut buf = String::new();
+        write_tokens(&mut buf, &self.dummy_top_token);
+        buf
+    }
+
+    /// The alpha memories along with the constant tests and discrimination network leading to them.
+    pub fn alpha_memories_to_string(&self) -> String {
+        let mut buf = String::new();
+        write_alpha_network(&mut buf, &self.constant_tests);
+        write_discrimination_network(&mut buf, &self.alpha_network);
+        buf
+    }
+
+    pub fn beta_network_to_string(&self) -> String {
+        let mut buf = String::new();
+        write_beta_network(&mut buf, &self.dummy_top_node);
+        buf
+    }
+
+    pub fn productions_to_string(&self) -> String {
+        let mut buf = String::new();
+        write_productions(&mut buf, &self.productions);
+        buf
+    }
+}
+
 fn write_productions(buf: &mut String, prods: &HashMap<usize, ReteNode>) {
     let mut items = prods.iter().collect::<Vec<_>>();
     items.sort_by(|a, b| a.0.cmp(b.0));
@@ -483,13 +923,43 @@ fn write_wmes(buf: &mut String, wmes: &HashMap<usize, RcCell<Wme>>) {
 
 fn write_alpha_network(buf: &mut String, alpha: &HashMap<ConstantTest, RcCell<AlphaMemoryNode>>) {
     let mut items = alpha.iter().collect::<Vec<_>>();
-    items.sort_by(|a, b| a.1.borrow().id.cmp(&b.1.borrow().id));
+    items.sort_by_key(|a| a.1.borrow().id);
     for (test, alpha) in items {
         let count = Rc::strong_count(alpha);
         writeln!(buf, "{:?} :\n{}\n, refs: {}", test, alpha.borrow(), count).unwrap();
     }
 }
 
+fn write_discrimination_network(
+    buf: &mut String,
+    alpha: &HashMap<ConstantTest, Vec<AlphaTestNode>>,
+) {
+    fn write_nodes(buf: &mut String, nodes: &[AlphaTestNode], depth: usize) {
+        for node in nodes {
+            write!(
+                buf,
+                "{}AlphaTest {{ id: {}, test: {} }}",
+                "  ".repeat(depth),
+                node.id,
+                node.test
+            )
+            .unwrap();
+            match node.memory {
+                Some(ref memory) => writeln!(buf, " -> {}", memory.borrow()).unwrap(),
+                None => writeln!(buf).unwrap(),
+            }
+            write_nodes(buf, &node.children, depth + 1);
+        }
+    }
+
+    let mut items = alpha.iter().collect::<Vec<_>>();
+    items.sort_by_key(|a| a.1.first().map(|node| node.id));
+    for (test, nodes) in items {
+        writeln!(buf, "{:?} :", test).unwrap();
+        write_nodes(buf, nodes, 1);
+    }
+}
+
 fn write_beta_network(buf: &mut String, node: &ReteNode) {
     let count = Rc::strong_count(node);
     let node = node.borrow();
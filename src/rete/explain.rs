@@ -0,0 +1,282 @@
+use super::{
+    item::{Activation, Condition, ConditionTest, Token, Wme},
+    node::Node,
+    RcCell, Rete, ReteNode, ReteToken,
+};
+use std::{collections::BTreeMap, rc::Rc};
+
+/// Describes why a production fired, i.e. which WMEs matched its positive conditions and
+/// what had to be absent for its negated ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub production: usize,
+
+    /// The production token representing the match
+    pub token: usize,
+
+    /// Values of the variables bound by the match
+    pub bindings: BTreeMap<usize, usize>,
+
+    /// One entry per condition of the production, in order
+    pub conditions: Vec<ConditionExplanation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionExplanation {
+    /// A positive condition matched by the WME with the given ID and fields.
+    Matched {
+        condition: Condition,
+        wme_id: usize,
+        fields: Vec<usize>,
+    },
+
+    /// A negative condition, no WME matched the pattern. Fields are `None` where the pattern
+    /// contains a variable not bound by the match.
+    Absent {
+        condition: Condition,
+        pattern: Vec<Option<usize>>,
+    },
+
+    /// An NCC or forall condition, its subconditions had no match extending this one.
+    NoConjunction { condition: Condition },
+
+    /// A test condition whose predicate held for the values of its variables.
+    Passed {
+        condition: Condition,
+        values: Vec<usize>,
+    },
+}
+
+impl Rete {
+    /// Explains the match that caused the activation by walking its token chain up to the
+    /// dummy top token.
+    ///
+    /// Returns `None` if the production or its token no longer exist.
+    pub fn explain(&self, activation: &Activation) -> Option<Explanation> {
+        let production = self.productions.get(&activation.production())?;
+        let production = production.borrow();
+        let Node::Production(ref p_node) = *production else {
+            return None;
+        };
+
+        let token = p_node
+            .items
+            .iter()
+            .find(|token| token.borrow().id() == activation.token())?;
+
+        let mut wmes = token_wmes(token).into_iter();
+        let mut bindings = BTreeMap::new();
+        let mut conditions = vec![];
+
+        for condition in p_node.production.conditions.iter() {
+            let explanation = match condition {
+                Condition::Positive { test, .. } => {
+                    let wme = wmes.next()?;
+                    let wme = wme.borrow();
+                    for (field, t) in test.iter().enumerate() {
+                        if let ConditionTest::Variable(var) = t {
+                            bindings.entry(*var).or_insert(wme.fields[field]);
+                        }
+                    }
+                    ConditionExplanation::Matched {
+                        condition: condition.clone(),
+                        wme_id: wme.id,
+                        fields: wme.fields.clone(),
+                    }
+                }
+                Condition::Negative { test, .. } => ConditionExplanation::Absent {
+                    condition: condition.clone(),
+                    pattern: test
+                        .iter()
+                        .map(|t| match t {
+                            ConditionTest::Constant(c) => Some(*c),
+                            ConditionTest::Variable(var) => bindings.get(var).copied(),
+                        })
+                        .collect(),
+                },
+                Condition::NegativeConjunction { .. } | Condition::Forall { .. } => {
+                    ConditionExplanation::NoConjunction {
+                        condition: condition.clone(),
+                    }
+                }
+                Condition::Test { variables, .. } => ConditionExplanation::Passed {
+                    condition: condition.clone(),
+                    values: variables
+                        .iter()
+                        .map(|var| bindings.get(var).copied())
+                        .collect::<Option<Vec<_>>>()?,
+                },
+            };
+            conditions.push(explanation);
+        }
+
+        Some(Explanation {
+            production: activation.production(),
+            token: activation.token(),
+            bindings,
+            conditions,
+        })
+    }
+}
+
+/// Returns the WMEs of the token's chain in the order of the conditions they matched.
+/// Every positive condition stores exactly one WME in the chain, all other tokens hold none.
+pub(in crate::rete) fn token_wmes(token: &ReteToken) -> Vec<RcCell<Wme>> {
+    let mut wmes = vec![];
+    let mut current: Option<ReteToken> = Some(Rc::clone(token));
+    while let Some(token) = current {
+        let token = token.borrow();
+        if let Some(wme) = token.wme() {
+            wmes.push(Rc::clone(wme));
+        }
+        current = token.parent().cloned();
+    }
+    wmes.reverse();
+    wmes
+}
+
+/// Diagnostics for a production, describing how far down its path from the dummy top node
+/// matches got. See [Rete::why_not].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhyNot {
+    pub production: usize,
+
+    /// Whether the production currently has any matches
+    pub fired: bool,
+
+    /// The deepest node on the path holding tokens, i.e. the last point partial matches reached
+    pub deepest: Option<NodeRef>,
+
+    /// The nodes on the production's path ordered from the dummy top node to the production node
+    pub path: Vec<NodeDiagnostic>,
+}
+
+/// Identifies a node by its kind and ID, since IDs are only unique per kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeRef {
+    pub kind: &'static str,
+    pub id: usize,
+}
+
+impl NodeRef {
+    pub fn of(node: &Node) -> Self {
+        Self {
+            kind: node._type(),
+            id: node.id(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeDiagnostic {
+    pub node: NodeRef,
+
+    /// The amount of tokens stored in the node, always 0 for join nodes
+    pub tokens: usize,
+
+    /// The ID of the alpha memory of join and negative nodes, and the amount of WMEs in it
+    pub alpha_memory: Option<(usize, usize)>,
+
+    pub left_linked: bool,
+    pub right_linked: bool,
+
+    /// Partial matches stored in the node that are kept from propagating
+    pub blocking: Vec<Blocking>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocking {
+    /// The token of a negative node is blocked by a WME matching the negated condition.
+    JoinResult {
+        token: usize,
+        wme_id: usize,
+        fields: Vec<usize>,
+    },
+
+    /// The token of an NCC node is blocked by matches of its subnetwork.
+    NccResults { token: usize, results: Vec<usize> },
+}
+
+impl Rete {
+    /// Reports on the state of the nodes leading to the production, to help finding out why
+    /// it does not fire.
+    ///
+    /// Returns `None` if the production does not exist.
+    pub fn why_not(&self, production: usize) -> Option<WhyNot> {
+        let node = self.productions.get(&production)?;
+
+        let mut path = vec![];
+        let mut current: Option<ReteNode> = Some(Rc::clone(node));
+        while let Some(node) = current {
+            let node = node.borrow();
+            path.push(diagnose_node(&node));
+            current = node.parent();
+        }
+        path.reverse();
+
+        let deepest = path
+            .iter()
+            .rev()
+            .find(|diagnostic| diagnostic.tokens > 0)
+            .map(|diagnostic| diagnostic.node);
+
+        Some(WhyNot {
+            production,
+            fired: path.last().is_some_and(|diagnostic| diagnostic.tokens > 0),
+            deepest,
+            path,
+        })
+    }
+}
+
+fn diagnose_node(node: &Node) -> NodeDiagnostic {
+    let node_ref = NodeRef::of(node);
+
+    let alpha_memory = match node {
+        Node::Join(join) => Some(&join.alpha_mem),
+        Node::Negative(negative) => Some(&negative.alpha_mem),
+        _ => None,
+    }
+    .map(|mem| {
+        let mem = mem.borrow();
+        (mem.id, mem.items.len())
+    });
+
+    let mut blocking = vec![];
+    for token in node.tokens() {
+        match &*token.borrow() {
+            Token::Negative {
+                base, join_results, ..
+            } => {
+                for result in join_results {
+                    let wme = result.borrow().wme.clone();
+                    let wme = wme.borrow();
+                    blocking.push(Blocking::JoinResult {
+                        token: base.id,
+                        wme_id: wme.id,
+                        fields: wme.fields.clone(),
+                    })
+                }
+            }
+            Token::NCC {
+                base, ncc_results, ..
+            } if !ncc_results.is_empty() => blocking.push(Blocking::NccResults {
+                token: base.id,
+                results: ncc_results
+                    .iter()
+                    .map(|result| result.borrow().id())
+                    .collect(),
+            }),
+            _ => {}
+        }
+    }
+
+    NodeDiagnostic {
+        node: node_ref,
+        tokens: node.tokens().len(),
+        alpha_memory,
+        left_linked: node.is_left_linked(),
+        right_linked: node.is_right_linked(),
+        blocking,
+    }
+}
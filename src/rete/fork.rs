@@ -0,0 +1,507 @@
+use super::{
+    item::{Activation, AlphaMemoryItem, NegativeJoinResult, Production, Token, TokenBase, Wme},
+    node::{
+        AlphaMemoryNode, AlphaTestNode, BetaMemoryNode, FilterNode, JoinNode, NccNode,
+        NccPartnerNode, NegativeNode, Node, ProductionNode,
+    },
+    IntoCell, RcCell, Rete, ReteNode, ReteToken,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::mpsc::{channel, Sender},
+};
+
+/// An independent copy of a [Rete] used for hypothetical reasoning. Changes made to the fork
+/// do not affect the Rete it was created from, they can either be discarded by dropping the
+/// fork or applied with [Rete::merge].
+///
+/// The fork shares the compiled tests of the network with the original, see [Rete::fork].
+#[derive(Debug)]
+pub struct Fork {
+    pub rete: Rete,
+
+    /// Reference counts of the WMEs present when the fork was created
+    base: HashMap<usize, usize>,
+}
+
+impl Rete {
+    /// Creates a fork with the same productions, queries and working memory as this Rete.
+    ///
+    /// The fork has the same network as this Rete: its nodes keep their IDs and connections,
+    /// and share their join tests, alpha tests and predicates with the nodes they were copied
+    /// from. Tokens, alpha memories and the linking state of the nodes are copied, so the fork
+    /// starts with the same matches without running any tests. Matches existing at the time of
+    /// forking are not reported again, `activation_tx` only receives the activations caused by
+    /// changes made to the fork. Productions, WMEs and tokens keep their IDs.
+    ///
+    /// Changes buffered by a transaction in progress are not part of the fork.
+    ///
+    /// Forking is linear in the amount of nodes, WMEs and tokens. Merging compares the fork's
+    /// working memory with the one it was forked from, which is linear in its size. Reuse a
+    /// fork for several changes instead of forking for each of them.
+    pub fn fork(&self, activation_tx: Sender<Activation>) -> Fork {
+        trace!("------------\nForking rete\n------------");
+
+        let mut copy = NetworkCopy::new(&self.dummy_top_node, &self.dummy_top_token);
+        copy.fill(&activation_tx);
+
+        let rete = Rete {
+            config: self.config,
+            dummy_top_token: copy.token(&self.dummy_top_token),
+            dummy_top_node: copy.node(&self.dummy_top_node),
+            constant_tests: self
+                .constant_tests
+                .iter()
+                .map(|(test, memory)| (test.clone(), copy.alpha_memory(memory)))
+                .collect(),
+            alpha_network: self
+                .alpha_network
+                .iter()
+                .map(|(test, nodes)| (test.clone(), copy.alpha_test_nodes(nodes)))
+                .collect(),
+            shapes: self.shapes.clone(),
+            working_memory: self
+                .working_memory
+                .iter()
+                .map(|(id, wme)| (*id, copy.wme(wme)))
+                .collect(),
+            wme_set: self.wme_set.clone(),
+            wme_alphas: self
+                .wme_alphas
+                .iter()
+                .map(|(id, memories)| (*id, copy.alpha_memories(memories)))
+                .collect(),
+            wme_index: self.wme_index.clone(),
+            productions: self
+                .productions
+                .iter()
+                .map(|(id, node)| (*id, copy.node(node)))
+                .collect(),
+            queries: self
+                .queries
+                .iter()
+                .map(|(name, node)| (name.clone(), copy.node(node)))
+                .collect(),
+            #[cfg(feature = "serde")]
+            log: None,
+            transaction: None,
+            clock: Rc::clone(&self.clock),
+            expirations: self.expirations.clone(),
+            window_exits: self
+                .window_exits
+                .iter()
+                .map(|(key, memories)| (*key, copy.alpha_memories(memories)))
+                .collect(),
+        };
+
+        copy.finish();
+
+        Fork {
+            rete,
+            base: self.reference_counts(),
+        }
+    }
+
+    /// Applies the changes made to working memory in the fork to this Rete, as a single
+    /// [transaction][Rete::begin_transaction] unless one is already in progress.
+    ///
+    /// WMEs added in the fork get added with their IDs and WMEs removed in it get removed,
+    /// changes made to this Rete since forking are kept. Productions are not merged.
+    pub fn merge(&mut self, fork: Fork) {
+        trace!("------------\nMerging fork\n------------");
+
+        let Fork { rete, base } = fork;
+        let counts = rete.reference_counts();
+
+        let own_transaction = !self.in_transaction();
+        if own_transaction {
+            self.begin_transaction();
+        }
+
+        let mut removed = base
+            .iter()
+            .filter_map(|(id, count)| {
+                let current = counts.get(id).copied().unwrap_or(0);
+                (current < *count).then_some((*id, count - current))
+            })
+            .collect::<Vec<_>>();
+        removed.sort();
+
+        for (id, count) in removed {
+            for _ in 0..count {
+                self.remove_wme(id);
+            }
+        }
+
+        let mut added = counts
+            .into_iter()
+            .filter_map(|(id, count)| {
+                let original = base.get(&id).copied().unwrap_or(0);
+                (count > original).then_some((id, count - original))
+            })
+            .collect::<Vec<_>>();
+        added.sort();
+
+        for (id, count) in added {
+            let wme = rete.working_memory[&id].borrow();
+            for _ in 0..count {
+                self.add_wme(wme.duplicate());
+            }
+        }
+
+        if own_transaction {
+            self.commit_transaction();
+        }
+    }
+
+    /// Maps the IDs of WMEs in working memory to the amount of times they were added.
+    fn reference_counts(&self) -> HashMap<usize, usize> {
+        self.working_memory
+            .iter()
+            .map(|(id, wme)| {
+                let count = self
+                    .wme_set
+                    .get(&wme.borrow().fields)
+                    .map_or(1, |(_, count)| *count);
+                (*id, count)
+            })
+            .collect()
+    }
+}
+
+/// Copies the state of a network, mapping the nodes, tokens, WMEs and memories of the original
+/// to their copies by address.
+///
+/// Nodes and tokens reference each other in cycles, so placeholders get allocated for all of
+/// them first and are [filled][NetworkCopy::fill] afterwards. WMEs, memories and negative join
+/// results are copied when first encountered, their references get filled on
+/// [finish][NetworkCopy::finish].
+struct NetworkCopy {
+    nodes: Vec<(ReteNode, ReteNode)>,
+    node_map: HashMap<*const RefCell<Node>, ReteNode>,
+    tokens: Vec<(ReteToken, ReteToken)>,
+    token_map: HashMap<*const RefCell<Token>, ReteToken>,
+    wmes: Vec<(RcCell<Wme>, RcCell<Wme>)>,
+    wme_map: HashMap<*const RefCell<Wme>, RcCell<Wme>>,
+    memories: Vec<(RcCell<AlphaMemoryNode>, RcCell<AlphaMemoryNode>)>,
+    memory_map: HashMap<*const RefCell<AlphaMemoryNode>, RcCell<AlphaMemoryNode>>,
+    join_results: HashMap<*const RefCell<NegativeJoinResult>, RcCell<NegativeJoinResult>>,
+}
+
+impl NetworkCopy {
+    /// Allocates placeholders for all nodes and tokens reachable from the roots.
+    fn new(dummy_top_node: &ReteNode, dummy_top_token: &ReteToken) -> Self {
+        let mut copy = Self {
+            nodes: vec![],
+            node_map: HashMap::new(),
+            tokens: vec![],
+            token_map: HashMap::new(),
+            wmes: vec![],
+            wme_map: HashMap::new(),
+            memories: vec![],
+            memory_map: HashMap::new(),
+            join_results: HashMap::new(),
+        };
+
+        let placeholder = || {
+            Rc::new(RefCell::new(Node::Beta(BetaMemoryNode {
+                id: 0,
+                parent: None,
+                children: vec![],
+                items: vec![],
+                all_children: vec![],
+            })))
+        };
+
+        // Left unlinked joins are only in `all_children` and the other children only in `children`
+        let mut visited = HashSet::new();
+        let mut stack = vec![Rc::clone(dummy_top_node)];
+        while let Some(node) = stack.pop() {
+            if !visited.insert(Rc::as_ptr(&node)) {
+                continue;
+            }
+            let n = node.borrow();
+            stack.extend(n.children().iter().cloned());
+            stack.extend(n.all_children().iter().cloned());
+            drop(n);
+
+            let new = placeholder();
+            copy.node_map.insert(Rc::as_ptr(&node), Rc::clone(&new));
+            copy.nodes.push((node, new));
+        }
+
+        let placeholder_node = placeholder();
+        let mut stack = vec![Rc::clone(dummy_top_token)];
+        while let Some(token) = stack.pop() {
+            stack.extend(token.borrow().children().iter().cloned());
+
+            let new = Token::dummy(&placeholder_node);
+            copy.token_map.insert(Rc::as_ptr(&token), Rc::clone(&new));
+            copy.tokens.push((token, new));
+        }
+
+        copy
+    }
+
+    /// Replaces the placeholders with copies of the nodes and tokens. Productions send their
+    /// activations through `activation_tx`.
+    fn fill(&mut self, activation_tx: &Sender<Activation>) {
+        for (old, new) in self.nodes.clone() {
+            let node = self.copy_node(&old.borrow(), activation_tx);
+            *new.borrow_mut() = node;
+        }
+
+        for (old, new) in self.tokens.clone() {
+            let token = self.copy_token(&old.borrow());
+            *new.borrow_mut() = token;
+        }
+    }
+
+    /// Fills the items and successors of the copied memories and the tokens and join results
+    /// of the copied WMEs.
+    fn finish(&mut self) {
+        for (old, new) in self.memories.clone() {
+            let old = old.borrow();
+            let items = old
+                .items
+                .iter()
+                .map(|item| {
+                    let item = item.borrow();
+                    AlphaMemoryItem {
+                        id: item.id,
+                        wme: self.wme(&item.wme),
+                        alpha_memory: Rc::clone(&new),
+                    }
+                    .to_cell()
+                })
+                .collect();
+            let successors = old.successors.iter().map(|node| self.node(node)).collect();
+
+            let mut new = new.borrow_mut();
+            new.items = items;
+            new.successors = successors;
+        }
+
+        for (old, new) in self.wmes.clone() {
+            let old = old.borrow();
+            let tokens = self.tokens(&old.tokens);
+            let join_results = old
+                .negative_join_results
+                .iter()
+                .map(|result| self.join_result(result))
+                .collect();
+
+            let mut new = new.borrow_mut();
+            new.tokens = tokens;
+            new.negative_join_results = join_results;
+        }
+    }
+
+    fn node(&self, node: &ReteNode) -> ReteNode {
+        Rc::clone(&self.node_map[&Rc::as_ptr(node)])
+    }
+
+    fn nodes(&self, nodes: &[ReteNode]) -> Vec<ReteNode> {
+        nodes.iter().map(|node| self.node(node)).collect()
+    }
+
+    fn token(&self, token: &ReteToken) -> ReteToken {
+        Rc::clone(&self.token_map[&Rc::as_ptr(token)])
+    }
+
+    fn tokens(&self, tokens: &[ReteToken]) -> Vec<ReteToken> {
+        tokens.iter().map(|token| self.token(token)).collect()
+    }
+
+    fn wme(&mut self, wme: &RcCell<Wme>) -> RcCell<Wme> {
+        if let Some(copied) = self.wme_map.get(&Rc::as_ptr(wme)) {
+            return Rc::clone(copied);
+        }
+
+        let copied = wme.borrow().duplicate().to_cell();
+        self.wme_map.insert(Rc::as_ptr(wme), Rc::clone(&copied));
+        self.wmes.push((Rc::clone(wme), Rc::clone(&copied)));
+        copied
+    }
+
+    fn alpha_memory(&mut self, memory: &RcCell<AlphaMemoryNode>) -> RcCell<AlphaMemoryNode> {
+        if let Some(copied) = self.memory_map.get(&Rc::as_ptr(memory)) {
+            return Rc::clone(copied);
+        }
+
+        let old = memory.borrow();
+        let copied = AlphaMemoryNode {
+            id: old.id,
+            reference_count: old.reference_count,
+            window: old.window,
+            ..Default::default()
+        }
+        .to_cell();
+        drop(old);
+
+        self.memory_map
+            .insert(Rc::as_ptr(memory), Rc::clone(&copied));
+        self.memories.push((Rc::clone(memory), Rc::clone(&copied)));
+        copied
+    }
+
+    fn alpha_memories(
+        &mut self,
+        memories: &[RcCell<AlphaMemoryNode>],
+    ) -> Vec<RcCell<AlphaMemoryNode>> {
+        memories
+            .iter()
+            .map(|memory| self.alpha_memory(memory))
+            .collect()
+    }
+
+    fn alpha_test_nodes(&mut self, nodes: &[AlphaTestNode]) -> Vec<AlphaTestNode> {
+        nodes
+            .iter()
+            .map(|node| AlphaTestNode {
+                id: node.id,
+                test: Rc::clone(&node.test),
+                children: self.alpha_test_nodes(&node.children),
+                memory: node.memory.as_ref().map(|memory| self.alpha_memory(memory)),
+            })
+            .collect()
+    }
+
+    fn join_result(&mut self, result: &RcCell<NegativeJoinResult>) -> RcCell<NegativeJoinResult> {
+        if let Some(copied) = self.join_results.get(&Rc::as_ptr(result)) {
+            return Rc::clone(copied);
+        }
+
+        let old = result.borrow();
+        let copied = Rc::new(RefCell::new(NegativeJoinResult {
+            id: old.id,
+            owner: self.token(&old.owner),
+            wme: self.wme(&old.wme),
+        }));
+        drop(old);
+
+        self.join_results
+            .insert(Rc::as_ptr(result), Rc::clone(&copied));
+        copied
+    }
+
+    fn copy_node(&mut self, node: &Node, activation_tx: &Sender<Activation>) -> Node {
+        match node {
+            Node::Beta(beta) => Node::Beta(BetaMemoryNode {
+                id: beta.id,
+                parent: beta.parent.as_ref().map(|parent| self.node(parent)),
+                children: self.nodes(&beta.children),
+                items: self.tokens(&beta.items),
+                all_children: self.nodes(&beta.all_children),
+            }),
+            Node::Join(join) => Node::Join(JoinNode {
+                id: join.id,
+                parent: self.node(&join.parent),
+                alpha_mem: self.alpha_memory(&join.alpha_mem),
+                children: self.nodes(&join.children),
+                tests: Rc::clone(&join.tests),
+                nearest_ancestor: join.nearest_ancestor.as_ref().map(|node| self.node(node)),
+                left_linked: join.left_linked,
+                right_linked: join.right_linked,
+            }),
+            Node::Negative(negative) => Node::Negative(NegativeNode {
+                id: negative.id,
+                parent: self.node(&negative.parent),
+                children: self.nodes(&negative.children),
+                items: self.tokens(&negative.items),
+                alpha_mem: self.alpha_memory(&negative.alpha_mem),
+                tests: Rc::clone(&negative.tests),
+                nearest_ancestor: negative
+                    .nearest_ancestor
+                    .as_ref()
+                    .map(|node| self.node(node)),
+                right_linked: negative.right_linked,
+            }),
+            Node::Ncc(ncc) => Node::Ncc(NccNode {
+                id: ncc.id,
+                parent: self.node(&ncc.parent),
+                children: self.nodes(&ncc.children),
+                items: self.tokens(&ncc.items),
+                partner: ncc.partner.as_ref().map(|node| self.node(node)),
+            }),
+            Node::NccPartner(partner) => Node::NccPartner(NccPartnerNode {
+                id: partner.id,
+                parent: self.node(&partner.parent),
+                number_of_conjucts: partner.number_of_conjucts,
+                ncc_node: self.node(&partner.ncc_node),
+                new_results: self.tokens(&partner.new_results),
+            }),
+            Node::Filter(filter) => Node::Filter(FilterNode {
+                id: filter.id,
+                parent: self.node(&filter.parent),
+                children: self.nodes(&filter.children),
+                items: self.tokens(&filter.items),
+                predicate: filter.predicate.clone(),
+                locations: Rc::clone(&filter.locations),
+            }),
+            Node::Production(p_node) => {
+                // Queries never send activations, the channel only satisfies the production
+                let activation_channel = if p_node.query {
+                    channel().0
+                } else {
+                    activation_tx.clone()
+                };
+
+                Node::Production(ProductionNode {
+                    id: p_node.id,
+                    parent: self.node(&p_node.parent),
+                    production: Production {
+                        id: p_node.production.id,
+                        conditions: p_node.production.conditions.clone(),
+                        activation_channel,
+                        name: p_node.production.name.clone(),
+                    },
+                    items: self.tokens(&p_node.items),
+                    deferred: None,
+                    query: p_node.query,
+                })
+            }
+        }
+    }
+
+    fn copy_token(&mut self, token: &Token) -> Token {
+        match token {
+            Token::Dummy { id, node, children } => Token::Dummy {
+                id: *id,
+                node: self.node(node),
+                children: self.tokens(children),
+            },
+            Token::Beta { base } => Token::Beta {
+                base: self.copy_base(base),
+            },
+            Token::Negative { base, join_results } => Token::Negative {
+                base: self.copy_base(base),
+                join_results: join_results
+                    .iter()
+                    .map(|result| self.join_result(result))
+                    .collect(),
+            },
+            Token::NCC {
+                base,
+                ncc_results,
+                owner,
+            } => Token::NCC {
+                base: self.copy_base(base),
+                ncc_results: self.tokens(ncc_results),
+                owner: owner.as_ref().map(|token| self.token(token)),
+            },
+        }
+    }
+
+    fn copy_base(&mut self, base: &TokenBase) -> TokenBase {
+        TokenBase {
+            id: base.id,
+            node: self.node(&base.node),
+            parent: self.token(&base.parent),
+            children: self.tokens(&base.children),
+            wme: base.wme.as_ref().map(|wme| self.wme(wme)),
+        }
+    }
+}
@@ -6,6 +6,7 @@ static TOKEN_ID_GENERATOR: AtomicUsize = AtomicUsize::new(1); // 0 reserved for
 static ITEM_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
 static WME_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
 static PROD_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
+static PREDICATE_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
 
 pub fn alpha_node_id() -> usize {
     ALPHA_NODE_ID_GENERATOR.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
@@ -31,6 +32,20 @@ pub fn prod_id() -> usize {
     PROD_ID_GENERATOR.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
 }
 
+pub fn predicate_id() -> usize {
+    PREDICATE_ID_GENERATOR.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+}
+
+/// Makes sure the WME ID generator never yields the given ID, used for restored WMEs.
+pub fn reserve_wme_id(id: usize) {
+    WME_ID_GENERATOR.fetch_max(id + 1, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Makes sure the production ID generator never yields the given ID, used for restored productions.
+pub fn reserve_prod_id(id: usize) {
+    PROD_ID_GENERATOR.fetch_max(id + 1, std::sync::atomic::Ordering::SeqCst);
+}
+
 pub fn reset() {
     ALPHA_NODE_ID_GENERATOR.store(0, std::sync::atomic::Ordering::SeqCst);
     BETA_NODE_ID_GENERATOR.store(1, std::sync::atomic::Ordering::SeqCst);
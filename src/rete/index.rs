@@ -0,0 +1,72 @@
+use super::{item::ConstantTest, item::Wme, Rete};
+
+impl Rete {
+    /// Returns the IDs of the WMEs in working memory matching the pattern, in ascending order.
+    /// Constants in the pattern must be equal to the WME's fields, `None` matches any value.
+    ///
+    /// If a condition without alpha tests created a memory for the pattern, its items are the
+    /// result. Otherwise only the WMEs holding the pattern's rarest constant are checked, all of
+    /// working memory for patterns without constants.
+    pub fn find(&self, pattern: impl Into<ConstantTest>) -> Vec<usize> {
+        let pattern = pattern.into();
+
+        let mut ids = if let Some(memory) = self.constant_tests.get(&pattern) {
+            trace!("Finding {pattern:?} in alpha memory {}", memory.borrow().id);
+            memory
+                .borrow()
+                .items
+                .iter()
+                .map(|item| item.borrow().wme.borrow().id)
+                .collect::<Vec<_>>()
+        } else {
+            let mut candidates = vec![];
+            for constant in pattern.constants() {
+                // No WME holds the constant, so none can match
+                let Some(ids) = self.wme_index.get(&constant) else {
+                    return vec![];
+                };
+                candidates.push(ids);
+            }
+
+            let matches = |id: &usize| {
+                self.working_memory
+                    .get(id)
+                    .is_some_and(|wme| pattern.matches(&wme.borrow()))
+            };
+
+            match candidates.into_iter().min_by_key(|ids| ids.len()) {
+                Some(ids) => ids.iter().copied().filter(matches).collect(),
+                None => self
+                    .working_memory
+                    .keys()
+                    .copied()
+                    .filter(matches)
+                    .collect(),
+            }
+        };
+
+        ids.sort();
+        ids
+    }
+
+    pub(in crate::rete) fn index_wme(&mut self, wme: &Wme) {
+        for (i, field) in wme.fields.iter().enumerate() {
+            self.wme_index
+                .entry((i, *field))
+                .or_default()
+                .insert(wme.id);
+        }
+    }
+
+    pub(in crate::rete) fn unindex_wme(&mut self, wme: &Wme) {
+        for (i, field) in wme.fields.iter().enumerate() {
+            let key = (i, *field);
+            if let Some(ids) = self.wme_index.get_mut(&key) {
+                ids.remove(&wme.id);
+                if ids.is_empty() {
+                    self.wme_index.remove(&key);
+                }
+            }
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use super::{
     activate_left,
-    id::{item_id, prod_id, token_id, wme_id},
+    id::{item_id, predicate_id, prod_id, token_id, wme_id},
     node::{AlphaMemoryNode, Node},
     IntoCell, RcCell, ReteNode, ReteToken,
 };
-use std::{hash::Hash, rc::Rc};
+use std::{fmt::Debug, hash::Hash, rc::Rc};
 use std::{ops::Index, sync::mpsc::Sender};
 
 pub const DUMMY_TOKEN_ID: usize = usize::MIN;
@@ -22,18 +22,27 @@ pub struct Wme {
     /// The system is usually concerned with the ID from the WME's [fields][Wme::fields].
     pub(in crate::rete) id: usize,
 
-    /// Represents an \[ID, ATTRIBUTE, VALUE] triple.
+    /// Represents an \[ID, ATTRIBUTE, VALUE] triple, or in general an arbitrary tuple
+    /// such as \[ORDER, ID, CUSTOMER, TOTAL, STATUS]. A WME can only match conditions
+    /// of the same arity.
     ///
     /// The external system using the Rete assigns these in a system specific way.
     /// The `ID` element of the fields is the rete identifier for a piece of
     /// system state.
-    pub fields: [usize; 3],
+    pub fields: Vec<usize>,
 
     /// Tokens which contain this WME as their element
     pub tokens: Vec<ReteToken>,
 
     /// A list of negative join results that succeeded on this WME.
     pub negative_join_results: Vec<RcCell<NegativeJoinResult>>,
+
+    /// When the WME happened, in milliseconds of the Rete's [clock][super::time::Clock].
+    /// Assigned when adding the WME unless set beforehand.
+    pub timestamp: Option<u64>,
+
+    /// How many milliseconds after its timestamp the WME gets retracted, if at all.
+    pub lifetime: Option<u64>,
 }
 
 impl PartialEq for Wme {
@@ -43,28 +52,73 @@ impl PartialEq for Wme {
 }
 
 impl Wme {
-    pub fn new(fields: [usize; 3]) -> Self {
+    pub fn new(fields: impl Into<Vec<usize>>) -> Self {
+        Self::with_id(wme_id(), fields)
+    }
+
+    /// Creates a WME with a previously assigned ID, used when restoring working memory.
+    pub(in crate::rete) fn with_id(id: usize, fields: impl Into<Vec<usize>>) -> Self {
         Self {
-            id: wme_id(),
-            fields,
+            id,
+            fields: fields.into(),
             tokens: vec![],
             negative_join_results: vec![],
+            timestamp: None,
+            lifetime: None,
+        }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Makes the WME expire the given amount of milliseconds after its timestamp.
+    pub fn with_lifetime(mut self, lifetime: u64) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Creates a WME with the same ID, fields and times, but not part of any match.
+    pub(in crate::rete) fn duplicate(&self) -> Self {
+        Self {
+            timestamp: self.timestamp,
+            lifetime: self.lifetime,
+            ..Self::with_id(self.id, self.fields.clone())
         }
     }
 
-    #[rustfmt::skip]
+    /// The time interval the WME holds in, see [TemporalRelation].
+    pub fn interval(&self) -> (u64, u64) {
+        let start = self.timestamp.unwrap_or_default();
+        (start, self.expires_at().unwrap_or(start))
+    }
+
+    /// The time at which the WME gets retracted, if it has a lifetime.
+    pub fn expires_at(&self) -> Option<u64> {
+        Some(self.timestamp?.saturating_add(self.lifetime?))
+    }
+
+    /// Returns the constant test with the given shape that this WME passes, i.e. one
+    /// holding the WME's fields where the shape is `true` and wildcards everywhere else.
+    ///
+    /// Instead of generating every one of the 2^n tests a WME of arity n could pass, the
+    /// Rete only looks up the shapes of constant tests that actually exist in the network.
+    #[inline]
+    pub fn constant_test(&self, shape: &[bool]) -> ConstantTest {
+        debug_assert_eq!(shape.len(), self.fields.len());
+        ConstantTest(
+            self.fields
+                .iter()
+                .zip(shape)
+                .map(|(field, constant)| constant.then_some(*field))
+                .collect(),
+        )
+    }
+
     #[inline]
-    pub fn permutations(&self) -> [ConstantTest; 8] {
-        [
-            ConstantTest([Some(self[0]), Some(self[1]), Some(self[2])]),
-            ConstantTest([Some(self[0]), Some(self[1]), None]),
-            ConstantTest([Some(self[0]), None,          Some(self[2])]),
-            ConstantTest([Some(self[0]), None,          None]),
-            ConstantTest([None,          Some(self[1]), Some(self[2])]),
-            ConstantTest([None,          Some(self[1]), None]),
-            ConstantTest([None,          None,          Some(self[2])]),
-            ConstantTest([None,          None,          None]),
-        ]
+    pub fn arity(&self) -> usize {
+        self.fields.len()
     }
 }
 
@@ -81,12 +135,7 @@ impl Index<usize> for Wme {
     type Output = usize;
 
     fn index(&self, index: usize) -> &Self::Output {
-        match index {
-            0 => &self.fields[0],
-            1 => &self.fields[1],
-            2 => &self.fields[2],
-            _ => panic!("WMEs cannot be indexed by indices higher than 2"),
-        }
+        &self.fields[index]
     }
 }
 
@@ -155,6 +204,7 @@ impl TokenBase {
 impl PartialEq for Token {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (Token::Dummy { id, .. }, Token::Dummy { id: _id, .. }) => id == _id,
             (Token::Beta { base }, Token::Beta { base: _base }) => {
                 base.id == _base.id && base.wme == _base.wme && base.children == _base.children
             }
@@ -181,7 +231,7 @@ impl Token {
             base: TokenBase::new(node, parent, wme),
         };
 
-        println!(
+        trace!(
             "Creating token {} and appending to token {}",
             token,
             parent.as_ref().borrow().id()
@@ -208,7 +258,7 @@ impl Token {
             join_results: vec![],
         };
 
-        println!(
+        trace!(
             "Creating token {} and appending to token {}",
             token,
             parent.as_ref().borrow().id()
@@ -236,7 +286,7 @@ impl Token {
             owner: None,
         };
 
-        println!(
+        trace!(
             "Creating token {} and appending to token {}",
             token,
             parent.as_ref().borrow().id()
@@ -424,18 +474,18 @@ impl Token {
             if let Some(parent) = Rc::clone(&token).borrow().parent() {
                 token = Rc::clone(parent)
             } else {
-                println!("Found {n}th parent token: {}", token.borrow().id());
+                trace!("Found {n}th parent token: {}", token.borrow().id());
                 return token;
             };
         }
-        println!("Found {n}th parent token: {}", token.borrow().id());
+        trace!("Found {n}th parent token: {}", token.borrow().id());
         token
     }
 
     /// Clean up the token and any of its descendants from the WME linked to it, its parent,
     /// and the node it is stored in. Also remove
     pub fn delete_self_and_descendants(token: RcCell<Self>) {
-        println!(
+        trace!(
             "Deleting token {}, refs: {}",
             token.borrow(),
             Rc::strong_count(&token)
@@ -452,7 +502,7 @@ impl Token {
             owner,
         } = Token::destructure(&mut token.borrow_mut());
 
-        println!("Deleting descendants of token {id}");
+        trace!("Deleting descendants of token {id}");
 
         Self::delete_descendants(children);
 
@@ -461,29 +511,40 @@ impl Token {
             node.borrow_mut().remove_token(id);
         }
 
+        // Notify the system the match no longer holds
+        if let Node::Production(p_node) = &*node.borrow() {
+            p_node.notify(Activation::Retracted {
+                production: p_node.production.id,
+                token: id,
+                name: p_node.production.name.clone(),
+            });
+        }
+
         if let Some(wme) = wme {
-            println!("Removing token {id} from wme {}", wme.borrow().id);
+            trace!("Removing token {id} from wme {}", wme.borrow().id);
             wme.borrow_mut()
                 .tokens
                 .retain(|tok| tok.borrow().id() != id)
         }
 
         if let Some(parent) = parent {
-            println!("Removing token {id} from parent {}", parent.borrow().id());
+            trace!("Removing token {id} from parent {}", parent.borrow().id());
             parent.borrow_mut().remove_child(id);
         }
 
         // Right unlink the token's node if it became empty, the check is done in the method
-        node.borrow().right_unlink();
+        Node::right_unlink(&node);
         if let Node::Negative(ref mut neg) = *node.borrow_mut() {
-            neg.right_linked = false;
+            if neg.items.is_empty() {
+                neg.right_linked = false;
+            }
         }
 
         // Remove all negative join results from corresponding WME
         for result in join_results {
             let result = result.borrow();
             let wme = &mut *result.wme.borrow_mut();
-            println!(
+            trace!(
                 "Removing result from WME {}'s negative join results",
                 wme.id
             );
@@ -493,17 +554,41 @@ impl Token {
 
         // Remove all NCC results from corresponding WME
         for result in ncc_results {
-            let result = &mut result.borrow_mut();
-            if let Some(wme) = result.base_mut().wme.take() {
-                println!(
+            let (result_id, result_wme, result_parent) = {
+                let result = &mut result.borrow_mut();
+
+                // The result may still get deleted through a WME, its owner must not be
+                // reactivated then
+                if let Token::NCC { owner, .. } = &mut **result {
+                    *owner = None;
+                }
+
+                let base = result.base_mut();
+                (base.id, base.wme.take(), Rc::clone(&base.parent))
+            };
+
+            if let Some(wme) = result_wme {
+                trace!(
                     "Removing token {} from WME {}'s NCC results",
-                    result.id(),
+                    result_id,
                     wme.borrow().id
                 );
-                wme.borrow_mut().tokens.retain(|t| t.borrow().id() != id)
+                wme.borrow_mut()
+                    .tokens
+                    .retain(|t| t.borrow().id() != result_id)
             }
 
-            result.base_mut().parent.borrow_mut().remove_child(id);
+            result_parent.borrow_mut().remove_child(result_id);
+        }
+
+        // The result may still be waiting in the buffer for its owner to get activated, which
+        // never happens if the owner's match got retracted meanwhile
+        if let Node::NccPartner(partner) = &mut *node.borrow_mut() {
+            if owner.is_none() {
+                partner
+                    .new_results
+                    .retain(|result| !Rc::ptr_eq(result, &token));
+            }
         }
 
         if let Node::NccPartner(node) = &*node.borrow() {
@@ -660,21 +745,289 @@ pub struct JoinTest {
     /// An index that ultimately indexes into a WME from the parent [Token]
     /// found by the `distance_to_wme`
     pub arg_two: usize,
+
+    /// When set, the timestamps of the two WMEs get compared with the relation
+    /// instead of their fields.
+    pub temporal: Option<TemporalRelation>,
+}
+
+/// Specifies where the value of a variable bound in an earlier condition is located.
+///
+/// These are stored by filter nodes and are used to collect the arguments of their [Predicate].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct VariableLocation {
+    /// Used to traverse the activating token's parents to find the WME binding the variable.
+    /// A distance of 0 denotes the WME the filter node is activated with.
+    pub distance_to_wme: usize,
+
+    /// The index of the variable in the WME found by the `distance_to_wme`
+    pub field: usize,
+}
+
+pub type PredicateFn = Rc<dyn Fn(&[usize]) -> bool>;
+
+/// A user supplied test over the values of bound variables.
+///
+/// Predicates are compared by the handle obtained from [Predicate::new], so conditions
+/// reusing clones of the same predicate can share filter nodes in the network.
+/// [Named][Predicate::named] predicates are compared by their name instead.
+#[derive(Clone)]
+pub struct Predicate {
+    pub id: usize,
+    name: Option<Rc<str>>,
+    func: PredicateFn,
+}
+
+impl Predicate {
+    pub fn new(func: impl Fn(&[usize]) -> bool + 'static) -> Self {
+        Self {
+            id: predicate_id(),
+            name: None,
+            func: Rc::new(func),
+        }
+    }
+
+    /// A predicate identified by its name. Snapshots and fact logs store the name, which lets
+    /// them be restored in another process by supplying a predicate with the same name.
+    /// The handles of unnamed predicates depend on the order predicates were created in.
+    pub fn named(name: impl Into<Rc<str>>, func: impl Fn(&[usize]) -> bool + 'static) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..Self::new(func)
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// A placeholder for a predicate that has yet to be supplied, e.g. after deserializing
+    /// conditions. Panics when tested.
+    #[cfg(feature = "serde")]
+    pub(in crate::rete) fn unresolved(id: usize, name: Option<Rc<str>>) -> Self {
+        Self {
+            id,
+            name,
+            func: Rc::new(move |_| panic!("Predicate {id} was never resolved")),
+        }
+    }
+
+    /// Evaluates the predicate for the given variable values, ordered as they were
+    /// specified in the test condition.
+    #[inline]
+    pub fn test(&self, values: &[usize]) -> bool {
+        (self.func)(values)
+    }
+}
+
+impl Debug for Predicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "Predicate({name})"),
+            None => write!(f, "Predicate({})", self.id),
+        }
+    }
+}
+
+impl PartialEq for Predicate {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.name, &other.name) {
+            (Some(name), Some(other)) => name == other,
+            (None, None) => self.id == other.id,
+            _ => false,
+        }
+    }
 }
 
+impl Eq for Predicate {}
+
 /// When productions are added to the network, constant tests are created based on the condition's constants that
 /// index into the appropriate alpha memories for the condition.
 /// If a constant exists in the condition, it will be represented by `Some(constant)` in the test.
 /// A `None` in the constant test represents a wildcard.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct ConstantTest([Option<usize>; 3]);
+///
+/// Only WMEs with the same arity as the test can pass it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConstantTest(Vec<Option<usize>>);
 
 impl ConstantTest {
+    pub fn from_pattern(pattern: &[ConditionTest]) -> Self {
+        Self(pattern.iter().map(|test| (*test).into()).collect())
+    }
+
     pub fn matches(&self, wme: &Wme) -> bool {
-        self.0[0].map_or(true, |s| s == wme.fields[0])
-            && self.0[1].map_or(true, |s| s == wme.fields[1])
-            && self.0[2].map_or(true, |s| s == wme.fields[2])
+        self.0.len() == wme.fields.len()
+            && self
+                .0
+                .iter()
+                .zip(wme.fields.iter())
+                .all(|(test, field)| test.is_none_or(|s| s == *field))
     }
+
+    /// Indicates which fields of the test are constants, used to index WMEs into
+    /// the alpha network, see [Wme::constant_test].
+    pub fn shape(&self) -> Vec<bool> {
+        self.0.iter().map(Option::is_some).collect()
+    }
+
+    pub fn arity(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the positions of the test's constants along with their values.
+    pub fn constants(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, test)| test.map(|constant| (i, constant)))
+    }
+}
+
+impl From<Vec<Option<usize>>> for ConstantTest {
+    fn from(pattern: Vec<Option<usize>>) -> Self {
+        Self(pattern)
+    }
+}
+
+impl<const N: usize> From<[Option<usize>; N]> for ConstantTest {
+    fn from(pattern: [Option<usize>; N]) -> Self {
+        Self(pattern.to_vec())
+    }
+}
+
+/// An intra-WME test performed by the alpha discrimination network after a WME passes
+/// the [ConstantTest] of a condition. Filtering here keeps WMEs that can never match
+/// out of the alpha memories and thus away from the join nodes.
+///
+/// Fields are indices into [Wme::fields] and values are compared as raw symbols.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AlphaTest {
+    NotEqual {
+        field: usize,
+        value: usize,
+    },
+    Less {
+        field: usize,
+        value: usize,
+    },
+    Greater {
+        field: usize,
+        value: usize,
+    },
+    /// Inclusive on both ends.
+    Range {
+        field: usize,
+        min: usize,
+        max: usize,
+    },
+    OneOf {
+        field: usize,
+        values: Vec<usize>,
+    },
+    FieldsEqual {
+        field_one: usize,
+        field_two: usize,
+    },
+    /// Passes for WMEs whose timestamp lies within the last `duration` milliseconds. Unlike other
+    /// tests, the result changes over time and WMEs leave the memories behind this test once
+    /// they are too old, see [Rete::expire][super::Rete::expire].
+    Within {
+        duration: u64,
+    },
+}
+
+impl AlphaTest {
+    /// Returns the indices of the fields accessed by this test.
+    pub fn fields(&self) -> Vec<usize> {
+        match self {
+            AlphaTest::NotEqual { field, .. }
+            | AlphaTest::Less { field, .. }
+            | AlphaTest::Greater { field, .. }
+            | AlphaTest::Range { field, .. }
+            | AlphaTest::OneOf { field, .. } => vec![*field],
+            AlphaTest::FieldsEqual {
+                field_one,
+                field_two,
+            } => vec![*field_one, *field_two],
+            AlphaTest::Within { .. } => vec![],
+        }
+    }
+
+    /// Tests the WME at the given time. Only [AlphaTest::Within] depends on it.
+    pub fn matches(&self, wme: &Wme, now: u64) -> bool {
+        let fields = &wme.fields;
+        match self {
+            AlphaTest::NotEqual { field, value } => fields[*field] != *value,
+            AlphaTest::Less { field, value } => fields[*field] < *value,
+            AlphaTest::Greater { field, value } => fields[*field] > *value,
+            AlphaTest::Range { field, min, max } => (*min..=*max).contains(&fields[*field]),
+            AlphaTest::OneOf { field, values } => values.contains(&fields[*field]),
+            AlphaTest::FieldsEqual {
+                field_one,
+                field_two,
+            } => fields[*field_one] == fields[*field_two],
+            AlphaTest::Within { duration } => wme
+                .timestamp
+                .is_none_or(|timestamp| timestamp.saturating_add(*duration) > now),
+        }
+    }
+}
+
+/// An Allen-style relation between the time intervals of two WMEs. The interval of a WME
+/// starts at its timestamp and ends when it [expires][Wme::expires_at], WMEs without a
+/// lifetime are points in time.
+///
+/// Relations read as "the tested WME *relation* the other WME", all bounds are in milliseconds
+/// and inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TemporalRelation {
+    /// Ends between `min` and `max` before the other one starts.
+    Before { min: u64, max: u64 },
+    /// Starts between `min` and `max` after the other one ends.
+    After { min: u64, max: u64 },
+    /// Lies within the other one.
+    During,
+    /// Contains the other one.
+    Includes,
+    /// Starts before the other one and ends while it is ongoing.
+    Overlaps,
+    /// Starts and ends at most `tolerance` apart from the other one.
+    Coincides { tolerance: u64 },
+}
+
+impl TemporalRelation {
+    pub fn holds(&self, this: &Wme, other: &Wme) -> bool {
+        let (start, end) = this.interval();
+        let (other_start, other_end) = other.interval();
+
+        match *self {
+            TemporalRelation::Before { min, max } => {
+                other_start >= end && (min..=max).contains(&(other_start - end))
+            }
+            TemporalRelation::After { min, max } => {
+                start >= other_end && (min..=max).contains(&(start - other_end))
+            }
+            TemporalRelation::During => other_start <= start && end <= other_end,
+            TemporalRelation::Includes => start <= other_start && other_end <= end,
+            TemporalRelation::Overlaps => {
+                start < other_start && other_start < end && end < other_end
+            }
+            TemporalRelation::Coincides { tolerance } => {
+                start.abs_diff(other_start) <= tolerance && end.abs_diff(other_end) <= tolerance
+            }
+        }
+    }
+}
+
+/// Relates the WME matching a condition to the WME bound to `variable` by an earlier
+/// positive condition, see [Condition::with_temporal_test].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemporalTest {
+    pub relation: TemporalRelation,
+    pub variable: usize,
 }
 
 #[inline]
@@ -687,6 +1040,14 @@ pub fn conditions_to_constant_tests(acc: &mut Vec<ConstantTest>, conditions: &[C
             Condition::NegativeConjunction { subconditions } => {
                 conditions_to_constant_tests(acc, subconditions);
             }
+            Condition::Test { .. } => {}
+            Condition::Forall {
+                pattern,
+                constraints,
+            } => {
+                acc.push(ConstantTest::from_pattern(pattern));
+                conditions_to_constant_tests(acc, constraints);
+            }
         }
     }
 }
@@ -694,10 +1055,12 @@ pub fn conditions_to_constant_tests(acc: &mut Vec<ConstantTest>, conditions: &[C
 impl From<Condition> for ConstantTest {
     fn from(condition: Condition) -> Self {
         match condition {
-            Condition::Positive { test } | Condition::Negative { test } => {
-                Self([test[0].into(), test[1].into(), test[2].into()])
+            Condition::Positive { test, .. } | Condition::Negative { test, .. } => {
+                Self::from_pattern(&test)
             }
-            Condition::NegativeConjunction { .. } => {
+            Condition::NegativeConjunction { .. }
+            | Condition::Forall { .. }
+            | Condition::Test { .. } => {
                 panic!("Cannot convert condition to constant test")
             }
         }
@@ -706,10 +1069,12 @@ impl From<Condition> for ConstantTest {
 impl From<&Condition> for ConstantTest {
     fn from(condition: &Condition) -> Self {
         match condition {
-            Condition::Positive { test } | Condition::Negative { test } => {
-                Self([test[0].into(), test[1].into(), test[2].into()])
+            Condition::Positive { test, .. } | Condition::Negative { test, .. } => {
+                Self::from_pattern(test)
             }
-            Condition::NegativeConjunction { .. } => {
+            Condition::NegativeConjunction { .. }
+            | Condition::Forall { .. }
+            | Condition::Test { .. } => {
                 panic!("Cannot convert condition to constant test")
             }
         }
@@ -725,6 +1090,47 @@ impl From<ConditionTest> for Option<usize> {
     }
 }
 
+/// Sent through a production's activation channel whenever a match for it appears or disappears.
+/// Carries the production's [name][Production::name], if it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Activation {
+    /// The production's conditions were fully matched, `token` represents the match.
+    Fired {
+        production: usize,
+        token: usize,
+        name: Option<Rc<str>>,
+    },
+
+    /// The match represented by `token` no longer holds and the token got deleted.
+    Retracted {
+        production: usize,
+        token: usize,
+        name: Option<Rc<str>>,
+    },
+}
+
+impl Activation {
+    pub fn production(&self) -> usize {
+        match self {
+            Activation::Fired { production, .. } | Activation::Retracted { production, .. } => {
+                *production
+            }
+        }
+    }
+
+    pub fn token(&self) -> usize {
+        match self {
+            Activation::Fired { token, .. } | Activation::Retracted { token, .. } => *token,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Activation::Fired { name, .. } | Activation::Retracted { name, .. } => name.as_deref(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Production {
     pub id: usize,
@@ -732,23 +1138,42 @@ pub struct Production {
     /// Conditions required to be fully matched in order for this production to fire.
     pub conditions: Vec<Condition>,
 
-    /// When a production is activated, the overlying system is notified via the receiving
-    /// side of this channel
-    pub activation_channel: Sender<usize>,
+    /// When a production is activated or one of its matches is retracted, the overlying system
+    /// is notified via the receiving side of this channel
+    pub activation_channel: Sender<Activation>,
+
+    /// Identifies the production to humans, included in its activations and in dumps
+    pub name: Option<Rc<str>>,
 }
 
 impl Production {
-    pub fn new(conditions: &[Condition], activation_tx: Sender<usize>) -> Self {
+    pub fn new(conditions: &[Condition], activation_tx: Sender<Activation>) -> Self {
+        Self::with_id(prod_id(), conditions, activation_tx)
+    }
+
+    /// Creates a production with a previously assigned ID, used when restoring productions.
+    pub(in crate::rete) fn with_id(
+        id: usize,
+        conditions: &[Condition],
+        activation_tx: Sender<Activation>,
+    ) -> Self {
         Self {
-            id: prod_id(),
+            id,
             conditions: conditions.to_vec(),
             activation_channel: activation_tx,
+            name: None,
         }
     }
+
+    pub fn with_name(mut self, name: impl Into<Rc<str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
 }
 
 /// A test for a single symbol.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConditionTest {
     /// Test for a symbol with the given ID.
     Constant(usize),
@@ -758,31 +1183,168 @@ pub enum ConditionTest {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Condition {
-    Positive { test: [ConditionTest; 3] },
-    Negative { test: [ConditionTest; 3] },
-    NegativeConjunction { subconditions: Vec<Self> },
+    Positive {
+        test: Vec<ConditionTest>,
+        /// Additional tests a WME must pass in the alpha network, see [AlphaTest].
+        alpha_tests: Vec<AlphaTest>,
+        /// Tests relating the WME to the WMEs of earlier conditions in time, see [TemporalTest].
+        #[cfg_attr(feature = "serde", serde(default))]
+        temporal_tests: Vec<TemporalTest>,
+    },
+    Negative {
+        test: Vec<ConditionTest>,
+        alpha_tests: Vec<AlphaTest>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        temporal_tests: Vec<TemporalTest>,
+    },
+    NegativeConjunction {
+        subconditions: Vec<Self>,
+    },
+    /// Passes when the predicate holds for the values of the given variables, all of which
+    /// must be bound by preceding positive conditions.
+    Test {
+        predicate: Predicate,
+        variables: Vec<usize>,
+    },
+    /// Universal quantification. Satisfied when every WME matching `pattern` also satisfies
+    /// all of the `constraints`, including when no WME matches the pattern at all.
+    ///
+    /// Variables bound by the pattern and the constraints are only visible inside the forall.
+    Forall {
+        pattern: Vec<ConditionTest>,
+        constraints: Vec<Self>,
+    },
 }
 
 impl Condition {
-    pub const fn new_positive(test: [ConditionTest; 3]) -> Self {
-        Self::Positive { test }
+    pub fn new_positive(test: impl Into<Vec<ConditionTest>>) -> Self {
+        Self::Positive {
+            test: test.into(),
+            alpha_tests: Vec::new(),
+            temporal_tests: Vec::new(),
+        }
+    }
+
+    pub fn new_negative(test: impl Into<Vec<ConditionTest>>) -> Self {
+        Self::Negative {
+            test: test.into(),
+            alpha_tests: Vec::new(),
+            temporal_tests: Vec::new(),
+        }
+    }
+
+    /// Adds an alpha test to a positive or negative condition.
+    ///
+    /// Panics if the condition is of any other kind.
+    pub fn with_alpha_test(mut self, test: AlphaTest) -> Self {
+        match &mut self {
+            Condition::Positive { alpha_tests, .. } | Condition::Negative { alpha_tests, .. } => {
+                alpha_tests.push(test)
+            }
+            _ => panic!("Only positive and negative conditions can have alpha tests"),
+        }
+        self
     }
 
-    pub const fn new_negative(test: [ConditionTest; 3]) -> Self {
-        Self::Negative { test }
+    /// Requires the WME matching a positive or negative condition to stand in the relation to
+    /// the WME bound to `variable` by an earlier positive condition. The test is performed by the
+    /// condition's join or negative node.
+    ///
+    /// Panics if the condition is of any other kind.
+    pub fn with_temporal_test(mut self, relation: TemporalRelation, variable: usize) -> Self {
+        match &mut self {
+            Condition::Positive { temporal_tests, .. }
+            | Condition::Negative { temporal_tests, .. } => {
+                temporal_tests.push(TemporalTest { relation, variable })
+            }
+            _ => panic!("Only positive and negative conditions can have temporal tests"),
+        }
+        self
+    }
+
+    /// Returns the alpha tests of a positive or negative condition in a canonical order,
+    /// including the implicit field equality tests for variables occurring more than once
+    /// in its pattern. Ordering the tests maximises sharing in the discrimination network.
+    ///
+    /// Any other kind of condition yields an empty list.
+    pub fn alpha_tests(&self) -> Vec<AlphaTest> {
+        let (Condition::Positive {
+            test, alpha_tests, ..
+        }
+        | Condition::Negative {
+            test, alpha_tests, ..
+        }) = self
+        else {
+            return vec![];
+        };
+
+        let mut tests = alpha_tests.clone();
+
+        for (i, t) in test.iter().enumerate() {
+            let ConditionTest::Variable(var) = t else {
+                continue;
+            };
+            if let Some(first) = test[..i]
+                .iter()
+                .position(|t| *t == ConditionTest::Variable(*var))
+            {
+                tests.push(AlphaTest::FieldsEqual {
+                    field_one: first,
+                    field_two: i,
+                });
+            }
+        }
+
+        tests.sort();
+        tests.dedup();
+        tests
     }
 
     pub fn new_ncc(subconditions: Vec<Self>) -> Self {
         Self::NegativeConjunction { subconditions }
     }
 
+    pub fn new_test(predicate: Predicate, variables: Vec<usize>) -> Self {
+        Self::Test {
+            predicate,
+            variables,
+        }
+    }
+
+    pub fn new_forall(pattern: impl Into<Vec<ConditionTest>>, constraints: Vec<Self>) -> Self {
+        Self::Forall {
+            pattern: pattern.into(),
+            constraints,
+        }
+    }
+
+    /// Returns the subconditions of the NCC a forall condition compiles to, i.e.
+    /// there is no WME matching the pattern that does not satisfy the constraints.
+    ///
+    /// Returns `None` if the condition is not a forall.
+    pub fn forall_to_ncc_subconditions(&self) -> Option<Vec<Self>> {
+        let Condition::Forall {
+            pattern,
+            constraints,
+        } = self
+        else {
+            return None;
+        };
+
+        Some(vec![
+            Self::new_positive(pattern.clone()),
+            Self::new_ncc(constraints.clone()),
+        ])
+    }
+
     /// Returns an iterator over only the variable test, along with
     /// their indices.
     #[inline]
     pub fn variables(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
         match self {
-            Condition::Positive { test } | Condition::Negative { test } => {
+            Condition::Positive { test, .. } | Condition::Negative { test, .. } => {
                 test.iter().enumerate().filter_map(|(i, test)| match test {
                     ConditionTest::Variable(id) => Some((i, *id)),
                     ConditionTest::Constant(_) => None,
@@ -791,8 +1353,191 @@ impl Condition {
             Condition::NegativeConjunction { .. } => {
                 panic!("NCC Condition does not have variables")
             }
+            Condition::Forall { .. } => {
+                panic!("Forall Condition does not have variables")
+            }
+            Condition::Test { .. } => {
+                panic!("Test Condition does not bind variables")
+            }
+        }
+    }
+}
+
+/// Errors arising from conditions whose variables are not scoped in a way the network can
+/// evaluate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionError {
+    /// The LHS of a production, an NCC or a forall contains no conditions.
+    Empty,
+
+    /// A variable is tested in a negative condition before the positive condition binding it.
+    /// Negative nodes can only access bindings from conditions higher up in the network.
+    NegatedBeforeBound { variable: usize },
+
+    /// None of the forall's constraints reference a variable bound by its pattern,
+    /// which makes the pattern irrelevant to the outcome.
+    UnconstrainedForall,
+
+    /// A test condition uses a variable that is not bound by a preceding positive condition.
+    UnboundTestVariable { variable: usize },
+
+    /// An alpha test accesses a field outside of its condition's pattern.
+    AlphaTestOutOfBounds { field: usize, arity: usize },
+
+    /// A temporal test relates to a variable that is not bound by a preceding positive condition.
+    UnboundTemporalVariable { variable: usize },
+}
+
+impl std::error::Error for ConditionError {}
+
+/// Validates the variable scoping of the given conditions. Variables bound in a positive
+/// condition are visible to all conditions following it on the same level, and to all
+/// subconditions of NCCs and foralls following it.
+/// Variables bound inside an NCC or a forall are not visible outside of it.
+pub fn validate_conditions(conditions: &[Condition]) -> Result<(), ConditionError> {
+    validate_scope(conditions, &mut vec![])
+}
+
+fn check_alpha_tests(condition: &Condition) -> Result<(), ConditionError> {
+    let (Condition::Positive {
+        test, alpha_tests, ..
+    }
+    | Condition::Negative {
+        test, alpha_tests, ..
+    }) = condition
+    else {
+        return Ok(());
+    };
+
+    let arity = test.len();
+
+    match alpha_tests
+        .iter()
+        .flat_map(AlphaTest::fields)
+        .find(|field| *field >= arity)
+    {
+        Some(field) => Err(ConditionError::AlphaTestOutOfBounds { field, arity }),
+        None => Ok(()),
+    }
+}
+
+fn check_temporal_tests(condition: &Condition, bound: &[usize]) -> Result<(), ConditionError> {
+    let (Condition::Positive { temporal_tests, .. } | Condition::Negative { temporal_tests, .. }) =
+        condition
+    else {
+        return Ok(());
+    };
+
+    match temporal_tests
+        .iter()
+        .find(|test| !bound.contains(&test.variable))
+    {
+        Some(test) => Err(ConditionError::UnboundTemporalVariable {
+            variable: test.variable,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn validate_scope(conditions: &[Condition], bound: &mut Vec<usize>) -> Result<(), ConditionError> {
+    if conditions.is_empty() {
+        return Err(ConditionError::Empty);
+    }
+
+    let scope_start = bound.len();
+
+    for (i, condition) in conditions.iter().enumerate() {
+        let following = &conditions[i + 1..];
+
+        match condition {
+            Condition::Positive { .. } => {
+                check_alpha_tests(condition)?;
+                check_temporal_tests(condition, bound)?;
+                bound.extend(condition.variables().map(|(_, var)| var));
+            }
+            Condition::Negative { .. } => {
+                check_alpha_tests(condition)?;
+                check_temporal_tests(condition, bound)?;
+                check_negated_before_bound(std::slice::from_ref(condition), following, bound)?;
+            }
+            Condition::NegativeConjunction { subconditions } => {
+                validate_scope(subconditions, bound)?;
+                check_negated_before_bound(subconditions, following, bound)?;
+            }
+            Condition::Test { variables, .. } => {
+                if let Some(var) = variables.iter().find(|var| !bound.contains(var)) {
+                    return Err(ConditionError::UnboundTestVariable { variable: *var });
+                }
+            }
+            Condition::Forall {
+                pattern,
+                constraints,
+            } => {
+                let subconditions = condition.forall_to_ncc_subconditions().unwrap();
+                validate_scope(&subconditions, bound)?;
+
+                let pattern_vars = pattern
+                    .iter()
+                    .filter_map(|test| match test {
+                        ConditionTest::Variable(var) if !bound.contains(var) => Some(*var),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                if !pattern_vars.is_empty()
+                    && !pattern_vars
+                        .iter()
+                        .any(|var| references_variable(constraints, *var))
+                {
+                    return Err(ConditionError::UnconstrainedForall);
+                }
+
+                check_negated_before_bound(&subconditions, following, bound)?;
+            }
         }
     }
+
+    bound.truncate(scope_start);
+
+    Ok(())
+}
+
+/// Variables used in negated conditions are local to them unless bound beforehand,
+/// so binding them in a following positive condition indicates a misordered LHS.
+fn check_negated_before_bound(
+    negated: &[Condition],
+    following: &[Condition],
+    bound: &[usize],
+) -> Result<(), ConditionError> {
+    for condition in following {
+        let Condition::Positive { .. } = condition else {
+            continue;
+        };
+        for (_, var) in condition.variables() {
+            if !bound.contains(&var) && references_variable(negated, var) {
+                return Err(ConditionError::NegatedBeforeBound { variable: var });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `var` appears anywhere in the given conditions, including nested ones.
+fn references_variable(conditions: &[Condition], var: usize) -> bool {
+    conditions.iter().any(|condition| match condition {
+        Condition::Positive { temporal_tests, .. } | Condition::Negative { temporal_tests, .. } => {
+            condition.variables().any(|(_, v)| v == var)
+                || temporal_tests.iter().any(|test| test.variable == var)
+        }
+        Condition::NegativeConjunction { subconditions } => references_variable(subconditions, var),
+        Condition::Test { variables, .. } => variables.contains(&var),
+        Condition::Forall {
+            pattern,
+            constraints,
+        } => {
+            pattern.contains(&ConditionTest::Variable(var)) || references_variable(constraints, var)
+        }
+    })
 }
 
 /// A negative join results represents a successful join test performed by a negative node.
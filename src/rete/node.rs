@@ -1,6 +1,9 @@
 use super::{
     id::{alpha_node_id, beta_node_id},
-    item::{AlphaMemoryItem, JoinTest, Production},
+    item::{
+        Activation, AlphaMemoryItem, AlphaTest, JoinTest, Predicate, Production, VariableLocation,
+        Wme,
+    },
     IntoCell, IntoNodeCell, RcCell, ReteNode, ReteToken,
 };
 use std::{collections::VecDeque, rc::Rc};
@@ -15,6 +18,7 @@ pub enum Node {
     Negative(NegativeNode),
     Ncc(NccNode),
     NccPartner(NccPartnerNode),
+    Filter(FilterNode),
 }
 
 impl Node {
@@ -27,11 +31,12 @@ impl Node {
             Node::Production(ProductionNode { production, .. }) => production.id,
             Node::Ncc(ncc) => ncc.id,
             Node::NccPartner(ncc_partner) => ncc_partner.id,
+            Node::Filter(filter) => filter.id,
         }
     }
 
     #[inline]
-    pub fn _type(&self) -> &str {
+    pub fn _type(&self) -> &'static str {
         match self {
             Node::Beta(_) => "beta",
             Node::Join(_) => "join",
@@ -39,6 +44,7 @@ impl Node {
             Node::Production(_) => "prod",
             Node::Ncc(_) => "ncc",
             Node::NccPartner(_) => "ncc-partner",
+            Node::Filter(_) => "filter",
         }
     }
 
@@ -49,6 +55,7 @@ impl Node {
             Node::Join(node) => &node.children,
             Node::Negative(node) => &node.children,
             Node::Ncc(node) => &node.children,
+            Node::Filter(node) => &node.children,
             _ => &[],
         }
     }
@@ -60,6 +67,7 @@ impl Node {
             Node::Join(node) => &node.children,
             Node::Negative(node) => &node.children,
             Node::Ncc(node) => &node.children,
+            Node::Filter(node) => &node.children,
             _ => &[],
         }
     }
@@ -70,6 +78,8 @@ impl Node {
             Node::Beta(node) => &node.items,
             Node::Negative(node) => &node.items,
             Node::Ncc(node) => &node.items,
+            Node::Filter(node) => &node.items,
+            Node::Production(node) => &node.items,
             _ => &[],
         }
     }
@@ -83,6 +93,7 @@ impl Node {
             Node::Ncc(node) => Some(Rc::clone(&node.parent)),
             Node::Production(node) => Some(Rc::clone(&node.parent)),
             Node::NccPartner(node) => Some(Rc::clone(&node.parent)),
+            Node::Filter(node) => Some(Rc::clone(&node.parent)),
         }
     }
 
@@ -90,7 +101,7 @@ impl Node {
     pub fn add_child(&mut self, node: &ReteNode) {
         match self {
             Node::Beta(ref mut beta) => {
-                println!(
+                trace!(
                     "👶 Adding child {}({}) to Beta Node {}",
                     node.borrow()._type(),
                     node.borrow().id(),
@@ -99,7 +110,7 @@ impl Node {
                 beta.children.push(Rc::clone(node))
             }
             Node::Join(ref mut join) => {
-                println!(
+                trace!(
                     "👶 Adding child {}({}) to Join Node {}",
                     node.borrow()._type(),
                     node.borrow().id(),
@@ -108,7 +119,7 @@ impl Node {
                 join.children.push(Rc::clone(node))
             }
             Node::Negative(ref mut negative) => {
-                println!(
+                trace!(
                     "👶 Adding child {}({}) to Negative Node {}",
                     node.borrow()._type(),
                     node.borrow().id(),
@@ -117,7 +128,7 @@ impl Node {
                 negative.children.push(Rc::clone(node))
             }
             Node::Ncc(ref mut ncc) => {
-                println!(
+                trace!(
                     "👶 Adding child {}({}) to NCC Node {}",
                     node.borrow()._type(),
                     node.borrow().id(),
@@ -125,6 +136,15 @@ impl Node {
                 );
                 ncc.children.push(Rc::clone(node))
             }
+            Node::Filter(ref mut filter) => {
+                trace!(
+                    "👶 Adding child {}({}) to Filter Node {}",
+                    node.borrow()._type(),
+                    node.borrow().id(),
+                    filter.id
+                );
+                filter.children.push(Rc::clone(node))
+            }
             Node::Production(_) => unreachable!("Production Node cannot have children"),
             Node::NccPartner(_) => unreachable!("NCC Partner Node cannot have children"),
         }
@@ -134,21 +154,25 @@ impl Node {
     pub fn remove_child(&mut self, id: usize) {
         match self {
             Node::Beta(beta) => {
-                println!("❌ Removing {} from Beta Node {}", id, beta.id);
+                trace!("❌ Removing {} from Beta Node {}", id, beta.id);
                 beta.children.retain(|child| child.borrow().id() != id)
             }
             Node::Join(join) => {
-                println!("❌ Removing {} from Join Node {}", id, join.id);
+                trace!("❌ Removing {} from Join Node {}", id, join.id);
                 join.children.retain(|child| child.borrow().id() != id)
             }
             Node::Negative(negative) => {
-                println!("❌ Removing {} from Negative Node {}", id, negative.id);
+                trace!("❌ Removing {} from Negative Node {}", id, negative.id);
                 negative.children.retain(|child| child.borrow().id() != id)
             }
             Node::Ncc(ncc) => {
-                println!("❌ Removing {} from NCC Node {}", id, ncc.id);
+                trace!("❌ Removing {} from NCC Node {}", id, ncc.id);
                 ncc.children.retain(|child| child.borrow().id() != id)
             }
+            Node::Filter(filter) => {
+                trace!("❌ Removing {} from Filter Node {}", id, filter.id);
+                filter.children.retain(|child| child.borrow().id() != id)
+            }
             Node::Production(_) => unreachable!("Production Node cannot have children"),
             Node::NccPartner(_) => unreachable!("NCC Partner Node cannot have children"),
         }
@@ -160,53 +184,35 @@ impl Node {
     /// Right unlinking makes sure no unnecessary work is performed when traversing the Rete
     /// due to activations.
     #[inline]
-    pub fn right_unlink(&self) {
-        match self {
-            Node::Beta(node) => {
-                if node.items.is_empty() {
-                    for child in node.children.iter() {
-                        if let Node::Join(ref join) = *child.borrow() {
-                            join.alpha_mem
-                                .borrow_mut()
-                                .successors
-                                .retain(|suc| suc.borrow().id() != join.id);
-                        }
-                        if let Node::Negative(ref negative) = *child.borrow() {
-                            negative
-                                .alpha_mem
-                                .borrow_mut()
-                                .successors
-                                .retain(|suc| suc.borrow().id() != negative.id);
+    pub fn right_unlink(node: &ReteNode) {
+        match &*node.borrow() {
+            Node::Beta(beta) if beta.items.is_empty() => {
+                for child in beta.children.iter() {
+                    match &mut *child.borrow_mut() {
+                        Node::Join(join) => {
+                            trace!(
+                                "💥 Right unlinking {} from {}",
+                                join.id,
+                                join.alpha_mem.borrow().id
+                            );
+                            join.alpha_mem.borrow_mut().remove_successor(child);
+                            join.right_linked = false;
                         }
-                        match &mut *child.borrow_mut() {
-                            Node::Join(node) => {
-                                println!(
-                                    "💥 Right unlinking {} from {}",
-                                    node.id,
-                                    node.alpha_mem.borrow().id
-                                );
-                                node.right_linked = false;
-                            }
-                            Node::Negative(node) => {
-                                println!(
-                                    "💥 Right unlinking {} from {}",
-                                    node.id,
-                                    node.alpha_mem.borrow().id
-                                );
-                                node.right_linked = false;
-                            }
-                            _ => {}
+                        Node::Negative(negative) => {
+                            trace!(
+                                "💥 Right unlinking {} from {}",
+                                negative.id,
+                                negative.alpha_mem.borrow().id
+                            );
+                            negative.alpha_mem.borrow_mut().remove_successor(child);
+                            negative.right_linked = false;
                         }
+                        _ => {}
                     }
                 }
             }
-            Node::Negative(node) => {
-                if node.items.is_empty() {
-                    node.alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .retain(|suc| suc.borrow().id() != node.id);
-                }
+            Node::Negative(negative) if negative.items.is_empty() => {
+                negative.alpha_mem.borrow_mut().remove_successor(node);
             }
             _ => {}
         }
@@ -216,86 +222,57 @@ impl Node {
     /// always want to activate descendants before ancestors.
     #[inline]
     pub fn relink_to_alpha_mem(node: &ReteNode) {
-        match &*node.borrow() {
-            Node::Join(join) => {
-                println!(
-                    "🔗 Relinking join {} to alpha memory {}",
-                    join.id,
-                    join.alpha_mem.borrow().id
-                );
-
-                let mut ancestor = join.nearest_ancestor.clone();
-                while let Some(anc) = ancestor.clone() {
-                    let anc = anc.borrow();
-                    if !anc.is_right_linked() {
-                        ancestor = anc.nearest_ancestor();
-                        continue;
-                    }
-                    break;
-                }
-
-                println!(
-                    "Found nearest ancestor with same alpha mem: {:?}",
-                    ancestor.as_ref().map(|a| a.borrow().id())
-                );
+        let (alpha_mem, nearest_ancestor) = match &*node.borrow() {
+            Node::Join(join) => (Rc::clone(&join.alpha_mem), join.nearest_ancestor.clone()),
+            Node::Negative(negative) => (
+                Rc::clone(&negative.alpha_mem),
+                negative.nearest_ancestor.clone(),
+            ),
+            _ => return,
+        };
 
-                // We have to maintain the ordering of the ancestor, i.e. we always
-                // need to make sure descendants get activated before ancestors. We
-                // know the current node is a descendant and must be activated before its
-                // nearest ancestor.
-
-                // We are placing the descendant immediatelly after the ancestor because
-                // the successors get activated in reverse order
-                if let Some(anc) = ancestor {
-                    let index = join
-                        .alpha_mem
-                        .borrow()
-                        .successors
-                        .iter()
-                        .position(|suc| suc.borrow().id() == anc.borrow().id())
-                        .unwrap();
-                    join.alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .insert(index + 1, Rc::clone(node))
-                } else {
-                    join.alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .push_front(Rc::clone(node))
-                }
+        trace!(
+            "🔗 Relinking {} {} to alpha memory {}",
+            node.borrow()._type(),
+            node.borrow().id(),
+            alpha_mem.borrow().id
+        );
+
+        // An ancestor is linked if it is among the successors. Looking it up there instead
+        // of borrowing it works for ancestors in the middle of being activated, which are
+        // mutably borrowed.
+        let mut ancestor = nearest_ancestor;
+        while let Some(anc) = ancestor.clone() {
+            if alpha_mem.borrow().has_successor(&anc) {
+                break;
             }
-            Node::Negative(negative) => {
-                let mut ancestor = negative.nearest_ancestor.clone();
-                while let Some(anc) = ancestor.clone() {
-                    let anc = &*anc.borrow();
-                    if !anc.is_right_linked() {
-                        ancestor = anc.nearest_ancestor();
-                    }
-                }
-                if let Some(anc) = ancestor {
-                    let index = negative
-                        .alpha_mem
-                        .borrow()
-                        .successors
-                        .iter()
-                        .position(|suc| suc.borrow().id() == anc.borrow().id())
-                        .unwrap();
-                    negative
-                        .alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .insert(index + 1, Rc::clone(node))
-                } else {
-                    negative
-                        .alpha_mem
-                        .borrow_mut()
-                        .successors
-                        .push_front(Rc::clone(node))
-                }
-            }
-            _ => {}
-        };
+            ancestor = anc.borrow().nearest_ancestor();
+        }
+
+        trace!(
+            "Found nearest ancestor with same alpha mem: {:?}",
+            ancestor.as_ref().map(Rc::as_ptr)
+        );
+
+        // We have to maintain the ordering of the ancestor, i.e. we always
+        // need to make sure descendants get activated before ancestors. We
+        // know the current node is a descendant and must be activated before its
+        // nearest ancestor.
+
+        // We are placing the descendant immediatelly after the ancestor because
+        // the successors get activated in reverse order
+        let mut alpha_mem = alpha_mem.borrow_mut();
+        if let Some(anc) = ancestor {
+            let index = alpha_mem
+                .successors
+                .iter()
+                .position(|suc| Rc::ptr_eq(suc, &anc))
+                .unwrap();
+            alpha_mem.successors.insert(index + 1, Rc::clone(node))
+        } else {
+            alpha_mem.successors.push_front(Rc::clone(node))
+        }
+        drop(alpha_mem);
 
         match &mut *node.borrow_mut() {
             Node::Join(node) => node.right_linked = true,
@@ -306,22 +283,26 @@ impl Node {
 
     #[inline]
     pub fn add_token(&mut self, token: &ReteToken) {
-        println!("Node {} adding token {}", self.id(), token.borrow());
+        trace!("Node {} adding token {}", self.id(), token.borrow());
         match self {
             Node::Beta(beta) => beta.items.push(Rc::clone(token)),
             Node::Negative(negative) => negative.items.push(Rc::clone(token)),
             Node::Ncc(ncc) => ncc.items.push(Rc::clone(token)),
+            Node::Filter(filter) => filter.items.push(Rc::clone(token)),
+            Node::Production(production) => production.items.push(Rc::clone(token)),
             _ => unreachable!("Node cannot contain tokens"),
         }
     }
 
     #[inline]
     pub fn remove_token(&mut self, id: usize) {
-        println!("Node {} removing token {}", self.id(), id);
+        trace!("Node {} removing token {}", self.id(), id);
         match self {
             Node::Beta(beta) => beta.items.retain(|tok| tok.borrow().id() != id),
             Node::Negative(negative) => negative.items.retain(|tok| tok.borrow().id() != id),
             Node::Ncc(ncc) => ncc.items.retain(|tok| tok.borrow().id() != id),
+            Node::Filter(filter) => filter.items.retain(|tok| tok.borrow().id() != id),
+            Node::Production(production) => production.items.retain(|tok| tok.borrow().id() != id),
             _ => unreachable!("Node cannot contain tokens"),
         }
     }
@@ -369,6 +350,14 @@ pub struct AlphaMemoryNode {
     pub id: usize,
     pub items: Vec<RcCell<AlphaMemoryItem>>,
     pub successors: VecDeque<ReteNode>,
+
+    /// The amount of join and negative nodes using this memory. Unlike `successors`, this
+    /// is unaffected by right unlinking and is used to determine when the memory can be deleted.
+    pub reference_count: usize,
+
+    /// The shortest [window][AlphaTest::Within] of the tests leading to this memory. WMEs
+    /// leave the memory once they are older than this.
+    pub window: Option<u64>,
 }
 
 impl AlphaMemoryNode {
@@ -377,10 +366,119 @@ impl AlphaMemoryNode {
             id: alpha_node_id(),
             items: vec![],
             successors: VecDeque::new(),
+            reference_count: 0,
+            window: None,
         };
-        println!("Created Alpha Memory: {am}");
+        trace!("Created Alpha Memory: {am}");
         am
     }
+
+    /// Returns `true` if the node is right linked to the memory. Compares pointers, so the
+    /// node does not get borrowed.
+    pub fn has_successor(&self, node: &ReteNode) -> bool {
+        self.successors.iter().any(|suc| Rc::ptr_eq(suc, node))
+    }
+
+    /// Right unlinks the node from the memory. Compares pointers, so neither the node nor
+    /// the other successors get borrowed.
+    pub fn remove_successor(&mut self, node: &ReteNode) {
+        self.successors.retain(|suc| !Rc::ptr_eq(suc, node));
+    }
+}
+
+/// A node in the alpha discrimination network. Every [ConstantTest][super::item::ConstantTest]
+/// with conditions containing [AlphaTest]s roots a tree of these, where each node performs
+/// a single test and passes WMEs down to its children only if it succeeds.
+///
+/// Conditions with the same constant test and a common prefix of (ordered) alpha tests
+/// share the nodes for that prefix.
+#[derive(Debug)]
+pub struct AlphaTestNode {
+    pub id: usize,
+
+    /// Shared with the [forks][crate::rete::Rete::fork] of the Rete
+    pub test: Rc<AlphaTest>,
+    pub children: Vec<AlphaTestNode>,
+
+    /// Holds the WMEs passing the tests on the path to this node, if a condition ends here.
+    pub memory: Option<RcCell<AlphaMemoryNode>>,
+}
+
+impl AlphaTestNode {
+    pub fn new(test: AlphaTest) -> Self {
+        Self {
+            id: alpha_node_id(),
+            test: Rc::new(test),
+            children: vec![],
+            memory: None,
+        }
+    }
+
+    /// Finds or creates the path for the given tests starting at `nodes` and returns the memory
+    /// at its end. The returned flag indicates whether the memory was newly created.
+    pub fn build_or_share(
+        nodes: &mut Vec<AlphaTestNode>,
+        tests: &[AlphaTest],
+    ) -> (RcCell<AlphaMemoryNode>, bool) {
+        let Some((test, rest)) = tests.split_first() else {
+            panic!("Cannot build alpha test nodes without tests")
+        };
+
+        let idx = match nodes.iter().position(|node| *node.test == *test) {
+            Some(idx) => idx,
+            None => {
+                nodes.push(AlphaTestNode::new(test.clone()));
+                nodes.len() - 1
+            }
+        };
+
+        let node = &mut nodes[idx];
+
+        if !rest.is_empty() {
+            return Self::build_or_share(&mut node.children, rest);
+        }
+
+        match node.memory {
+            Some(ref memory) => (Rc::clone(memory), false),
+            None => {
+                let memory = AlphaMemoryNode::new().to_cell();
+                node.memory = Some(Rc::clone(&memory));
+                (memory, true)
+            }
+        }
+    }
+
+    /// Pushes the memories of all nodes reachable from this one whose tests pass for the WME.
+    pub fn collect_memories(&self, wme: &Wme, now: u64, acc: &mut Vec<RcCell<AlphaMemoryNode>>) {
+        if !self.test.matches(wme, now) {
+            return;
+        }
+
+        if let Some(ref memory) = self.memory {
+            acc.push(Rc::clone(memory));
+        }
+
+        for child in self.children.iter() {
+            child.collect_memories(wme, now, acc);
+        }
+    }
+
+    /// Detaches the memory from the nodes and removes all nodes which no longer
+    /// lead to any memory.
+    pub fn remove_memory(nodes: &mut Vec<AlphaTestNode>, memory: &RcCell<AlphaMemoryNode>) {
+        for node in nodes.iter_mut() {
+            if node
+                .memory
+                .as_ref()
+                .is_some_and(|mem| Rc::ptr_eq(mem, memory))
+            {
+                node.memory = None;
+            }
+            Self::remove_memory(&mut node.children, memory);
+        }
+
+        nodes.retain(|node| node.memory.is_some() || !node.children.is_empty());
+    }
 }
 
 impl PartialEq for AlphaMemoryNode {
@@ -412,7 +510,7 @@ impl BetaMemoryNode {
     }
 
     pub fn dummy() -> ReteNode {
-        println!("Initiating dummy Beta Node");
+        trace!("Initiating dummy Beta Node");
         Self {
             id: DUMMY_NODE_ID,
             parent: None,
@@ -430,7 +528,9 @@ pub struct JoinNode {
     pub parent: ReteNode,
     pub alpha_mem: RcCell<AlphaMemoryNode>,
     pub children: Vec<ReteNode>,
-    pub tests: Vec<JoinTest>,
+
+    /// Shared with the [forks][crate::rete::Rete::fork] of the Rete
+    pub tests: Rc<[JoinTest]>,
 
     /// Indicates the nearest ancestor node with the same
     /// alpha memory as this one. Used for relinking.
@@ -450,7 +550,7 @@ impl JoinNode {
             parent: parent.clone(),
             alpha_mem: alpha_mem.clone(),
             children: vec![],
-            tests,
+            tests: tests.into(),
             nearest_ancestor: None,
             left_linked: true,
             right_linked: true,
@@ -469,7 +569,9 @@ pub struct NegativeNode {
     pub children: Vec<ReteNode>,
     pub items: Vec<ReteToken>,
     pub alpha_mem: RcCell<AlphaMemoryNode>,
-    pub tests: Vec<JoinTest>,
+
+    /// Shared with the [forks][crate::rete::Rete::fork] of the Rete
+    pub tests: Rc<[JoinTest]>,
     pub nearest_ancestor: Option<ReteNode>,
     pub right_linked: bool,
 }
@@ -484,7 +586,7 @@ impl NegativeNode {
             id: beta_node_id(),
             items: vec![],
             alpha_mem: Rc::clone(alpha_mem),
-            tests,
+            tests: tests.into(),
             parent: Rc::clone(parent),
             children: vec![],
             nearest_ancestor: None,
@@ -551,11 +653,51 @@ impl NccPartnerNode {
     }
 }
 
+/// Filter nodes evaluate a [Predicate] over the values of variables bound by earlier conditions.
+/// Like negative nodes they keep a local memory of tokens, but only for the matches that
+/// passed the predicate, which are cleaned up along with their WMEs.
+#[derive(Debug)]
+pub struct FilterNode {
+    pub id: usize,
+    pub parent: ReteNode,
+    pub children: Vec<ReteNode>,
+    pub items: Vec<ReteToken>,
+    pub predicate: Predicate,
+
+    /// Where to find the predicate's arguments, in the order the predicate expects them.
+    /// Shared with the [forks][crate::rete::Rete::fork] of the Rete.
+    pub locations: Rc<[VariableLocation]>,
+}
+
+impl FilterNode {
+    pub fn new(parent: &ReteNode, predicate: &Predicate, locations: Vec<VariableLocation>) -> Self {
+        Self {
+            id: beta_node_id(),
+            parent: Rc::clone(parent),
+            children: vec![],
+            items: vec![],
+            predicate: predicate.clone(),
+            locations: locations.into(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ProductionNode {
     pub id: usize,
     pub parent: ReteNode,
     pub production: Production,
+
+    /// Tokens representing the complete matches of the production.
+    pub items: Vec<ReteToken>,
+
+    /// When set, activations get buffered here instead of being sent. Used to deliver
+    /// the activations of a [transaction][crate::rete::transaction] at once.
+    pub deferred: Option<RcCell<Vec<Activation>>>,
+
+    /// Set for the nodes of [queries][crate::rete::Rete::add_query], which only keep their
+    /// matches and never send activations.
+    pub query: bool,
 }
 
 impl ProductionNode {
@@ -564,12 +706,31 @@ impl ProductionNode {
             id: prod.id,
             parent: Rc::clone(parent),
             production: prod,
+            items: vec![],
+            deferred: None,
+            query: false,
         };
 
-        println!("Created production node {node}");
+        trace!("Created production node {node}");
 
         node
     }
+
+    /// Sends the activation through the production's channel, or buffers it if
+    /// activations are deferred. Nothing happens if the receiver was dropped.
+    pub fn notify(&self, activation: Activation) {
+        if self.query {
+            return;
+        }
+
+        if let Some(ref deferred) = self.deferred {
+            deferred.borrow_mut().push(activation);
+            return;
+        }
+
+        // Nobody listens for the activations anymore, the match still lives in the network
+        let _ = self.production.activation_channel.send(activation);
+    }
 }
 
 impl IntoNodeCell for BetaMemoryNode {
@@ -607,3 +768,9 @@ impl IntoNodeCell for NccPartnerNode {
         std::rc::Rc::new(std::cell::RefCell::new(Node::NccPartner(self)))
     }
 }
+
+impl IntoNodeCell for FilterNode {
+    fn to_node_cell(self) -> ReteNode {
+        std::rc::Rc::new(std::cell::RefCell::new(Node::Filter(self)))
+    }
+}
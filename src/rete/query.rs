@@ -0,0 +1,177 @@
+use super::{
+    explain::token_wmes,
+    item::{validate_conditions, Condition, ConditionError, ConditionTest, Production},
+    node::{Node, ProductionNode},
+    update_new_node_with_matches_from_above, IntoNodeCell, Rete, ReteToken,
+};
+use std::{collections::BTreeMap, rc::Rc, sync::mpsc::channel};
+
+/// A current match of a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryMatch {
+    /// The token representing the match
+    pub token: usize,
+
+    /// Values of the variables bound by the match
+    pub bindings: BTreeMap<usize, usize>,
+
+    /// IDs of the WMEs matching the positive conditions, in order
+    pub wmes: Vec<usize>,
+}
+
+impl QueryMatch {
+    /// Returns the value bound to the variable.
+    pub fn get(&self, variable: usize) -> Option<usize> {
+        self.bindings.get(&variable).copied()
+    }
+}
+
+/// Reasons a query cannot be added to a [Rete].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// A query with the name already exists.
+    DuplicateName(String),
+
+    InvalidConditions(ConditionError),
+}
+
+impl std::error::Error for QueryError {}
+
+/// Iterates over the matches of a query as they were when [Rete::query] was called.
+#[derive(Debug)]
+pub struct QueryMatches {
+    tokens: std::vec::IntoIter<ReteToken>,
+    conditions: Vec<Condition>,
+}
+
+impl QueryMatches {
+    pub(in crate::rete) fn new(tokens: Vec<ReteToken>, conditions: Vec<Condition>) -> Self {
+        Self {
+            tokens: tokens.into_iter(),
+            conditions,
+        }
+    }
+}
+
+impl Iterator for QueryMatches {
+    type Item = QueryMatch;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.tokens.next()?;
+
+        let wmes = token_wmes(&token);
+        let mut bindings = BTreeMap::new();
+
+        let positive = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                Condition::Positive { test, .. } => Some(test),
+                _ => None,
+            });
+        for (test, wme) in positive.zip(wmes.iter()) {
+            let wme = wme.borrow();
+            for (field, t) in test.iter().enumerate() {
+                if let ConditionTest::Variable(var) = t {
+                    bindings.entry(*var).or_insert(wme.fields[field]);
+                }
+            }
+        }
+
+        let id = token.borrow().id();
+        Some(QueryMatch {
+            token: id,
+            bindings,
+            wmes: wmes.iter().map(|wme| wme.borrow().id).collect(),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.tokens.size_hint()
+    }
+}
+
+impl Rete {
+    /// Compiles the conditions into the network like a production, sharing nodes with productions
+    /// and other queries. The query keeps track of its matches as WMEs change but never causes
+    /// any activations, its current matches are obtained through [Rete::query].
+    ///
+    /// Queries are carried over to forks and snapshots, but are not part of fact logs.
+    /// Returns the ID of the query's node, or an error if a query with the same name exists
+    /// or the conditions are invalid.
+    pub fn add_query(
+        &mut self,
+        name: impl Into<String>,
+        conditions: &[Condition],
+    ) -> Result<usize, QueryError> {
+        let name = name.into();
+
+        if self.queries.contains_key(&name) {
+            return Err(QueryError::DuplicateName(name));
+        }
+
+        validate_conditions(conditions).map_err(QueryError::InvalidConditions)?;
+
+        // Queries never send activations, the channel only satisfies the production
+        let production = Production::new(conditions, channel().0);
+
+        Ok(self.insert_query(name, production))
+    }
+
+    /// Compiles the query's production, whose conditions must be valid. Replaces an existing
+    /// query with the same name.
+    pub(in crate::rete) fn insert_query(&mut self, name: String, production: Production) -> usize {
+        trace!("------------\nAdding query {name}\n------------");
+
+        if let Some(existing) = self.queries.remove(&name) {
+            self.delete_node_and_unused_ancestors(existing);
+        }
+
+        let current_node = self.build_or_share_network_for_conditions(
+            &Rc::clone(&self.dummy_top_node),
+            &production.conditions,
+            &mut vec![],
+        );
+
+        let production = production.with_name(name.as_str());
+        let id = production.id;
+
+        let mut node = ProductionNode::new(production, &current_node);
+        node.query = true;
+        let node = node.to_node_cell();
+
+        self.queries.insert(name, Rc::clone(&node));
+
+        current_node.borrow_mut().add_child(&node);
+
+        update_new_node_with_matches_from_above(&node);
+
+        id
+    }
+
+    pub fn remove_query(&mut self, name: &str) -> bool {
+        let Some(node) = self.queries.remove(name) else {
+            return false;
+        };
+
+        trace!("------------\nRemoving query {name}\n------------");
+
+        self.delete_node_and_unused_ancestors(node);
+
+        true
+    }
+
+    /// Returns the current matches of the query, or `None` if it does not exist.
+    pub fn query(&self, name: &str) -> Option<QueryMatches> {
+        let node = self.queries.get(name)?;
+        let node = node.borrow();
+        let Node::Production(ref p_node) = *node else {
+            unreachable!("Query map contains a non production node")
+        };
+
+        Some(QueryMatches::new(
+            p_node.items.clone(),
+            p_node.production.conditions.clone(),
+        ))
+    }
+}
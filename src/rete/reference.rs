@@ -0,0 +1,241 @@
+use super::{
+    item::{Condition, ConditionTest, Wme},
+    node::Node,
+    query::{QueryMatch, QueryMatches},
+    Rete,
+};
+use std::{cell::Ref, collections::BTreeMap};
+
+/// A complete match of a list of conditions, comparable between the network and the
+/// [reference matcher][Rete::reference_matches].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompleteMatch {
+    /// IDs of the WMEs matching the positive conditions, in order
+    pub wmes: Vec<usize>,
+
+    /// Values of the variables bound by the positive conditions
+    pub bindings: BTreeMap<usize, usize>,
+}
+
+impl From<QueryMatch> for CompleteMatch {
+    fn from(query_match: QueryMatch) -> Self {
+        Self {
+            wmes: query_match.wmes,
+            bindings: query_match.bindings,
+        }
+    }
+}
+
+impl Rete {
+    /// Computes all complete matches of the conditions against the current working memory
+    /// from scratch, by trying every combination of WMEs. Negations, NCCs and foralls hold if
+    /// no combination of WMEs matches them.
+    ///
+    /// This is slow but simple enough to be obviously correct, and serves as a reference for
+    /// the matches the network computes incrementally, see [Rete::production_matches].
+    /// [Windows][super::item::AlphaTest::Within] are evaluated at the current time of the
+    /// clock, so both only agree once expired WMEs left their memories with [Rete::expire].
+    ///
+    /// The matches are sorted.
+    pub fn reference_matches(&self, conditions: &[Condition]) -> Vec<CompleteMatch> {
+        let mut wmes = self
+            .working_memory
+            .values()
+            .map(|wme| wme.borrow())
+            .collect::<Vec<_>>();
+        wmes.sort_by_key(|wme| wme.id);
+
+        let matcher = ReferenceMatcher {
+            wmes,
+            now: self.clock.now(),
+        };
+
+        let mut matches = vec![];
+        matcher.search(conditions, &mut Scope::default(), &mut |scope: &Scope| {
+            matches.push(CompleteMatch {
+                wmes: scope.positives.iter().map(|(_, wme)| wme.id).collect(),
+                bindings: scope.bindings.clone(),
+            });
+            true
+        });
+
+        matches.sort();
+        matches
+    }
+
+    /// Returns the complete matches of the production held by the network, i.e. the tokens
+    /// that reached its node, sorted. Returns `None` if the production does not exist.
+    pub fn production_matches(&self, production: usize) -> Option<Vec<CompleteMatch>> {
+        let node = self.productions.get(&production)?;
+        let node = node.borrow();
+        let Node::Production(ref p_node) = *node else {
+            unreachable!("Production map contains a non production node")
+        };
+
+        let mut matches =
+            QueryMatches::new(p_node.items.clone(), p_node.production.conditions.clone())
+                .map(CompleteMatch::from)
+                .collect::<Vec<_>>();
+
+        matches.sort();
+        Some(matches)
+    }
+}
+
+/// The state of a partial match.
+#[derive(Default)]
+struct Scope<'a> {
+    /// Values of the variables bound by the positive conditions matched so far
+    bindings: BTreeMap<usize, usize>,
+
+    /// The positive conditions matched so far along with their WMEs, including
+    /// those of enclosing conditions when matching an NCC
+    positives: Vec<(&'a Condition, &'a Wme)>,
+}
+
+struct ReferenceMatcher<'a> {
+    wmes: Vec<Ref<'a, Wme>>,
+    now: u64,
+}
+
+impl ReferenceMatcher<'_> {
+    /// Extends the scope with matches for the conditions and calls `found` for every
+    /// complete match. Stops and returns `false` as soon as `found` does.
+    fn search<'s>(
+        &'s self,
+        conditions: &'s [Condition],
+        scope: &mut Scope<'s>,
+        found: &mut dyn FnMut(&Scope) -> bool,
+    ) -> bool {
+        let Some((condition, rest)) = conditions.split_first() else {
+            return found(scope);
+        };
+
+        match condition {
+            Condition::Positive { test, .. } => {
+                for wme in self.wmes.iter() {
+                    if !self.passes(condition, wme, scope) {
+                        continue;
+                    }
+
+                    let mut bound = vec![];
+                    for (field, t) in test.iter().enumerate() {
+                        if let ConditionTest::Variable(var) = t {
+                            if !scope.bindings.contains_key(var) {
+                                scope.bindings.insert(*var, wme.fields[field]);
+                                bound.push(*var);
+                            }
+                        }
+                    }
+                    scope.positives.push((condition, wme));
+
+                    let proceed = self.search(rest, scope, found);
+
+                    scope.positives.pop();
+                    for var in bound {
+                        scope.bindings.remove(&var);
+                    }
+
+                    if !proceed {
+                        return false;
+                    }
+                }
+                true
+            }
+            Condition::Negative { .. } => {
+                if self
+                    .wmes
+                    .iter()
+                    .any(|wme| self.passes(condition, wme, scope))
+                {
+                    return true;
+                }
+                self.search(rest, scope, found)
+            }
+            Condition::NegativeConjunction { subconditions } => {
+                if self.exists(subconditions, scope) {
+                    return true;
+                }
+                self.search(rest, scope, found)
+            }
+            Condition::Forall { .. } => {
+                let subconditions = condition.forall_to_ncc_subconditions().unwrap();
+                if self.exists(&subconditions, scope) {
+                    return true;
+                }
+                self.search(rest, scope, found)
+            }
+            Condition::Test {
+                predicate,
+                variables,
+            } => {
+                let values = variables
+                    .iter()
+                    .map(|var| scope.bindings[var])
+                    .collect::<Vec<_>>();
+                if !predicate.test(&values) {
+                    return true;
+                }
+                self.search(rest, scope, found)
+            }
+        }
+    }
+
+    /// Returns `true` if the conditions match in the scope. Bindings made while matching
+    /// them are discarded.
+    fn exists(&self, conditions: &[Condition], scope: &Scope) -> bool {
+        let mut inner = Scope {
+            bindings: scope.bindings.clone(),
+            positives: scope.positives.clone(),
+        };
+        !self.search(conditions, &mut inner, &mut |_| false)
+    }
+
+    /// Tests the WME against a positive or negative condition: its constants, alpha tests,
+    /// temporal tests and the variables bound so far.
+    fn passes(&self, condition: &Condition, wme: &Wme, scope: &Scope) -> bool {
+        let (Condition::Positive {
+            test,
+            temporal_tests,
+            ..
+        }
+        | Condition::Negative {
+            test,
+            temporal_tests,
+            ..
+        }) = condition
+        else {
+            unreachable!("Only positive and negative conditions test single WMEs")
+        };
+
+        if test.len() != wme.fields.len() {
+            return false;
+        }
+
+        let fields_pass = test
+            .iter()
+            .zip(wme.fields.iter())
+            .all(|(t, field)| match t {
+                ConditionTest::Constant(constant) => constant == field,
+                ConditionTest::Variable(var) => {
+                    scope.bindings.get(var).is_none_or(|value| value == field)
+                }
+            });
+
+        fields_pass
+            && condition
+                .alpha_tests()
+                .iter()
+                .all(|alpha_test| alpha_test.matches(wme, self.now))
+            && temporal_tests.iter().all(|temporal| {
+                // Relates to the WME of the latest positive condition binding the variable
+                let other = scope.positives.iter().rev().find_map(|(condition, other)| {
+                    condition
+                        .variables()
+                        .any(|(_, var)| var == temporal.variable)
+                        .then_some(*other)
+                });
+                other.is_some_and(|other| temporal.relation.holds(wme, other))
+            })
+    }
+}
@@ -0,0 +1,269 @@
+use super::{
+    id::{reserve_prod_id, reserve_wme_id},
+    item::{
+        validate_conditions, Activation, Condition, ConditionError, Predicate, Production, Wme,
+    },
+    node::Node,
+    transaction::set_deferred,
+    Rete, ReteConfig,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::mpsc::{channel, Sender},
+};
+
+/// The persistable state of a [Rete]. Tokens and nodes are not part of it, they are re-derived
+/// from the productions and working memory when [restoring][Rete::restore].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub config: ReteConfig,
+
+    /// Ordered by ID, i.e. by the order the WMEs were added in
+    pub wmes: Vec<WmeSnapshot>,
+
+    /// Ordered by ID
+    pub productions: Vec<ProductionSnapshot>,
+
+    /// Ordered by ID
+    #[serde(default)]
+    pub queries: Vec<QuerySnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WmeSnapshot {
+    pub id: usize,
+    pub fields: Vec<usize>,
+
+    /// How many times the WME was added, always 1 without set semantics
+    pub references: usize,
+
+    pub timestamp: Option<u64>,
+    pub lifetime: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProductionSnapshot {
+    pub id: usize,
+    pub name: Option<String>,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuerySnapshot {
+    pub id: usize,
+    pub name: String,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    /// A test condition uses a predicate which was not supplied to the restore.
+    UnresolvedPredicate {
+        production: usize,
+        predicate: Predicate,
+    },
+
+    /// A production has no action supplied to the restore.
+    MissingAction { production: usize },
+
+    /// A production's conditions are not scoped in a way the network can evaluate.
+    InvalidConditions {
+        production: usize,
+        error: ConditionError,
+    },
+}
+
+impl std::error::Error for RestoreError {}
+
+/// How a predicate is stored, its function has to be supplied again when restoring.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredPredicate {
+    Id(usize),
+    Name(String),
+}
+
+/// Named predicates are stored by name, others by their ID.
+impl Serialize for Predicate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.name() {
+            Some(name) => StoredPredicate::Name(name.to_string()),
+            None => StoredPredicate::Id(self.id),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Predicate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match StoredPredicate::deserialize(deserializer)? {
+            StoredPredicate::Id(id) => Predicate::unresolved(id, None),
+            StoredPredicate::Name(name) => Predicate::unresolved(0, Some(name.into())),
+        })
+    }
+}
+
+impl Rete {
+    pub fn snapshot(&self) -> Snapshot {
+        let mut wmes = self
+            .working_memory
+            .values()
+            .map(|wme| {
+                let wme = wme.borrow();
+                let references = self.wme_set.get(&wme.fields).map_or(1, |(_, count)| *count);
+                WmeSnapshot {
+                    id: wme.id,
+                    fields: wme.fields.clone(),
+                    references,
+                    timestamp: wme.timestamp,
+                    lifetime: wme.lifetime,
+                }
+            })
+            .collect::<Vec<_>>();
+        wmes.sort_by_key(|wme| wme.id);
+
+        let mut productions = self
+            .productions
+            .iter()
+            .map(|(id, node)| {
+                let Node::Production(ref p_node) = *node.borrow() else {
+                    unreachable!("Production map contains a non production node")
+                };
+                ProductionSnapshot {
+                    id: *id,
+                    name: p_node.production.name.as_deref().map(String::from),
+                    conditions: p_node.production.conditions.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        productions.sort_by_key(|production| production.id);
+
+        let mut queries = self
+            .queries
+            .iter()
+            .map(|(name, node)| {
+                let Node::Production(ref p_node) = *node.borrow() else {
+                    unreachable!("Query map contains a non production node")
+                };
+                QuerySnapshot {
+                    id: p_node.production.id,
+                    name: name.clone(),
+                    conditions: p_node.production.conditions.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        queries.sort_by_key(|query| query.id);
+
+        Snapshot {
+            config: self.config,
+            wmes,
+            productions,
+            queries,
+        }
+    }
+
+    /// Rebuilds a Rete from the snapshot, keeping the IDs of its WMEs, productions and queries.
+    /// The predicates used by test conditions are resolved from `predicates`, by their name if
+    /// they are [named][Predicate::named] and by their ID otherwise. Only named predicates can
+    /// be resolved reliably in another process.
+    ///
+    /// All productions send their activations through `activation_tx`. The tokens of the matches
+    /// existing at the time of the snapshot get re-derived without sending activations for them,
+    /// so only changes made after restoring cause activations.
+    pub fn restore(
+        snapshot: Snapshot,
+        activation_tx: Sender<Activation>,
+        predicates: &[Predicate],
+    ) -> Result<Self, RestoreError> {
+        let mut rete = Rete::new(snapshot.config);
+
+        for production in snapshot.productions {
+            let mut conditions = production.conditions;
+            resolve_predicates(&mut conditions, predicates).map_err(|predicate| {
+                RestoreError::UnresolvedPredicate {
+                    production: production.id,
+                    predicate,
+                }
+            })?;
+
+            reserve_prod_id(production.id);
+            let mut restored =
+                Production::with_id(production.id, &conditions, activation_tx.clone());
+            restored.name = production.name.map(Into::into);
+            rete.add_production(restored)
+                .map_err(|error| RestoreError::InvalidConditions {
+                    production: production.id,
+                    error,
+                })?;
+        }
+
+        for query in snapshot.queries {
+            let mut conditions = query.conditions;
+            resolve_predicates(&mut conditions, predicates).map_err(|predicate| {
+                RestoreError::UnresolvedPredicate {
+                    production: query.id,
+                    predicate,
+                }
+            })?;
+            validate_conditions(&conditions).map_err(|error| RestoreError::InvalidConditions {
+                production: query.id,
+                error,
+            })?;
+
+            reserve_prod_id(query.id);
+            // Queries never send activations, the channel only satisfies the production
+            let production = Production::with_id(query.id, &conditions, channel().0);
+            rete.insert_query(query.name, production);
+        }
+
+        // The matches re-derived from the restored WMEs were reported before the snapshot
+        let deferred = Rc::new(RefCell::new(vec![]));
+        let productions = rete.productions.values().cloned().collect::<Vec<_>>();
+        set_deferred(&productions, Some(&deferred));
+
+        for wme in snapshot.wmes {
+            reserve_wme_id(wme.id);
+            for _ in 0..wme.references {
+                let mut restored = Wme::with_id(wme.id, wme.fields.clone());
+                restored.timestamp = wme.timestamp;
+                restored.lifetime = wme.lifetime;
+                rete.add_wme(restored);
+            }
+        }
+
+        set_deferred(&productions, None);
+
+        trace!(
+            "Discarding {} activations of the restored matches",
+            deferred.borrow().len()
+        );
+
+        Ok(rete)
+    }
+}
+
+/// Replaces the placeholder predicates of test conditions with the supplied ones.
+/// Returns the ID of the first predicate that could not be found.
+pub(in crate::rete) fn resolve_predicates(
+    conditions: &mut [Condition],
+    predicates: &[Predicate],
+) -> Result<(), Predicate> {
+    for condition in conditions.iter_mut() {
+        match condition {
+            Condition::Test { predicate, .. } => {
+                let Some(resolved) = predicates.iter().find(|p| *p == predicate) else {
+                    return Err(predicate.clone());
+                };
+                *predicate = resolved.clone();
+            }
+            Condition::NegativeConjunction { subconditions } => {
+                resolve_predicates(subconditions, predicates)?
+            }
+            Condition::Forall { constraints, .. } => resolve_predicates(constraints, predicates)?,
+            Condition::Positive { .. } | Condition::Negative { .. } => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use super::{
+    item::Token,
+    node::{AlphaMemoryNode, Node},
+    remove_from_alpha_memory, retract_negative_join_results, RcCell, Rete, ReteNode,
+};
+use std::{
+    cell::Cell,
+    fmt::Debug,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Supplies the current time to a [Rete], in milliseconds.
+pub trait Clock: Debug {
+    fn now(&self) -> u64;
+}
+
+/// Milliseconds since the Unix epoch. Used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64)
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations.
+#[derive(Debug, Default)]
+pub struct PseudoClock {
+    now: Cell<u64>,
+}
+
+impl PseudoClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.set(now)
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.now.set(self.now.get() + millis)
+    }
+}
+
+impl Clock for PseudoClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+impl Rete {
+    /// Replaces the clock used for timestamping WMEs. Share the clock through the `Rc`
+    /// to keep control over it, e.g. when using a [PseudoClock].
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Retracts all WMEs whose lifetime has passed and removes WMEs from the memories of
+    /// [windows][super::item::AlphaTest::Within] they are no longer within.
+    ///
+    /// Gets called whenever a WME is added, call it to account for time passing without
+    /// any new WMEs. Returns the IDs of the retracted WMEs.
+    ///
+    /// During a [transaction][Rete::begin_transaction], the retractions are buffered like any
+    /// other removal and the WMEs expire again if the transaction is rolled back. WMEs only
+    /// leave windows once no transaction is in progress.
+    pub fn expire(&mut self) -> Vec<usize> {
+        let now = self.now();
+
+        let mut expired = vec![];
+        while let Some(mut entry) = self.expirations.first_entry() {
+            let (at, id) = *entry.key();
+            if at > now {
+                break;
+            }
+            // Each addition of the WME expiring at this time removes it once
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+
+            if !self.working_memory.contains_key(&id) {
+                trace!("Skipping expiration of removed WME {id}");
+                continue;
+            }
+
+            trace!("WME {id} expired at {at}");
+            if let Some(ref mut transaction) = self.transaction {
+                transaction.expired.push((at, id));
+            }
+            self.remove_wme(id);
+            expired.push(id);
+        }
+
+        if self.transaction.is_some() {
+            return expired;
+        }
+
+        while let Some(entry) = self.window_exits.first_entry() {
+            let (at, id) = *entry.key();
+            if at > now {
+                break;
+            }
+            for memory in entry.remove() {
+                trace!(
+                    "WME {id} left the window of alpha memory {} at {at}",
+                    memory.borrow().id
+                );
+                self.leave_alpha_memory(id, &memory);
+            }
+        }
+
+        expired
+    }
+
+    /// Registers the time at which the WME leaves the memory, if the memory has a window.
+    pub(in crate::rete) fn schedule_window_exit(
+        &mut self,
+        id: usize,
+        timestamp: Option<u64>,
+        memory: &RcCell<AlphaMemoryNode>,
+    ) {
+        let (Some(timestamp), Some(window)) = (timestamp, memory.borrow().window) else {
+            return;
+        };
+
+        self.window_exits
+            .entry((timestamp.saturating_add(window), id))
+            .or_default()
+            .push(Rc::clone(memory));
+    }
+
+    /// Removes the WME from a single alpha memory while keeping it in working memory,
+    /// retracting all matches it was part of through that memory.
+    fn leave_alpha_memory(&mut self, id: usize, memory: &RcCell<AlphaMemoryNode>) {
+        let Some(memories) = self.wme_alphas.get_mut(&id) else {
+            return;
+        };
+
+        let len = memories.len();
+        memories.retain(|mem| !Rc::ptr_eq(mem, memory));
+        if memories.len() == len {
+            return;
+        }
+        if memories.is_empty() {
+            self.wme_alphas.remove(&id);
+        }
+
+        remove_from_alpha_memory(memory, id);
+
+        let Some(wme) = self.working_memory.get(&id).cloned() else {
+            return;
+        };
+
+        let tokens = wme
+            .borrow()
+            .tokens
+            .iter()
+            .filter(|token| {
+                joined_from(token.borrow().node()).is_some_and(|mem| Rc::ptr_eq(&mem, memory))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Newest first, same as when removing the WME
+        for token in tokens.into_iter().rev() {
+            Token::delete_self_and_descendants(token)
+        }
+
+        let mut wme = wme.borrow_mut();
+        let (results, remaining) = std::mem::take(&mut wme.negative_join_results)
+            .into_iter()
+            .partition::<Vec<_>, _>(|result| {
+                let owner = Rc::clone(result.borrow().owner.borrow().node());
+                let owner = owner.borrow();
+                matches!(&*owner, Node::Negative(negative) if Rc::ptr_eq(&negative.alpha_mem, memory))
+            });
+        wme.negative_join_results = remaining;
+        drop(wme);
+
+        retract_negative_join_results(results);
+    }
+}
+
+/// Returns the alpha memory of the join node that created tokens stored in the node,
+/// skipping over filter nodes in between.
+fn joined_from(node: &ReteNode) -> Option<RcCell<AlphaMemoryNode>> {
+    let mut current = node.borrow().parent();
+    while let Some(node) = current {
+        let node = node.borrow();
+        match &*node {
+            Node::Join(join) => return Some(Rc::clone(&join.alpha_mem)),
+            Node::Filter(filter) => current = Some(Rc::clone(&filter.parent)),
+            _ => return None,
+        }
+    }
+    None
+}
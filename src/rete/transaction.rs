@@ -0,0 +1,219 @@
+use super::{
+    item::{Activation, Wme},
+    node::Node,
+    RcCell, Rete, ReteNode,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/// Changes to working memory buffered between [Rete::begin_transaction] and
+/// [Rete::commit_transaction].
+#[derive(Debug, Default)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+
+    /// The set semantics entries touched by the transaction as they will be once it is committed.
+    /// `None` marks entries whose WME gets retracted.
+    set: HashMap<Vec<usize>, Option<(usize, usize)>>,
+
+    /// Fields of the WMEs added in the transaction
+    added: HashMap<usize, Vec<usize>>,
+
+    /// The [expirations][Rete::expirations] consumed while the transaction was in progress,
+    /// restored when it is rolled back
+    pub(in crate::rete) expired: Vec<(u64, usize)>,
+}
+
+#[derive(Debug)]
+pub enum Operation {
+    AddWme(Wme),
+    RemoveWme(usize),
+}
+
+impl Transaction {
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+}
+
+impl Rete {
+    /// Starts buffering changes to working memory. Until the transaction is committed, [Rete::add_wme]
+    /// and [Rete::remove_wme] only record the change and the network keeps matching against the
+    /// working memory as it was before the transaction. Productions are not part of transactions.
+    ///
+    /// Panics if a transaction is already in progress.
+    pub fn begin_transaction(&mut self) {
+        if self.transaction.is_some() {
+            panic!("Transactions cannot be nested");
+        }
+        self.transaction = Some(Transaction::default());
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Applies the changes of the current transaction in the order they were made.
+    ///
+    /// Activations caused by the commit get sent once all changes are applied. Matches that only
+    /// existed in between changes, i.e. got both created and retracted by the commit, are not
+    /// reported at all.
+    ///
+    /// Returns `false` if no transaction was in progress.
+    pub fn commit_transaction(&mut self) -> bool {
+        let Some(transaction) = self.transaction.take() else {
+            return false;
+        };
+
+        trace!(
+            "------------\nCommitting transaction with {} operations\n------------",
+            transaction.operations.len()
+        );
+
+        let deferred: RcCell<Vec<_>> = Rc::new(RefCell::new(vec![]));
+        let productions = self.productions.values().cloned().collect::<Vec<_>>();
+        set_deferred(&productions, Some(&deferred));
+
+        for operation in transaction.operations {
+            match operation {
+                Operation::AddWme(wme) => {
+                    self.add_wme(wme);
+                }
+                Operation::RemoveWme(id) => self.remove_wme(id),
+            }
+        }
+
+        set_deferred(&productions, None);
+
+        let activations = deferred.take();
+
+        let fired = activations
+            .iter()
+            .filter(|activation| matches!(activation, Activation::Fired { .. }))
+            .map(|activation| activation.token())
+            .collect::<HashSet<_>>();
+        let transient = activations
+            .iter()
+            .filter(|activation| {
+                matches!(activation, Activation::Retracted { .. })
+                    && fired.contains(&activation.token())
+            })
+            .map(|activation| activation.token())
+            .collect::<HashSet<_>>();
+
+        for activation in activations {
+            if transient.contains(&activation.token()) {
+                trace!("Dropping transient activation {activation:?}");
+                continue;
+            }
+
+            let Some(production) = self.productions.get(&activation.production()) else {
+                continue;
+            };
+            let Node::Production(ref p_node) = *production.borrow() else {
+                unreachable!("Production map contains a non production node")
+            };
+            p_node.notify(activation);
+        }
+
+        true
+    }
+
+    /// Discards the changes of the current transaction, leaving the Rete in the state it was in
+    /// when the transaction began.
+    ///
+    /// Returns `false` if no transaction was in progress.
+    pub fn rollback_transaction(&mut self) -> bool {
+        let Some(transaction) = self.transaction.take() else {
+            return false;
+        };
+
+        trace!(
+            "------------\nRolling back transaction with {} operations\n------------",
+            transaction.operations.len()
+        );
+
+        for key in transaction.expired {
+            *self.expirations.entry(key).or_default() += 1;
+        }
+
+        true
+    }
+
+    /// Records the addition of the WME in the current transaction and returns the ID it will have
+    /// once committed.
+    pub(in crate::rete) fn buffer_add_wme(&mut self, wme: Wme) -> usize {
+        let transaction = self
+            .transaction
+            .as_mut()
+            .expect("No transaction in progress");
+
+        let mut id = wme.id;
+
+        if self.config.set_semantics {
+            let entry = transaction
+                .set
+                .entry(wme.fields.clone())
+                .or_insert_with(|| self.wme_set.get(&wme.fields).copied());
+            let (existing, count) = entry.get_or_insert((id, 0));
+            *count += 1;
+            id = *existing;
+        }
+
+        if id == wme.id {
+            transaction.added.insert(id, wme.fields.clone());
+        }
+
+        trace!("Buffered adding WME {wme:?}");
+        transaction.operations.push(Operation::AddWme(wme));
+
+        id
+    }
+
+    /// Records the removal of the WME in the current transaction.
+    pub(in crate::rete) fn buffer_remove_wme(&mut self, id: usize) {
+        let transaction = self
+            .transaction
+            .as_mut()
+            .expect("No transaction in progress");
+
+        if self.config.set_semantics {
+            let fields = match self.working_memory.get(&id) {
+                Some(wme) => Some(wme.borrow().fields.clone()),
+                None => transaction.added.get(&id).cloned(),
+            };
+
+            if let Some(fields) = fields {
+                let entry = transaction
+                    .set
+                    .entry(fields.clone())
+                    .or_insert_with(|| self.wme_set.get(&fields).copied());
+
+                if let Some((_, count)) = entry {
+                    *count -= 1;
+                    if *count == 0 {
+                        *entry = None;
+                    }
+                }
+            }
+        }
+
+        trace!("Buffered removing WME {id}");
+        transaction.operations.push(Operation::RemoveWme(id));
+    }
+}
+
+/// Makes the production nodes buffer their activations in `deferred`, or send them again if `None`.
+pub(in crate::rete) fn set_deferred(
+    productions: &[ReteNode],
+    deferred: Option<&RcCell<Vec<Activation>>>,
+) {
+    for production in productions {
+        if let Node::Production(ref mut p_node) = *production.borrow_mut() {
+            p_node.deferred = deferred.cloned();
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use super::{
+    explain::NodeRef,
+    item::{Token, Wme},
+    node::{AlphaMemoryNode, AlphaTestNode, Node},
+    RcCell, Rete, ReteNode, ReteToken,
+};
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+/// An invariant of the network that does not hold, found by [Rete::validate].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Violation {
+    /// The token's node does not hold it.
+    TokenNotInNode { token: usize, node: NodeRef },
+
+    /// The node holds a token belonging to a different node.
+    TokenNodeMismatch { token: usize, node: NodeRef },
+
+    /// The node holds a token that cannot be reached from the dummy top token.
+    UnreachableToken { token: usize, node: NodeRef },
+
+    /// The token is a child of a token other than its parent.
+    TokenParentMismatch { token: usize, parent: usize },
+
+    /// The token's WME does not hold it in its `tokens`.
+    TokenNotInWme { token: usize, wme: usize },
+
+    /// The WME holds a token that does not point to it or is no longer in the network.
+    StaleWmeToken { wme: usize, token: usize },
+
+    /// The WME is referenced by the network but is not the one in working memory.
+    WmeNotInWorkingMemory { wme: usize },
+
+    /// A negative join result of the token is not registered on its WME.
+    JoinResultNotInWme { token: usize, wme: usize },
+
+    /// The WME holds a negative join result its owner does not hold.
+    StaleJoinResult { wme: usize, token: usize },
+
+    /// The NCC partner result is owned by a token that does not hold it.
+    NccResultOwnerMismatch { token: usize, owner: usize },
+
+    /// The NCC partner holds results that were never handed to their owners.
+    PendingNccResults { node: NodeRef },
+
+    /// The NCC node has no partner or its partner belongs to another node.
+    NccPartnerMismatch { node: NodeRef },
+
+    /// The node's `right_linked` flag disagrees with its presence in the alpha memory's successors.
+    RightLinkMismatch {
+        node: NodeRef,
+        alpha_memory: usize,
+        right_linked: bool,
+    },
+
+    /// The node appears more than once in the alpha memory's successors.
+    DuplicateSuccessor { node: NodeRef, alpha_memory: usize },
+
+    /// The join's `left_linked` flag disagrees with its presence in the parent's children.
+    LeftLinkMismatch {
+        node: NodeRef,
+        parent: NodeRef,
+        left_linked: bool,
+    },
+
+    /// The node is a child of a node other than its parent.
+    ChildParentMismatch { node: NodeRef, parent: NodeRef },
+
+    /// The alpha memory holds the WME but it is missing from the memory's `wme_alphas` entry.
+    UnregisteredAlphaItem { wme: usize, alpha_memory: usize },
+
+    /// The WME's `wme_alphas` entry lists a memory that does not hold it.
+    MissingAlphaItem { wme: usize, alpha_memory: usize },
+
+    /// The field index disagrees with the WME's fields.
+    IndexMismatch { wme: usize },
+}
+
+type TokenSet = HashSet<*const RefCell<Token>>;
+
+impl Rete {
+    /// Walks the whole network and checks the invariants between the tokens, nodes, alpha
+    /// memories and working memory, returning every violation found.
+    ///
+    /// This visits every node, token and WME, so it is meant for tests and debugging rather
+    /// than being called after every change in production.
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = vec![];
+
+        let tokens = self.validate_tokens(&mut violations);
+        self.validate_nodes(&tokens, &mut violations);
+        self.validate_working_memory(&tokens, &mut violations);
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Panics listing the violations if the network is not [valid][Rete::validate].
+    #[track_caller]
+    pub fn assert_valid(&self) {
+        if let Err(violations) = self.validate() {
+            let violations = violations
+                .iter()
+                .map(|violation| format!("  {violation}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            panic!("Rete invariants violated:\n{violations}");
+        }
+    }
+
+    /// Checks every token reachable from the dummy top token and returns them.
+    fn validate_tokens(&self, violations: &mut Vec<Violation>) -> TokenSet {
+        let mut visited = TokenSet::new();
+        let mut stack = vec![Rc::clone(&self.dummy_top_token)];
+
+        while let Some(token) = stack.pop() {
+            if !visited.insert(Rc::as_ptr(&token)) {
+                continue;
+            }
+
+            let tok = token.borrow();
+            let id = tok.id();
+
+            for child in tok.children() {
+                if !child
+                    .borrow()
+                    .parent()
+                    .is_some_and(|parent| Rc::ptr_eq(parent, &token))
+                {
+                    violations.push(Violation::TokenParentMismatch {
+                        token: child.borrow().id(),
+                        parent: id,
+                    });
+                }
+                stack.push(Rc::clone(child));
+            }
+
+            let node = tok.node().borrow();
+            match (&*tok, &*node) {
+                (Token::NCC { owner, .. }, Node::NccPartner(partner)) => {
+                    let delivered = owner.as_ref().is_some_and(|owner| match &*owner.borrow() {
+                        Token::NCC { ncc_results, .. } => contains(ncc_results, &token),
+                        _ => false,
+                    });
+                    if !delivered && !contains(&partner.new_results, &token) {
+                        violations.push(Violation::NccResultOwnerMismatch {
+                            token: id,
+                            owner: owner.as_ref().map_or(id, |owner| owner.borrow().id()),
+                        });
+                    }
+                }
+                (_, node) => {
+                    if !contains(node.tokens(), &token) {
+                        violations.push(Violation::TokenNotInNode {
+                            token: id,
+                            node: NodeRef::of(node),
+                        });
+                    }
+                }
+            }
+
+            if let Some(wme) = tok.wme() {
+                let w = wme.borrow();
+                if !contains(&w.tokens, &token) {
+                    violations.push(Violation::TokenNotInWme {
+                        token: id,
+                        wme: w.id,
+                    });
+                }
+                self.check_in_working_memory(wme, violations);
+            }
+
+            if let Token::Negative { join_results, .. } = &*tok {
+                for result in join_results {
+                    let r = result.borrow();
+                    let registered = r
+                        .wme
+                        .borrow()
+                        .negative_join_results
+                        .iter()
+                        .any(|res| Rc::ptr_eq(res, result));
+                    if !registered || !Rc::ptr_eq(&r.owner, &token) {
+                        violations.push(Violation::JoinResultNotInWme {
+                            token: id,
+                            wme: r.wme.borrow().id,
+                        });
+                    }
+                    self.check_in_working_memory(&r.wme, violations);
+                }
+            }
+
+            if let Token::NCC { ncc_results, .. } = &*tok {
+                for result in ncc_results {
+                    let owned = match &*result.borrow() {
+                        Token::NCC { owner, .. } => owner
+                            .as_ref()
+                            .is_some_and(|owner| Rc::ptr_eq(owner, &token)),
+                        _ => false,
+                    };
+                    if !owned {
+                        violations.push(Violation::NccResultOwnerMismatch {
+                            token: result.borrow().id(),
+                            owner: id,
+                        });
+                    }
+                }
+            }
+        }
+
+        visited
+    }
+
+    /// Checks every node reachable from the dummy top node, including left unlinked ones.
+    fn validate_nodes(&self, tokens: &TokenSet, violations: &mut Vec<Violation>) {
+        let mut visited = HashSet::new();
+        let mut stack = vec![Rc::clone(&self.dummy_top_node)];
+        stack.extend(self.productions.values().cloned());
+        stack.extend(self.queries.values().cloned());
+
+        while let Some(node) = stack.pop() {
+            if !visited.insert(Rc::as_ptr(&node)) {
+                continue;
+            }
+
+            let n = node.borrow();
+            let id = NodeRef::of(&n);
+
+            for token in n.tokens() {
+                if !Rc::ptr_eq(token.borrow().node(), &node) {
+                    violations.push(Violation::TokenNodeMismatch {
+                        token: token.borrow().id(),
+                        node: id,
+                    });
+                }
+                if !tokens.contains(&Rc::as_ptr(token)) {
+                    violations.push(Violation::UnreachableToken {
+                        token: token.borrow().id(),
+                        node: id,
+                    });
+                }
+            }
+
+            for child in n.all_children() {
+                if !child
+                    .borrow()
+                    .parent()
+                    .is_some_and(|parent| Rc::ptr_eq(&parent, &node))
+                {
+                    violations.push(Violation::ChildParentMismatch {
+                        node: NodeRef::of(&child.borrow()),
+                        parent: id,
+                    });
+                }
+                stack.push(Rc::clone(child));
+            }
+
+            // Productions and partners are reachable from the top, but their parents must be too
+            if let Some(parent) = n.parent() {
+                stack.push(parent);
+            }
+
+            match &*n {
+                Node::Join(join) => {
+                    check_right_link(&node, &join.alpha_mem, join.right_linked, violations);
+
+                    let parent = join.parent.borrow();
+                    let in_children = parent
+                        .children()
+                        .iter()
+                        .any(|child| Rc::ptr_eq(child, &node));
+                    if in_children != join.left_linked {
+                        violations.push(Violation::LeftLinkMismatch {
+                            node: id,
+                            parent: NodeRef::of(&parent),
+                            left_linked: join.left_linked,
+                        });
+                    }
+                }
+                Node::Negative(negative) => {
+                    check_right_link(
+                        &node,
+                        &negative.alpha_mem,
+                        negative.right_linked,
+                        violations,
+                    );
+                }
+                Node::Ncc(ncc) => {
+                    let paired = ncc.partner.as_ref().is_some_and(|partner| {
+                        stack.push(Rc::clone(partner));
+                        match &*partner.borrow() {
+                            Node::NccPartner(partner) => Rc::ptr_eq(&partner.ncc_node, &node),
+                            _ => false,
+                        }
+                    });
+                    if !paired {
+                        violations.push(Violation::NccPartnerMismatch { node: id });
+                    }
+                }
+                Node::NccPartner(partner) => {
+                    if !partner.new_results.is_empty() {
+                        violations.push(Violation::PendingNccResults { node: id });
+                    }
+
+                    let paired = match &*partner.ncc_node.borrow() {
+                        Node::Ncc(ncc) => ncc
+                            .partner
+                            .as_ref()
+                            .is_some_and(|partner| Rc::ptr_eq(partner, &node)),
+                        _ => false,
+                    };
+                    if !paired {
+                        violations.push(Violation::NccPartnerMismatch {
+                            node: NodeRef::of(&partner.ncc_node.borrow()),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Checks the WMEs against their tokens, join results, alpha memories and the field index.
+    fn validate_working_memory(&self, tokens: &TokenSet, violations: &mut Vec<Violation>) {
+        for wme in self.working_memory.values() {
+            let w = wme.borrow();
+
+            for token in w.tokens.iter() {
+                let points_back = token
+                    .borrow()
+                    .wme()
+                    .is_some_and(|token_wme| Rc::ptr_eq(token_wme, wme));
+                if !points_back || !tokens.contains(&Rc::as_ptr(token)) {
+                    violations.push(Violation::StaleWmeToken {
+                        wme: w.id,
+                        token: token.borrow().id(),
+                    });
+                }
+            }
+
+            for result in w.negative_join_results.iter() {
+                let r = result.borrow();
+                let held = match &*r.owner.borrow() {
+                    Token::Negative { join_results, .. } => {
+                        join_results.iter().any(|res| Rc::ptr_eq(res, result))
+                    }
+                    _ => false,
+                };
+                if !held || !Rc::ptr_eq(&r.wme, wme) || !tokens.contains(&Rc::as_ptr(&r.owner)) {
+                    violations.push(Violation::StaleJoinResult {
+                        wme: w.id,
+                        token: r.owner.borrow().id(),
+                    });
+                }
+            }
+
+            for memory in self.wme_alphas.get(&w.id).into_iter().flatten() {
+                let holds = memory
+                    .borrow()
+                    .items
+                    .iter()
+                    .any(|item| Rc::ptr_eq(&item.borrow().wme, wme));
+                if !holds {
+                    violations.push(Violation::MissingAlphaItem {
+                        wme: w.id,
+                        alpha_memory: memory.borrow().id,
+                    });
+                }
+            }
+
+            let indexed = w.fields.iter().enumerate().all(|(i, field)| {
+                self.wme_index
+                    .get(&(i, *field))
+                    .is_some_and(|ids| ids.contains(&w.id))
+            });
+            if !indexed {
+                violations.push(Violation::IndexMismatch { wme: w.id });
+            }
+        }
+
+        for ((i, field), ids) in self.wme_index.iter() {
+            for id in ids {
+                let matches = self
+                    .working_memory
+                    .get(id)
+                    .is_some_and(|wme| wme.borrow().fields.get(*i) == Some(field));
+                if !matches {
+                    violations.push(Violation::IndexMismatch { wme: *id });
+                }
+            }
+        }
+
+        for memory in self.alpha_memories() {
+            let m = memory.borrow();
+            for item in m.items.iter() {
+                let item = item.borrow();
+                let wme = item.wme.borrow();
+
+                self.check_in_working_memory(&item.wme, violations);
+
+                let registered = self
+                    .wme_alphas
+                    .get(&wme.id)
+                    .is_some_and(|memories| memories.iter().any(|mem| Rc::ptr_eq(mem, &memory)));
+                if !registered {
+                    violations.push(Violation::UnregisteredAlphaItem {
+                        wme: wme.id,
+                        alpha_memory: m.id,
+                    });
+                }
+            }
+        }
+    }
+
+    /// All alpha memories of the constant tests and the alpha network.
+    fn alpha_memories(&self) -> Vec<RcCell<AlphaMemoryNode>> {
+        fn collect(nodes: &[AlphaTestNode], acc: &mut Vec<RcCell<AlphaMemoryNode>>) {
+            for node in nodes {
+                acc.extend(node.memory.clone());
+                collect(&node.children, acc);
+            }
+        }
+
+        let mut memories = self.constant_tests.values().cloned().collect::<Vec<_>>();
+        for nodes in self.alpha_network.values() {
+            collect(nodes, &mut memories);
+        }
+
+        let mut seen = HashSet::new();
+        memories.retain(|memory| seen.insert(Rc::as_ptr(memory)));
+        memories
+    }
+
+    fn check_in_working_memory(&self, wme: &RcCell<Wme>, violations: &mut Vec<Violation>) {
+        let id = wme.borrow().id;
+        let present = self
+            .working_memory
+            .get(&id)
+            .is_some_and(|current| Rc::ptr_eq(current, wme));
+        if !present {
+            let violation = Violation::WmeNotInWorkingMemory { wme: id };
+            if !violations.contains(&violation) {
+                violations.push(violation);
+            }
+        }
+    }
+}
+
+fn check_right_link(
+    node: &ReteNode,
+    alpha_memory: &RcCell<AlphaMemoryNode>,
+    right_linked: bool,
+    violations: &mut Vec<Violation>,
+) {
+    let memory = alpha_memory.borrow();
+    let occurrences = memory
+        .successors
+        .iter()
+        .filter(|successor| Rc::ptr_eq(successor, node))
+        .count();
+
+    let id = NodeRef::of(&node.borrow());
+    if occurrences > 1 {
+        violations.push(Violation::DuplicateSuccessor {
+            node: id,
+            alpha_memory: memory.id,
+        });
+    }
+    if (occurrences > 0) != right_linked {
+        violations.push(Violation::RightLinkMismatch {
+            node: id,
+            alpha_memory: memory.id,
+            right_linked,
+        });
+    }
+}
+
+fn contains(tokens: &[ReteToken], token: &ReteToken) -> bool {
+    tokens.iter().any(|tok| Rc::ptr_eq(tok, token))
+}
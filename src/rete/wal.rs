@@ -0,0 +1,227 @@
+use super::{
+    id::{reserve_prod_id, reserve_wme_id},
+    item::{Activation, Condition, Predicate, Production, Wme},
+    snapshot::{resolve_predicates, RestoreError},
+    time::Clock,
+    Rete, ReteConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::mpsc::Sender,
+};
+
+/// A single call made to a [Rete], as recorded in a [FactLog].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogEntry {
+    AddWme {
+        id: usize,
+        fields: Vec<usize>,
+        timestamp: Option<u64>,
+        lifetime: Option<u64>,
+    },
+    RemoveWme {
+        id: usize,
+    },
+    AddProduction {
+        id: usize,
+        name: Option<String>,
+        conditions: Vec<Condition>,
+    },
+    RemoveProduction {
+        id: usize,
+    },
+}
+
+/// An append-only log of the changes made to a Rete, one JSON encoded [LogEntry] per line.
+///
+/// Entries are written and synced to disk before the change is applied to the network, so the
+/// log contains every change the Rete has seen, even if the process or the machine crashes.
+///
+/// Once writing an entry fails, the error is kept and no further entries are written, since
+/// they would not replay correctly without the missing one. See [FactLog::error].
+#[derive(Debug)]
+pub struct FactLog {
+    path: PathBuf,
+    file: File,
+    error: Option<std::io::Error>,
+}
+
+impl FactLog {
+    /// Opens the log at the given path, creating it if it does not exist. New entries
+    /// are appended to the existing ones.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            error: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The error which stopped the log from recording changes, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn append(&mut self, entry: &LogEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    /// Reads all entries of the log at the given path.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<LogEntry>, ReplayError> {
+        let file = File::open(path).map_err(ReplayError::Io)?;
+
+        let mut entries = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(ReplayError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line).map_err(|e| ReplayError::Parse {
+                line: i + 1,
+                message: e.to_string(),
+            })?;
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+
+    /// A line of the log is not a valid entry. Lines are 1-based.
+    Parse {
+        line: usize,
+        message: String,
+    },
+
+    Restore(RestoreError),
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Rete {
+    /// Attaches a log recording every subsequent change to this Rete.
+    pub fn set_log(&mut self, log: FactLog) {
+        self.log = Some(log);
+    }
+
+    /// Detaches the log from this Rete, if any.
+    pub fn take_log(&mut self) -> Option<FactLog> {
+        self.log.take()
+    }
+
+    /// The error which stopped the attached log from recording changes, if any.
+    /// The Rete itself keeps working when writing to its log fails.
+    pub fn log_error(&self) -> Option<&std::io::Error> {
+        self.log.as_ref().and_then(FactLog::error)
+    }
+
+    pub(in crate::rete) fn write_log(&mut self, entry: LogEntry) {
+        let Some(ref mut log) = self.log else {
+            return;
+        };
+
+        if log.error.is_some() {
+            return;
+        }
+
+        if let Err(e) = log.append(&entry) {
+            trace!("Failed writing {entry:?} to {}: {e}", log.path().display());
+            log.error = Some(e);
+        }
+    }
+
+    /// Reconstructs a Rete by applying the entries of the log at the given path in order,
+    /// keeping the original IDs of WMEs and productions.
+    ///
+    /// Like when [restoring][Rete::restore] a snapshot, the predicates used by test conditions are
+    /// resolved by their ID and all productions send their activations through `activation_tx`.
+    /// The returned Rete has no log attached and uses `clock`.
+    ///
+    /// WMEs do not expire while replaying, since the log already records their retraction at
+    /// the time it happened. The WMEs which expired since are retracted once all entries are
+    /// applied.
+    pub fn replay(
+        path: impl AsRef<Path>,
+        config: ReteConfig,
+        clock: Rc<dyn Clock>,
+        activation_tx: Sender<Activation>,
+        predicates: &[Predicate],
+    ) -> Result<Self, ReplayError> {
+        let mut rete = Rete::new(config);
+        rete.set_clock(clock);
+        rete.apply_log_entries(FactLog::read(path)?, activation_tx, predicates)?;
+        Ok(rete)
+    }
+
+    /// Applies the log entries to this Rete in order. Like [Rete::replay], WMEs which expired
+    /// are retracted after applying all entries.
+    pub fn apply_log_entries(
+        &mut self,
+        entries: impl IntoIterator<Item = LogEntry>,
+        activation_tx: Sender<Activation>,
+        predicates: &[Predicate],
+    ) -> Result<(), ReplayError> {
+        for entry in entries {
+            match entry {
+                LogEntry::AddWme {
+                    id,
+                    fields,
+                    timestamp,
+                    lifetime,
+                } => {
+                    reserve_wme_id(id);
+                    let mut wme = Wme::with_id(id, fields);
+                    wme.timestamp = timestamp;
+                    wme.lifetime = lifetime;
+                    self.insert_wme(wme);
+                }
+                LogEntry::RemoveWme { id } => self.remove_wme(id),
+                LogEntry::AddProduction {
+                    id,
+                    name,
+                    mut conditions,
+                } => {
+                    resolve_predicates(&mut conditions, predicates).map_err(|predicate| {
+                        ReplayError::Restore(RestoreError::UnresolvedPredicate {
+                            production: id,
+                            predicate,
+                        })
+                    })?;
+                    reserve_prod_id(id);
+                    let mut production =
+                        Production::with_id(id, &conditions, activation_tx.clone());
+                    production.name = name.map(Into::into);
+                    self.add_production(production).map_err(|error| {
+                        ReplayError::Restore(RestoreError::InvalidConditions {
+                            production: id,
+                            error,
+                        })
+                    })?;
+                }
+                LogEntry::RemoveProduction { id } => {
+                    self.remove_production(id);
+                }
+            }
+        }
+
+        self.expire();
+        Ok(())
+    }
+}
@@ -1,13 +1,18 @@
-use std::sync::atomic::AtomicUsize;
+use std::rc::Rc;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use threte::engine::{Engine, Rule};
+use threte::engine::{Engine, Rule, RuleError, RuleMetadata};
+use threte::rete::explain::{Blocking, ConditionExplanation};
 use threte::rete::item::Wme;
 use threte::{
     engine::IntoWmes,
     rete::{
         id::reset,
-        item::{Condition, ConditionTest, Production},
-        Rete,
+        item::{
+            validate_conditions, Activation, AlphaTest, Condition, ConditionTest, ConstantTest,
+            Predicate, Production, Token,
+        },
+        validate::Violation,
+        Rete, ReteConfig,
     },
 };
 
@@ -22,6 +27,7 @@ const ON: usize = 10;
 const COLOR: usize = 11;
 const LEFT_OF: usize = 12;
 const ID: usize = 13;
+const SIZE: usize = 14;
 
 const RED: usize = 20;
 const MAIZE: usize = 21;
@@ -51,15 +57,32 @@ const C_COLOR: ConditionTest = ConditionTest::Constant(COLOR);
 const C_RED: ConditionTest = ConditionTest::Constant(RED);
 const C_MAIZE: ConditionTest = ConditionTest::Constant(MAIZE);
 const C_BLUE: ConditionTest = ConditionTest::Constant(BLUE);
-const C_ID: ConditionTest = ConditionTest::Constant(ID);
 const C_TABLE: ConditionTest = ConditionTest::Constant(TABLE);
+const C_SIZE: ConditionTest = ConditionTest::Constant(SIZE);
 
-const C1: Condition = Condition::new_positive([V_X, C_ON, V_Y]);
-const C2: Condition = Condition::new_positive([V_Y, C_LEFT_OF, V_Z]);
-const C3: Condition = Condition::new_positive([V_Z, C_COLOR, C_RED]);
-const C4: Condition = Condition::new_positive([V_A, C_COLOR, C_MAIZE]);
-const C5: Condition = Condition::new_positive([V_B, C_COLOR, C_BLUE]);
-const C6: Condition = Condition::new_positive([V_Z, C_ON, C_TABLE]);
+fn c1() -> Condition {
+    Condition::new_positive([V_X, C_ON, V_Y])
+}
+
+fn c2() -> Condition {
+    Condition::new_positive([V_Y, C_LEFT_OF, V_Z])
+}
+
+fn c3() -> Condition {
+    Condition::new_positive([V_Z, C_COLOR, C_RED])
+}
+
+fn c4() -> Condition {
+    Condition::new_positive([V_A, C_COLOR, C_MAIZE])
+}
+
+fn c5() -> Condition {
+    Condition::new_positive([V_B, C_COLOR, C_BLUE])
+}
+
+fn c6() -> Condition {
+    Condition::new_positive([V_Z, C_ON, C_TABLE])
+}
 
 #[derive(Debug, Hash)]
 struct Block {
@@ -76,8 +99,6 @@ struct Block {
     color: Color, // WME attribute 11
 }
 
-static IDENTIFIER: AtomicUsize = AtomicUsize::new(0);
-
 impl IntoWmes for Block {
     fn id(&self) -> usize {
         self.id
@@ -142,27 +163,29 @@ fn engine() {
     };
 
     let rule1 = Rule {
-        conditions: vec![C1, C2, C3],
+        conditions: vec![c1(), c2(), c3()],
         production: Box::new(|e, b| {
             println!("Rule 1 works!");
             let el = e.elements.get(&b[0]);
             dbg!(el);
         }),
         bindings: vec![1, 2],
+        metadata: RuleMetadata::default(),
     };
 
     let rule2 = Rule {
-        conditions: vec![C1, C2, C6],
+        conditions: vec![c1(), c2(), c6()],
         production: Box::new(|_e, _| {
             println!("Rule 2 works!");
         }),
         bindings: vec![],
+        metadata: RuleMetadata::default(),
     };
 
     let mut engine = Engine::default();
 
-    engine.add_rule(rule1);
-    engine.add_rule(rule2);
+    engine.add_rule(rule1).unwrap();
+    engine.add_rule(rule2).unwrap();
 
     engine.add_element(block1);
     engine.add_element(block2);
@@ -171,11 +194,11 @@ fn engine() {
     engine.activate_productions();
 }
 
-fn productions(tx: Sender<usize>) -> Vec<Production> {
+fn productions(tx: Sender<Activation>) -> Vec<Production> {
     vec![
-        Production::new(&[C1, C2, C3], tx.clone()),
-        Production::new(&[C1, C2, C4, C5], tx.clone()),
-        Production::new(&[C1, C2, C3, C4], tx),
+        Production::new(&[c1(), c2(), c3()], tx.clone()),
+        Production::new(&[c1(), c2(), c4(), c5()], tx.clone()),
+        Production::new(&[c1(), c2(), c3(), c4()], tx),
     ]
 }
 
@@ -188,7 +211,7 @@ fn add_productions() {
     let mut rete = Rete::default();
     let (tx, _rx) = channel();
     for p in productions(tx) {
-        rete.add_production(p);
+        rete.add_production(p).unwrap();
     }
 
     assert_eq!(rete.constant_tests.len(), 5);
@@ -201,7 +224,7 @@ fn add_productions_and_wmes() {
     let mut rete = Rete::default();
     let (tx, _rx) = channel();
     for p in productions(tx) {
-        rete.add_production(p);
+        rete.add_production(p).unwrap();
     }
 
     for wme in wmes() {
@@ -214,8 +237,8 @@ fn add_productions_and_wmes() {
 #[test]
 fn add_wme_then_prod() {
     let (tx, rx) = channel();
-    let production_one = Production::new(&[C1, C2, C3], tx.clone());
-    let production_two = Production::new(&[C1, C2, C4, C5], tx);
+    let production_one = Production::new(&[c1(), c2(), c3()], tx.clone());
+    let production_two = Production::new(&[c1(), c2(), c4(), c5()], tx);
 
     let mut rete = Rete::default();
 
@@ -232,11 +255,11 @@ fn add_wme_then_prod() {
 
     // And here, 2 in total
 
-    rete.add_production(production_one);
-    rete.add_production(production_two);
+    rete.add_production(production_one).unwrap();
+    rete.add_production(production_two).unwrap();
 
     // TODO: I have no clue why this is 3
-    assert_production_set_size(rx, 3);
+    assert_production_set_size(&rx, 3);
 
     reset()
 }
@@ -244,16 +267,16 @@ fn add_wme_then_prod() {
 #[test]
 fn add_productions_and_wmes_then_remove() {
     let (tx, rx) = channel();
-    let production_one = Production::new(&[C1, C2, C3], tx.clone());
-    let production_two = Production::new(&[C1, C2, C4, C5], tx);
+    let production_one = Production::new(&[c1(), c2(), c3()], tx.clone());
+    let production_two = Production::new(&[c1(), c2(), c4(), c5()], tx);
 
     let mut rete = Rete::default();
 
-    let prod_id1 = rete.add_production(production_one);
+    let prod_id1 = rete.add_production(production_one).unwrap();
     rete.print_to_file("add_productions_and_wmes_then_remove/10_first_prod.txt")
         .unwrap();
 
-    let prod_id2 = rete.add_production(production_two);
+    let prod_id2 = rete.add_production(production_two).unwrap();
     rete.print_to_file("add_productions_and_wmes_then_remove/11_second_prod.txt")
         .unwrap();
 
@@ -304,15 +327,18 @@ fn add_productions_and_wmes_then_remove() {
     assert!(rete.dummy_top_node.borrow().children().is_empty());
 
     // TODO Also no idea why 3, fml
-    assert_production_set_size(rx, 3);
+    assert_production_set_size(&rx, 3);
 
     reset()
 }
 
-fn assert_production_set_size(rx: Receiver<usize>, size: usize) {
+/// Counts the fired activations in the channel, ignoring retractions.
+fn assert_production_set_size(rx: &Receiver<Activation>, size: usize) {
     let mut i = 0;
-    while rx.try_recv().is_ok() {
-        i += 1;
+    while let Ok(activation) = rx.try_recv() {
+        if let Activation::Fired { .. } = activation {
+            i += 1;
+        }
     }
 
     assert_eq!(i, size);
@@ -331,6 +357,28 @@ fn simple_wme_removal() {
     reset();
 }
 
+#[test]
+fn dropped_activation_receiver() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let production = rete
+        .add_production(Production::new(&[c1(), c2(), c3()], tx))
+        .unwrap();
+    drop(rx);
+
+    // Matches are still built and retracted without anyone receiving the activations
+    for wme in [W1, W5, W7] {
+        rete.add_wme(Wme::new(wme));
+    }
+    let red = rete.add_wme(Wme::new(W9));
+    assert_eq!(rete.productions[&production].borrow().tokens().len(), 1);
+
+    rete.remove_wme(red);
+    assert!(rete.productions[&production].borrow().tokens().is_empty());
+
+    reset();
+}
+
 #[test]
 fn wme_removal_with_tokens() {
     const X: usize = 0;
@@ -343,9 +391,9 @@ fn wme_removal_with_tokens() {
     let mut rete = Rete::default();
 
     let (tx, rx) = channel();
-    let production = Production::new(&[C1, C2, C3], tx);
+    let production = Production::new(&[c1(), c2(), c3()], tx);
 
-    rete.add_production(production);
+    rete.add_production(production).unwrap();
 
     let id1 = rete.add_wme(Wme::new(W1));
     let id2 = rete.add_wme(Wme::new(W2));
@@ -360,7 +408,7 @@ fn wme_removal_with_tokens() {
 
     rete.remove_wme(id1);
 
-    assert_production_set_size(rx, 0);
+    assert_production_set_size(&rx, 0);
 
     assert!(rete.working_memory.is_empty());
     assert_eq!(rete.dummy_top_token.borrow().children().len(), 1);
@@ -376,9 +424,9 @@ fn simple_production_removal() {
     let mut rete = Rete::default();
 
     let (tx, _rx) = channel();
-    let production = Production::new(&[C1, C2, C3], tx);
+    let production = Production::new(&[c1(), c2(), c3()], tx);
 
-    let id = rete.add_production(production);
+    let id = rete.add_production(production).unwrap();
     let removed = rete.remove_production(id);
 
     assert!(removed);
@@ -400,9 +448,9 @@ fn production_removal_with_tokens() {
     let mut rete = Rete::default();
 
     let (tx, rx) = channel();
-    let production = Production::new(&[C1, C2, C3], tx);
+    let production = Production::new(&[c1(), c2(), c3()], tx);
 
-    let id = rete.add_production(production);
+    let id = rete.add_production(production).unwrap();
 
     let id1 = rete.add_wme(Wme::new(W1));
     let id2 = rete.add_wme(Wme::new(W2));
@@ -428,7 +476,7 @@ fn production_removal_with_tokens() {
     assert!(rete.working_memory.is_empty());
     assert!(rete.dummy_top_token.borrow().children().is_empty());
     assert!(rete.productions.is_empty());
-    assert_production_set_size(rx, 0);
+    assert_production_set_size(&rx, 0);
 
     reset();
 }
@@ -439,10 +487,18 @@ fn production_removal_with_similar_productions() {
     const Y: usize = 1;
     const Z: usize = 2;
 
-    const C1: Condition = Condition::new_positive([V_X, C_ON, V_Y]);
-    const C2: Condition = Condition::new_positive([V_Y, C_LEFT_OF, V_Z]);
-    const C3: Condition = Condition::new_positive([V_Z, C_COLOR, C_RED]);
-    const C4: Condition = Condition::new_positive([V_Z, C_COLOR, C_BLUE]);
+    fn c1() -> Condition {
+        Condition::new_positive([V_X, C_ON, V_Y])
+    }
+    fn c2() -> Condition {
+        Condition::new_positive([V_Y, C_LEFT_OF, V_Z])
+    }
+    fn c3() -> Condition {
+        Condition::new_positive([V_Z, C_COLOR, C_RED])
+    }
+    fn c4() -> Condition {
+        Condition::new_positive([V_Z, C_COLOR, C_BLUE])
+    }
 
     const W1: [usize; 3] = [X, ON, Y];
     const W2: [usize; 3] = [Y, LEFT_OF, Z];
@@ -450,11 +506,11 @@ fn production_removal_with_similar_productions() {
     let mut rete = Rete::default();
 
     let (tx, rx) = channel();
-    let production_1 = Production::new(&[C1, C2, C3], tx.clone());
-    let production_2 = Production::new(&[C1, C2, C4], tx);
+    let production_1 = Production::new(&[c1(), c2(), c3()], tx.clone());
+    let production_2 = Production::new(&[c1(), c2(), c4()], tx);
 
-    let prod_id1 = rete.add_production(production_1);
-    let prod_id2 = rete.add_production(production_2);
+    let prod_id1 = rete.add_production(production_1).unwrap();
+    let prod_id2 = rete.add_production(production_2).unwrap();
 
     let id1 = rete.add_wme(Wme::new(W1));
     let id2 = rete.add_wme(Wme::new(W2));
@@ -487,7 +543,7 @@ fn production_removal_with_similar_productions() {
     assert!(rete.working_memory.is_empty());
     assert!(rete.dummy_top_token.borrow().children().is_empty());
     assert!(rete.productions.is_empty());
-    assert_production_set_size(rx, 0);
+    assert_production_set_size(&rx, 0);
 
     reset();
 }
@@ -498,9 +554,15 @@ fn add_remove_negative_node() {
     const Y: usize = 1;
     const Z: usize = 2;
 
-    const C1: Condition = Condition::new_positive([V_X, C_ON, V_Y]);
-    const C2: Condition = Condition::new_positive([V_Y, C_LEFT_OF, V_Z]);
-    const C3: Condition = Condition::new_negative([V_Z, C_COLOR, C_RED]);
+    fn c1() -> Condition {
+        Condition::new_positive([V_X, C_ON, V_Y])
+    }
+    fn c2() -> Condition {
+        Condition::new_positive([V_Y, C_LEFT_OF, V_Z])
+    }
+    fn c3() -> Condition {
+        Condition::new_negative([V_Z, C_COLOR, C_RED])
+    }
 
     const W1: [usize; 3] = [X, ON, Y];
     const W2: [usize; 3] = [Z, COLOR, BLUE];
@@ -509,9 +571,9 @@ fn add_remove_negative_node() {
     let mut rete = Rete::default();
 
     let (tx, rx) = channel();
-    let production = Production::new(&[C1, C2, C3], tx);
+    let production = Production::new(&[c1(), c2(), c3()], tx);
 
-    let prod_id1 = rete.add_production(production);
+    let prod_id1 = rete.add_production(production).unwrap();
     assert_eq!(rete.productions.len(), 1);
 
     rete.add_wme(Wme::new(W1));
@@ -529,7 +591,225 @@ fn add_remove_negative_node() {
     assert!(rete.dummy_top_token.borrow().children().is_empty());
     assert!(rete.productions.is_empty());
 
-    assert_production_set_size(rx, 1);
+    assert_production_set_size(&rx, 1);
+
+    reset();
+}
+
+#[test]
+fn negative_blocker_removed() {
+    let not_red = Condition::new_negative([V_Y, C_COLOR, C_RED]);
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1(), not_red], tx))
+        .unwrap();
+
+    // The blocking WME is already present when the token reaches the negative node
+    let red = rete.add_wme(Wme::new([B2, COLOR, RED]));
+    let w1 = rete.add_wme(Wme::new(W1));
+    assert_production_set_size(&rx, 0);
+
+    // Removing the blocker unblocks the token, and the WME that created it holds no result
+    rete.remove_wme(red);
+    assert_production_set_size(&rx, 1);
+    assert!(rete.working_memory[&w1]
+        .borrow()
+        .negative_join_results
+        .is_empty());
+
+    reset();
+}
+
+#[test]
+fn alpha_memory_refilled() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1(), c2()], tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new(W1));
+    let left_of = rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+    assert_production_set_size(&rx, 1);
+
+    // Emptying the alpha memory left unlinks the join, refilling it must relink it
+    rete.remove_wme(left_of);
+    rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+    assert_production_set_size(&rx, 1);
+
+    rete.add_wme(Wme::new([B4, ON, B2]));
+    assert_production_set_size(&rx, 1);
+
+    reset();
+}
+
+#[test]
+fn negative_node_with_remaining_tokens() {
+    let not_red = Condition::new_negative([V_Y, C_COLOR, C_RED]);
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1(), not_red], tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new(W1));
+    let other = rete.add_wme(Wme::new([B3, ON, B4]));
+
+    // The negative node keeps a token, so it must stay linked to its alpha memory
+    rete.remove_wme(other);
+    rete.add_wme(Wme::new([B5, ON, B6]));
+
+    // Neither must a new negative node that received tokens from above
+    let not_blue = Condition::new_negative([V_Y, C_COLOR, C_BLUE]);
+    let (tx_blue, _rx_blue) = channel();
+    rete.add_production(Production::new(&[c1(), not_blue], tx_blue))
+        .unwrap();
+    rete.add_wme(Wme::new([B6, ON, TABLE]));
+
+    for memory in rete.constant_tests.values() {
+        let memory = memory.borrow();
+        let mut successors = memory
+            .successors
+            .iter()
+            .map(|successor| successor.borrow().id())
+            .collect::<Vec<_>>();
+        successors.sort();
+        successors.dedup();
+        assert_eq!(successors.len(), memory.successors.len());
+    }
+
+    rete.add_wme(Wme::new([B2, COLOR, RED]));
+    assert_eq!(
+        rete.working_memory
+            .values()
+            .map(|wme| wme.borrow().negative_join_results.len())
+            .sum::<usize>(),
+        1
+    );
+    assert_production_set_size(&rx, 4);
+
+    reset();
+}
+
+#[test]
+fn negative_node_relinks_below_ancestor() {
+    let not_red_y = Condition::new_negative([V_Y, C_COLOR, C_RED]);
+    let not_red_z = Condition::new_negative([V_Z, C_COLOR, C_RED]);
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1(), not_red_y, c2(), not_red_z], tx))
+        .unwrap();
+
+    // The upper negative node has a token and stays linked while the lower one relinks
+    rete.add_wme(Wme::new(W1));
+    rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+    assert_production_set_size(&rx, 1);
+
+    rete.add_wme(Wme::new([B3, COLOR, RED]));
+    assert_eq!(rete.dummy_top_token.borrow().children().len(), 1);
+
+    reset();
+}
+
+#[test]
+fn new_negative_node_shares_alpha_memory() {
+    let mut rete = Rete::default();
+    rete.add_wme(Wme::new(W1));
+    rete.add_wme(Wme::new([B3, ON, B1]));
+
+    // The negative node tests the alpha memory that feeds the join above it, which must
+    // still hold its items while the join passes its matches down
+    let (tx, rx) = channel();
+    let not_below = Condition::new_negative([V_Z, C_ON, V_X]);
+    rete.add_production(Production::new(&[c1(), not_below], tx))
+        .unwrap();
+    assert_production_set_size(&rx, 1);
+
+    reset();
+}
+
+#[test]
+fn wme_blocking_its_own_token() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let not_back_on = Condition::new_negative([V_Y, C_ON, V_X]);
+    let prod_id = rete
+        .add_production(Production::new(&[c1(), not_back_on], tx))
+        .unwrap();
+
+    // The WME creates the token and blocks it, removing it must not unblock the deleted token
+    let id = rete.add_wme(Wme::new([B1, ON, B1]));
+    rete.remove_wme(id);
+
+    assert!(rete.productions[&prod_id].borrow().tokens().is_empty());
+    assert!(rx.try_recv().is_err());
+
+    reset();
+}
+
+#[test]
+fn wme_owning_its_own_ncc_result() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let nothing_below = Condition::new_ncc(vec![Condition::new_positive([V_Y, C_ON, V_Z])]);
+    let prod_id = rete
+        .add_production(Production::new(&[c1(), nothing_below], tx))
+        .unwrap();
+
+    // The WME creates the owner and its result, deleting the owner first must not let the
+    // result reactivate it
+    let id = rete.add_wme(Wme::new([B1, ON, B1]));
+    assert!(rx.try_recv().is_err());
+    rete.remove_wme(id);
+
+    assert!(rete.productions[&prod_id].borrow().tokens().is_empty());
+    assert!(rx.try_recv().is_err());
+
+    reset();
+}
+
+#[test]
+fn buffered_ncc_result_deleted() {
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+    let nothing_on_non_red = Condition::new_ncc(vec![
+        Condition::new_positive([V_Y, C_ON, V_Z]),
+        Condition::new_ncc(vec![Condition::new_positive([V_Z, C_COLOR, C_RED])]),
+    ]);
+    let prod_id = rete
+        .add_production(Production::new(&[c1(), nothing_on_non_red], tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new([B1, ON, B4]));
+    let id = rete.add_wme(Wme::new([B1, ON, B1]));
+    rete.add_wme(Wme::new([B4, COLOR, RED]));
+
+    // Removing the WME deletes a result still waiting for its owner, which must not be
+    // left behind in the partner's buffer
+    rete.remove_wme(id);
+    assert!(rete.validate().is_ok());
+    assert_eq!(rete.productions[&prod_id].borrow().tokens().len(), 1);
+
+    reset();
+}
+
+#[test]
+fn joins_relinking_below_activated_ancestor() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let red = Condition::new_positive([V_A, C_COLOR, C_RED]);
+    let on_top = Condition::new_positive([V_Y, C_ON, V_Z]);
+    rete.add_production(Production::new(&[red, c1(), on_top], tx))
+        .unwrap();
+
+    // Both joins on the alpha memory are right unlinked while their beta memories are empty
+    rete.add_wme(Wme::new(W1));
+    rete.add_wme(Wme::new([B2, ON, B3]));
+
+    // The upper join relinks and while it is activated the lower one relinks below it
+    rete.add_wme(Wme::new([B5, COLOR, RED]));
+    assert_production_set_size(&rx, 1);
 
     reset();
 }
@@ -540,8 +820,12 @@ fn add_remove_ncc_node() {
     const Y: usize = 1;
     const Z: usize = 2;
 
-    const C1: Condition = Condition::new_positive([V_X, C_ON, V_Y]);
-    const C2: Condition = Condition::new_positive([V_Y, C_LEFT_OF, V_Z]);
+    fn c1() -> Condition {
+        Condition::new_positive([V_X, C_ON, V_Y])
+    }
+    fn c2() -> Condition {
+        Condition::new_positive([V_Y, C_LEFT_OF, V_Z])
+    }
     let nccs = vec![
         Condition::new_positive([V_Z, C_COLOR, C_RED]),
         Condition::new_positive([V_Z, C_LEFT_OF, V_A]),
@@ -554,9 +838,9 @@ fn add_remove_ncc_node() {
 
     let mut rete = Rete::default();
     let (tx, rx) = channel();
-    let production = Production::new(&[C1, C2, nc_3], tx);
+    let production = Production::new(&[c1(), c2(), nc_3], tx);
 
-    let prod_id1 = rete.add_production(production);
+    let prod_id1 = rete.add_production(production).unwrap();
     assert_eq!(rete.productions.len(), 1);
 
     rete.print_to_file("add_remove_ncc_node/0_initial.txt")
@@ -582,7 +866,7 @@ fn add_remove_ncc_node() {
     assert!(rete.dummy_top_token.borrow().children().is_empty());
     assert!(rete.productions.is_empty());
 
-    assert_production_set_size(rx, 1);
+    assert_production_set_size(&rx, 1);
 
     reset();
 }
@@ -613,7 +897,7 @@ fn add_remove_single_ncc() {
     rete.print_to_file("add_remove_single_ncc/0_add_wmes.txt")
         .unwrap();
 
-    let prod_id1 = rete.add_production(production);
+    let prod_id1 = rete.add_production(production).unwrap();
     assert_eq!(rete.productions.len(), 1);
 
     rete.print_to_file("add_remove_single_ncc/1_add_production.txt")
@@ -630,6 +914,63 @@ fn add_remove_single_ncc() {
     reset();
 }
 
+#[test]
+fn ncc_results_leave_their_wmes() {
+    let ncc = Condition::new_ncc(vec![Condition::new_positive([V_Y, C_COLOR, C_RED])]);
+
+    let mut rete = Rete::default();
+
+    let (tx, _rx) = channel();
+    let production = Production::new(&[c1(), ncc], tx);
+
+    let prod_id = rete.add_production(production).unwrap();
+    let red = rete.add_wme(Wme::new([B2, COLOR, RED]));
+    let w1 = rete.add_wme(Wme::new(W1));
+    assert_eq!(rete.working_memory[&red].borrow().tokens.len(), 1);
+
+    let owner = rete.working_memory[&w1]
+        .borrow()
+        .tokens
+        .iter()
+        .find(|token| token.borrow().contains_ncc_results())
+        .cloned()
+        .expect("W1 owns the NCC result of the red WME");
+
+    // Deleting the owner deletes its result, which must no longer be held by its WME
+    Token::delete_self_and_descendants(owner);
+    assert!(rete.working_memory[&red].borrow().tokens.is_empty());
+
+    rete.remove_wme(w1);
+    rete.remove_wme(red);
+    rete.remove_production(prod_id);
+
+    reset();
+}
+
+#[test]
+fn ncc_result_after_owner() {
+    let ncc = Condition::new_ncc(vec![Condition::new_positive([V_Y, C_COLOR, C_RED])]);
+
+    let mut rete = Rete::default();
+
+    let (tx, rx) = channel();
+    let production = Production::new(&[c1(), ncc], tx);
+
+    let prod_id = rete.add_production(production).unwrap();
+    let w1 = rete.add_wme(Wme::new(W1));
+    assert_production_set_size(&rx, 1);
+
+    // The result emerges after its owner, which must still learn about it
+    let red = rete.add_wme(Wme::new([B2, COLOR, RED]));
+    rete.remove_wme(red);
+    assert_production_set_size(&rx, 1);
+
+    rete.remove_wme(w1);
+    rete.remove_production(prod_id);
+
+    reset();
+}
+
 #[test]
 fn ncc_complex() {
     const A: usize = 0;
@@ -667,7 +1008,7 @@ fn ncc_complex() {
     let (tx, rx) = channel();
     let production = Production::new(&[c, ncc], tx);
 
-    let prod_id1 = rete.add_production(production);
+    let prod_id1 = rete.add_production(production).unwrap();
     assert_eq!(rete.productions.len(), 1);
 
     rete.print_to_file("ncc_complex/0_initial.txt").unwrap();
@@ -722,7 +1063,2443 @@ fn ncc_complex() {
     assert!(rete.dummy_top_token.borrow().children().is_empty());
     assert!(rete.productions.is_empty());
 
-    assert_production_set_size(rx, 2);
+    assert_production_set_size(&rx, 2);
+
+    reset();
+}
+
+#[test]
+fn forall() {
+    const A: usize = 0;
+    const B: usize = 1;
+    const C: usize = 2;
+    const D: usize = 3;
+
+    // Every red block has a blue block on top of it
+    let forall = Condition::new_forall(
+        [V_X, C_COLOR, C_RED],
+        vec![
+            Condition::new_positive([V_Y, C_ON, V_X]),
+            Condition::new_positive([V_Y, C_COLOR, C_BLUE]),
+        ],
+    );
+
+    let mut rete = Rete::default();
+
+    let (tx, rx) = channel();
+    let production = Production::new(&[forall], tx);
+
+    let prod_id = rete.add_production(production).unwrap();
+
+    // Vacuously true when there are no red blocks
+    assert_production_set_size(&rx, 1);
+
+    let red_b = rete.add_wme(Wme::new([B, COLOR, RED]));
+    rete.add_wme(Wme::new([A, COLOR, BLUE]));
+    assert_production_set_size(&rx, 0);
+
+    let a_on_b = rete.add_wme(Wme::new([A, ON, B]));
+    assert_production_set_size(&rx, 1);
+
+    rete.add_wme(Wme::new([D, COLOR, RED]));
+    rete.add_wme(Wme::new([C, COLOR, BLUE]));
+    assert_production_set_size(&rx, 0);
+
+    rete.add_wme(Wme::new([C, ON, D]));
+    assert_production_set_size(&rx, 1);
+
+    rete.remove_wme(a_on_b);
+    assert_production_set_size(&rx, 0);
+
+    rete.remove_wme(red_b);
+    assert_production_set_size(&rx, 1);
+
+    rete.remove_production(prod_id);
+
+    assert!(rete.dummy_top_token.borrow().children().is_empty());
+    assert!(rete.productions.is_empty());
+
+    reset();
+}
+
+#[test]
+fn forall_shares_ncc_network() {
+    let forall = Condition::new_forall(
+        [V_X, C_COLOR, C_RED],
+        vec![Condition::new_positive([V_X, C_ON, C_TABLE])],
+    );
+    let ncc = Condition::new_ncc(vec![
+        Condition::new_positive([V_X, C_COLOR, C_RED]),
+        Condition::new_ncc(vec![Condition::new_positive([V_X, C_ON, C_TABLE])]),
+    ]);
+
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+
+    rete.add_production(Production::new(&[c1(), forall], tx.clone()))
+        .unwrap();
+    rete.add_production(Production::new(&[c1(), ncc], tx))
+        .unwrap();
+
+    assert_eq!(rete.constant_tests.len(), 3);
+
+    reset();
+}
+
+#[test]
+fn test_condition() {
+    // Blocks may only be stacked on larger blocks
+    let smaller = Predicate::new(|values| values[0] < values[1]);
+    let conditions = [
+        Condition::new_positive([V_X, C_ON, V_Y]),
+        Condition::new_positive([V_X, C_SIZE, V_A]),
+        Condition::new_positive([V_Y, C_SIZE, V_B]),
+        Condition::new_test(smaller, vec![3, 4]),
+    ];
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let prod_id = rete
+        .add_production(Production::new(&conditions, tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new([B1, ON, B2]));
+    let b1_size = rete.add_wme(Wme::new([B1, SIZE, 5]));
+    rete.add_wme(Wme::new([B2, SIZE, 3]));
+    assert_production_set_size(&rx, 0);
+
+    rete.remove_wme(b1_size);
+    rete.add_wme(Wme::new([B1, SIZE, 2]));
+    assert_production_set_size(&rx, 1);
+
+    // Filter tokens must be removed along with their WMEs
+    let filter = rete.productions[&prod_id].borrow().parent().unwrap();
+    let filter_items = || filter.borrow().tokens().len();
+    assert_eq!(filter_items(), 1);
+
+    let b3_on_b2 = rete.add_wme(Wme::new([B3, ON, B2]));
+    rete.add_wme(Wme::new([B3, SIZE, 1]));
+    assert_production_set_size(&rx, 1);
+    assert_eq!(filter_items(), 2);
+
+    rete.remove_wme(b3_on_b2);
+    assert_eq!(filter_items(), 1);
+
+    rete.remove_production(prod_id);
+
+    assert!(rete.dummy_top_token.borrow().children().is_empty());
+    assert!(rete.productions.is_empty());
+
+    reset();
+}
+
+#[test]
+fn test_condition_sharing() {
+    let lighter = Predicate::new(|values| values[0] < values[1]);
+    let conditions = |predicate: &Predicate| {
+        vec![
+            Condition::new_positive([V_X, C_SIZE, V_A]),
+            Condition::new_positive([V_Y, C_SIZE, V_B]),
+            Condition::new_test(predicate.clone(), vec![3, 4]),
+        ]
+    };
+
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+
+    let first = rete
+        .add_production(Production::new(&conditions(&lighter), tx.clone()))
+        .unwrap();
+    let second = rete
+        .add_production(Production::new(&conditions(&lighter), tx.clone()))
+        .unwrap();
+
+    // Equal closures are still different predicates
+    let other = Predicate::new(|values| values[0] < values[1]);
+    let third = rete
+        .add_production(Production::new(&conditions(&other), tx))
+        .unwrap();
+
+    let parent = |id: usize| rete.productions[&id].borrow().parent().unwrap();
+
+    assert!(Rc::ptr_eq(&parent(first), &parent(second)));
+    assert!(!Rc::ptr_eq(&parent(first), &parent(third)));
+
+    reset();
+}
+
+#[test]
+fn alpha_tests() {
+    let medium = Condition::new_positive([V_X, C_SIZE, V_A]).with_alpha_test(AlphaTest::Range {
+        field: 2,
+        min: 3,
+        max: 6,
+    });
+    let not_b1 = Condition::new_positive([V_X, C_COLOR, V_Y])
+        .with_alpha_test(AlphaTest::NotEqual {
+            field: 0,
+            value: B1,
+        })
+        .with_alpha_test(AlphaTest::OneOf {
+            field: 2,
+            values: vec![RED, BLUE],
+        });
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let prod_id = rete
+        .add_production(Production::new(&[medium, not_b1], tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new([B1, SIZE, 4]));
+    rete.add_wme(Wme::new([B1, COLOR, RED]));
+    assert_production_set_size(&rx, 0);
+
+    rete.add_wme(Wme::new([B2, SIZE, 7]));
+    rete.add_wme(Wme::new([B2, COLOR, BLUE]));
+    assert_production_set_size(&rx, 0);
+
+    rete.add_wme(Wme::new([B3, COLOR, MAIZE]));
+    rete.add_wme(Wme::new([B3, SIZE, 3]));
+    assert_production_set_size(&rx, 0);
+
+    let b3_red = rete.add_wme(Wme::new([B3, COLOR, RED]));
+    assert_production_set_size(&rx, 1);
+
+    // Filtered WMEs never reach the alpha memories
+    let memories = |id| rete.wme_alphas.get(&id).map_or(0, |mems| mems.len());
+    assert_eq!(memories(b3_red), 1);
+    assert_eq!(rete.constant_tests.len(), 0);
+    assert_eq!(rete.alpha_network.len(), 2);
+
+    rete.remove_production(prod_id);
+
+    assert!(rete.alpha_network.is_empty());
+    assert!(rete.wme_alphas.is_empty());
+
+    reset();
+}
+
+#[test]
+fn alpha_tests_repeated_variable() {
+    let conditions = [Condition::new_positive([V_X, C_LEFT_OF, V_X])];
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&conditions, tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new([B1, LEFT_OF, B2]));
+    assert_production_set_size(&rx, 0);
+
+    rete.add_wme(Wme::new([B1, LEFT_OF, B1]));
+    assert_production_set_size(&rx, 1);
+
+    reset();
+}
+
+#[test]
+fn alpha_test_sharing() {
+    let small = AlphaTest::Less { field: 2, value: 5 };
+    let large = AlphaTest::Greater { field: 2, value: 2 };
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+
+    let first = rete
+        .add_production(Production::new(
+            &[Condition::new_positive([V_X, C_SIZE, V_A]).with_alpha_test(small.clone())],
+            tx.clone(),
+        ))
+        .unwrap();
+    let second = rete
+        .add_production(Production::new(
+            &[Condition::new_positive([V_X, C_SIZE, V_A])
+                .with_alpha_test(large.clone())
+                .with_alpha_test(small.clone())],
+            tx,
+        ))
+        .unwrap();
+
+    // Both paths start with the same test node
+    let root =
+        &rete.alpha_network[&ConstantTest::from(&Condition::new_positive([V_X, C_SIZE, V_A]))];
+    assert_eq!(root.len(), 1);
+    assert_eq!(root[0].children.len(), 1);
+
+    rete.add_wme(Wme::new([B1, SIZE, 1]));
+    assert_production_set_size(&rx, 1);
+    rete.add_wme(Wme::new([B2, SIZE, 4]));
+    assert_production_set_size(&rx, 2);
+
+    rete.remove_production(second);
+    let root =
+        &rete.alpha_network[&ConstantTest::from(&Condition::new_positive([V_X, C_SIZE, V_A]))];
+    assert!(root[0].children.is_empty());
+    assert!(root[0].memory.is_some());
+
+    rete.remove_production(first);
+    assert!(rete.alpha_network.is_empty());
+
+    reset();
+}
+
+#[test]
+fn n_ary_wmes() {
+    const ORDER: usize = 30;
+    const CUSTOMER: usize = 31;
+    const OPEN: usize = 32;
+    const SHIPPED: usize = 33;
+
+    let c_order = ConditionTest::Constant(ORDER);
+    let c_customer = ConditionTest::Constant(CUSTOMER);
+    let c_open = ConditionTest::Constant(OPEN);
+
+    // (order id customer total status) joined with (customer id color)
+    let conditions = [
+        Condition::new_positive([c_order, V_X, V_Y, V_Z, c_open]).with_alpha_test(
+            AlphaTest::Greater {
+                field: 3,
+                value: 100,
+            },
+        ),
+        Condition::new_positive([c_customer, V_Y, C_RED]),
+    ];
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let prod_id = rete
+        .add_production(Production::new(&conditions, tx))
+        .unwrap();
+
+    rete.add_wme(Wme::new([CUSTOMER, B1, RED]));
+    rete.add_wme(Wme::new([CUSTOMER, B2, BLUE]));
+    assert_production_set_size(&rx, 0);
+
+    rete.add_wme(Wme::new([ORDER, 1, B1, 150, SHIPPED]));
+    rete.add_wme(Wme::new([ORDER, 2, B1, 50, OPEN]));
+    rete.add_wme(Wme::new([ORDER, 3, B2, 150, OPEN]));
+    assert_production_set_size(&rx, 0);
+
+    // A triple with the same leading constants must not match the 5-ary pattern
+    rete.add_wme(Wme::new([ORDER, 4, B1]));
+    assert_production_set_size(&rx, 0);
+
+    let order = rete.add_wme(Wme::new([ORDER, 5, B1, 200, OPEN]));
+    assert_production_set_size(&rx, 1);
+
+    // One look up per distinct shape, regardless of arity
+    assert_eq!(rete.shapes.len(), 2);
+
+    rete.remove_wme(order);
+    rete.remove_production(prod_id);
+
+    assert!(rete.shapes.is_empty());
+    assert!(rete.dummy_top_token.borrow().children().is_empty());
+
+    reset();
+}
+
+#[test]
+fn set_semantics() {
+    let mut rete = Rete::new(ReteConfig {
+        set_semantics: true,
+    });
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1(), c2(), c3()], tx))
+        .unwrap();
+
+    let first = rete.add_wme(Wme::new(W1));
+    let second = rete.add_wme(Wme::new(W1));
+    assert_eq!(first, second);
+    assert_eq!(rete.working_memory.len(), 1);
+
+    rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+    rete.add_wme(Wme::new(W9));
+    assert_production_set_size(&rx, 1);
+
+    // Still referenced once
+    rete.remove_wme(first);
+    assert!(rete.working_memory.contains_key(&first));
+    assert!(!rete.dummy_top_token.borrow().children().is_empty());
+
+    rete.remove_wme(first);
+    assert!(!rete.working_memory.contains_key(&first));
+    assert!(!rete.wme_set.contains_key(W1.as_slice()));
+
+    // Without set semantics duplicates produce duplicate firings
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1(), c2(), c3()], tx))
+        .unwrap();
+
+    let first = rete.add_wme(Wme::new(W1));
+    let second = rete.add_wme(Wme::new(W1));
+    assert_ne!(first, second);
+
+    rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+    rete.add_wme(Wme::new(W9));
+    assert_production_set_size(&rx, 2);
+
+    reset();
+}
+
+#[test]
+fn logical_assertions() {
+    const UNSTABLE: usize = 40;
+    const FACT: [usize; 3] = [TABLE, UNSTABLE, TABLE];
+
+    let on_blue = Rule {
+        conditions: vec![
+            Condition::new_positive([V_X, C_ON, V_Y]),
+            Condition::new_positive([V_Y, C_COLOR, C_BLUE]),
+        ],
+        production: Box::new(|e, _| {
+            e.add_logical_wme(Wme::new(FACT));
+        }),
+        bindings: vec![],
+        metadata: RuleMetadata::default(),
+    };
+
+    let block = |id: usize, positions, color| Block {
+        rete_id: id << (usize::BITS / 2),
+        id,
+        positions,
+        color,
+    };
+
+    let mut engine = Engine::default();
+    engine.add_rule(on_blue).unwrap();
+
+    let is_asserted = |engine: &Engine| {
+        engine
+            .rete
+            .working_memory
+            .values()
+            .any(|wme| wme.borrow().fields == FACT)
+    };
+
+    engine.add_element(block(1, vec![Position::Table], Color::Blue));
+    engine.activate_productions();
+    assert!(!is_asserted(&engine));
+
+    let b1 = 1 << (usize::BITS / 2);
+    engine.add_element(block(2, vec![Position::On(b1)], Color::Red));
+    engine.add_element(block(3, vec![Position::On(b1)], Color::Red));
+    engine.activate_productions();
+
+    // Both matches support a single WME
+    assert!(is_asserted(&engine));
+    assert_eq!(engine.logical_facts[FACT.as_slice()].support.len(), 2);
+
+    engine.remove_element(2);
+    engine.activate_productions();
+    assert!(is_asserted(&engine));
+    assert_eq!(engine.logical_facts[FACT.as_slice()].support.len(), 1);
+
+    engine.remove_element(3);
+    engine.activate_productions();
+    assert!(!is_asserted(&engine));
+    assert!(engine.logical_facts.is_empty());
+    assert!(engine.token_support.is_empty());
+
+    reset();
+}
+
+#[test]
+fn explanation() {
+    let distinct = Predicate::new(|values| values[0] != values[1]);
+    let conditions = [
+        c1(),
+        c2(),
+        Condition::new_negative([V_Z, C_COLOR, C_RED]),
+        Condition::new_ncc(vec![Condition::new_positive([V_Z, C_ON, V_A])]),
+        Condition::new_test(distinct, vec![0, 2]),
+    ];
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let prod_id = rete
+        .add_production(Production::new(&conditions, tx))
+        .unwrap();
+
+    let on = rete.add_wme(Wme::new([B1, ON, B2]));
+    let left_of = rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+
+    let activation = rx.try_recv().unwrap();
+    assert_eq!(activation.production(), prod_id);
+
+    let explanation = rete.explain(&activation).unwrap();
+    println!("{explanation}");
+
+    assert_eq!(
+        explanation.bindings.into_iter().collect::<Vec<_>>(),
+        vec![(0, B1), (1, B2), (2, B3)]
+    );
+
+    let [matched_on, matched_left_of, absent, ncc, test] = explanation.conditions.as_slice() else {
+        panic!("Expected one explanation per condition")
+    };
+
+    assert!(matches!(matched_on, ConditionExplanation::Matched { wme_id, .. } if *wme_id == on));
+    assert!(
+        matches!(matched_left_of, ConditionExplanation::Matched { wme_id, fields, .. } if *wme_id == left_of && fields == &[B2, LEFT_OF, B3])
+    );
+    assert!(
+        matches!(absent, ConditionExplanation::Absent { pattern, .. } if pattern == &[Some(B3), Some(COLOR), Some(RED)])
+    );
+    assert!(matches!(ncc, ConditionExplanation::NoConjunction { .. }));
+    assert!(matches!(test, ConditionExplanation::Passed { values, .. } if values == &[B1, B3]));
+
+    // Retracted matches cannot be explained
+    rete.remove_wme(on);
+    assert!(rete.explain(&activation).is_none());
+
+    reset();
+}
+
+#[test]
+fn why_not() {
+    let conditions = [c1(), Condition::new_negative([V_Y, C_COLOR, C_RED]), c2()];
+
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+    let prod_id = rete
+        .add_production(Production::new(&conditions, tx))
+        .unwrap();
+
+    assert!(rete.why_not(prod_id + 1).is_none());
+
+    let why_not = rete.why_not(prod_id).unwrap();
+    println!("{why_not}");
+    assert!(!why_not.fired);
+    assert_eq!(why_not.deepest.map(|node| node.kind), Some("beta"));
+    assert_eq!(
+        why_not
+            .path
+            .iter()
+            .map(|diagnostic| diagnostic.node.kind)
+            .collect::<Vec<_>>(),
+        vec!["beta", "beta", "join", "negative", "beta", "join", "prod"]
+    );
+    let join = &why_not.path[2];
+    assert_eq!(join.alpha_memory.map(|(_, items)| items), Some(0));
+    assert!(!join.left_linked);
+
+    rete.add_wme(Wme::new([B1, ON, B2]));
+    let red = rete.add_wme(Wme::new([B2, COLOR, RED]));
+
+    let why_not = rete.why_not(prod_id).unwrap();
+    println!("{why_not}");
+    let negative = &why_not.path[3];
+    assert_eq!(why_not.deepest, Some(negative.node));
+    assert!(matches!(
+        negative.blocking.as_slice(),
+        [Blocking::JoinResult { wme_id, .. }] if *wme_id == red
+    ));
+
+    rete.remove_wme(red);
+    rete.add_wme(Wme::new([B2, LEFT_OF, B3]));
+
+    let why_not = rete.why_not(prod_id).unwrap();
+    assert!(why_not.fired);
+    assert!(why_not.path.iter().all(|node| node.blocking.is_empty()));
+
+    reset();
+}
+
+#[test]
+fn transactions() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let production = rete
+        .add_production(Production::new(&[c1(), c2(), c3()], tx))
+        .unwrap();
+
+    for wme in [W5, W7, W9, [B4, COLOR, RED]] {
+        rete.add_wme(Wme::new(wme));
+    }
+
+    rete.begin_transaction();
+    let on = rete.add_wme(Wme::new(W1));
+    assert!(!rete.working_memory.contains_key(&on));
+    assert_production_set_size(&rx, 0);
+
+    assert!(rete.commit_transaction());
+    assert!(rete.working_memory.contains_key(&on));
+    assert_production_set_size(&rx, 1);
+
+    // Moving a block retracts the old match and fires the new one once everything is applied
+    rete.begin_transaction();
+    rete.remove_wme(on);
+    let moved = rete.add_wme(Wme::new(W2));
+    assert!(rete.working_memory.contains_key(&on));
+    assert!(rx.try_recv().is_err());
+
+    rete.commit_transaction();
+    let activations = rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(activations.len(), 2);
+    assert!(matches!(activations[0], Activation::Retracted { .. }));
+    assert!(matches!(activations[1], Activation::Fired { .. }));
+    assert_eq!(
+        rete.explain(&activations[1]).unwrap().conditions[0],
+        ConditionExplanation::Matched {
+            condition: c1(),
+            wme_id: moved,
+            fields: W2.to_vec(),
+        }
+    );
+
+    // Matches living only in between changes are never reported
+    rete.begin_transaction();
+    let id = rete.add_wme(Wme::new(W1));
+    rete.remove_wme(id);
+    rete.commit_transaction();
+    assert!(rx.try_recv().is_err());
+    assert_eq!(rete.productions[&production].borrow().tokens().len(), 1);
+
+    // Rolling back leaves the rete untouched
+    rete.begin_transaction();
+    rete.remove_wme(moved);
+    rete.add_wme(Wme::new(W1));
+    assert!(rete.rollback_transaction());
+    assert!(!rete.commit_transaction());
+    assert!(rx.try_recv().is_err());
+    assert!(rete.working_memory.contains_key(&moved));
+    assert_eq!(rete.working_memory.len(), 5);
+
+    // IDs returned in a transaction are the ones WMEs have once committed
+    let mut rete = Rete::new(ReteConfig {
+        set_semantics: true,
+    });
+    let existing = rete.add_wme(Wme::new(W1));
+    rete.begin_transaction();
+    assert_eq!(rete.add_wme(Wme::new(W1)), existing);
+    rete.remove_wme(existing);
+    rete.remove_wme(existing);
+    let new = rete.add_wme(Wme::new(W1));
+    assert_ne!(new, existing);
+    rete.commit_transaction();
+    assert_eq!(rete.wme_set[W1.as_slice()], (new, 1));
+
+    let mut engine = Engine::default();
+    engine
+        .add_rule(Rule {
+            conditions: vec![c1()],
+            production: Box::new(|_, _| {}),
+            bindings: vec![],
+            metadata: RuleMetadata::default(),
+        })
+        .unwrap();
+    engine.begin_transaction();
+    engine.add_element(Block {
+        rete_id: 1 << (usize::BITS / 2),
+        id: 1,
+        positions: vec![Position::Table],
+        color: Color::Blue,
+    });
+    assert!(engine.rollback_transaction());
+    assert!(engine.elements.is_empty());
+    assert!(engine.rete.working_memory.is_empty());
+
+    reset();
+}
+
+#[test]
+fn forks() {
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    let production = rete
+        .add_production(Production::new(&[c1(), c2(), c3()], tx))
+        .unwrap();
+
+    for wme in [W5, W7, W9, [B4, COLOR, RED]] {
+        rete.add_wme(Wme::new(wme));
+    }
+    let on = rete.add_wme(Wme::new(W2));
+    assert_production_set_size(&rx, 1);
+
+    // Existing matches are not reported by the fork
+    let (fork_tx, fork_rx) = channel();
+    let mut fork = rete.fork(fork_tx);
+    assert!(fork_rx.try_recv().is_err());
+    assert_eq!(fork.rete.working_memory.len(), rete.working_memory.len());
+    assert_eq!(
+        fork.rete.productions[&production].borrow().tokens().len(),
+        1
+    );
+
+    fork.rete.remove_wme(on);
+    let moved = fork.rete.add_wme(Wme::new(W1));
+    let activations = fork_rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(activations.len(), 2);
+    assert!(matches!(activations[1], Activation::Fired { production: p, .. } if p == production));
+
+    // The original is untouched
+    assert!(rx.try_recv().is_err());
+    assert!(rete.working_memory.contains_key(&on));
+    assert!(!rete.working_memory.contains_key(&moved));
+
+    // Changes made to the original since forking are kept when merging
+    let color = rete.add_wme(Wme::new(W6));
+    rete.merge(fork);
+    assert!(!rete.working_memory.contains_key(&on));
+    assert!(rete.working_memory.contains_key(&moved));
+    assert!(rete.working_memory.contains_key(&color));
+
+    let activations = rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(activations.len(), 2);
+    assert!(matches!(activations[0], Activation::Retracted { .. }));
+    assert!(matches!(activations[1], Activation::Fired { .. }));
+
+    // Dropping a fork discards its changes
+    let (fork_tx, _fork_rx) = channel();
+    let mut fork = rete.fork(fork_tx);
+    fork.rete.remove_wme(moved);
+    drop(fork);
+    assert!(rete.working_memory.contains_key(&moved));
+    assert_eq!(rete.productions[&production].borrow().tokens().len(), 1);
+
+    reset();
+}
+
+#[test]
+fn forks_share_the_network() {
+    use std::{cell::Cell, rc::Rc};
+    use threte::rete::node::Node;
+
+    let calls = Rc::new(Cell::new(0));
+    let counted = calls.clone();
+    let smaller = Predicate::new(move |values| {
+        counted.set(counted.get() + 1);
+        values[0] < values[1]
+    });
+
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+    let production = rete
+        .add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_SIZE, V_A])
+                    .with_alpha_test(AlphaTest::NotEqual { field: 2, value: 0 }),
+                Condition::new_positive([V_Y, C_SIZE, V_B]),
+                Condition::new_test(smaller, vec![3, 4]),
+                Condition::new_negative([V_X, C_ON, V_Y]),
+                Condition::new_ncc(vec![Condition::new_positive([V_Y, C_COLOR, C_RED])]),
+            ],
+            tx,
+        ))
+        .unwrap();
+
+    for wme in [[B1, SIZE, 1], [B2, SIZE, 2], [B3, SIZE, 3], W9] {
+        rete.add_wme(Wme::new(wme));
+    }
+    // B3 is red, which leaves B1 and B2
+    let matches = production_tokens(&rete, production);
+    assert_eq!(matches.len(), 1);
+
+    // Forking copies the matches instead of testing the WMEs again
+    let before = calls.get();
+    let (fork_tx, fork_rx) = channel();
+    let mut fork = rete.fork(fork_tx);
+    assert_eq!(calls.get(), before);
+    assert!(fork_rx.try_recv().is_err());
+    fork.rete.assert_valid();
+    assert_eq!(production_tokens(&fork.rete, production), matches);
+
+    // The nodes keep their IDs and share their tests, but not their state
+    let mut node = Some(Rc::clone(&rete.productions[&production]));
+    let mut forked = Some(Rc::clone(&fork.rete.productions[&production]));
+    while let (Some(n), Some(f)) = (node, forked) {
+        assert!(!Rc::ptr_eq(&n, &f));
+        let (n, f) = (n.borrow(), f.borrow());
+        assert_eq!((n._type(), n.id()), (f._type(), f.id()));
+        match (&*n, &*f) {
+            (Node::Join(n), Node::Join(f)) => assert!(Rc::ptr_eq(&n.tests, &f.tests)),
+            (Node::Negative(n), Node::Negative(f)) => assert!(Rc::ptr_eq(&n.tests, &f.tests)),
+            (Node::Filter(n), Node::Filter(f)) => {
+                assert!(Rc::ptr_eq(&n.locations, &f.locations));
+                assert_eq!(n.predicate, f.predicate);
+            }
+            _ => {}
+        }
+        node = n.parent();
+        forked = f.parent();
+    }
+    for (test, nodes) in rete.alpha_network.iter() {
+        let forked = &fork.rete.alpha_network[test];
+        assert_eq!(nodes[0].id, forked[0].id);
+        assert!(Rc::ptr_eq(&nodes[0].test, &forked[0].test));
+    }
+
+    // Changes to the fork only affect its own tokens
+    fork.rete.add_wme(Wme::new([B1, ON, B2]));
+    assert!(production_tokens(&fork.rete, production).is_empty());
+    assert_eq!(production_tokens(&rete, production), matches);
+    fork.rete.add_wme(Wme::new([B4, SIZE, 4]));
+    assert!(calls.get() > before);
+    assert_eq!(production_tokens(&fork.rete, production).len(), 3);
+    assert_eq!(fork_rx.try_iter().count(), 4);
+    fork.rete.assert_valid();
+    rete.assert_valid();
+    assert_eq!(production_tokens(&rete, production), matches);
+
+    reset();
+}
+
+fn production_tokens(rete: &Rete, production: usize) -> Vec<usize> {
+    let mut tokens = rete.productions[&production]
+        .borrow()
+        .tokens()
+        .iter()
+        .map(|token| token.borrow().id())
+        .collect::<Vec<_>>();
+    tokens.sort();
+    tokens
+}
+
+#[test]
+fn temporal_facts() {
+    use std::rc::Rc;
+    use threte::rete::time::PseudoClock;
+
+    let clock = Rc::new(PseudoClock::new(1000));
+
+    // Window conditions only match recent WMEs
+    let mut rete = Rete::default();
+    rete.set_clock(clock.clone());
+    let (tx, rx) = channel();
+    let windowed = rete
+        .add_production(Production::new(
+            &[
+                c1().with_alpha_test(AlphaTest::Within { duration: 5000 }),
+                c2(),
+                c3(),
+            ],
+            tx,
+        ))
+        .unwrap();
+
+    rete.add_wme(Wme::new(W5));
+    rete.add_wme(Wme::new(W9));
+    let on = rete.add_wme(Wme::new(W1));
+    assert_eq!(rete.working_memory[&on].borrow().timestamp, Some(1000));
+    assert_production_set_size(&rx, 1);
+
+    clock.advance(4999);
+    assert!(rete.expire().is_empty());
+    assert_eq!(rete.productions[&windowed].borrow().tokens().len(), 1);
+
+    clock.advance(1);
+    rete.expire();
+    assert!(matches!(rx.try_recv(), Ok(Activation::Retracted { .. })));
+    assert!(rete.productions[&windowed].borrow().tokens().is_empty());
+    assert!(rete.working_memory.contains_key(&on));
+
+    rete.add_wme(Wme::new([B1, ON, B2]).with_timestamp(1001));
+    assert_production_set_size(&rx, 1);
+    rete.add_wme(Wme::new([B1, ON, B2]).with_timestamp(1000));
+    assert_production_set_size(&rx, 0);
+
+    // WMEs with a lifetime get retracted once it passes
+    clock.set(0);
+    let mut rete = Rete::default();
+    rete.set_clock(clock.clone());
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c3()], tx)).unwrap();
+
+    let red = rete.add_wme(Wme::new(W9).with_lifetime(100));
+    assert_production_set_size(&rx, 1);
+
+    clock.advance(100);
+    rete.add_wme(Wme::new(W5));
+    assert!(!rete.working_memory.contains_key(&red));
+    assert!(matches!(rx.try_recv(), Ok(Activation::Retracted { .. })));
+
+    let red = rete.add_wme(Wme::new(W3).with_lifetime(10));
+    clock.advance(10);
+    assert_eq!(rete.expire(), vec![red]);
+
+    // Removed WMEs no longer expire
+    let red = rete.add_wme(Wme::new(W3).with_lifetime(10));
+    rete.remove_wme(red);
+    clock.advance(10);
+    assert!(rete.expire().is_empty());
+    assert!(rete.expirations.is_empty());
+
+    // Expiring during a transaction only buffers the removal, rolling back restores the expiration
+    let red = rete.add_wme(Wme::new(W3).with_lifetime(10));
+    clock.advance(10);
+    rete.begin_transaction();
+    assert_eq!(rete.expire(), vec![red]);
+    assert!(rete.working_memory.contains_key(&red));
+    assert!(rete.rollback_transaction());
+    assert!(rete.working_memory.contains_key(&red));
+    assert_eq!(rete.expire(), vec![red]);
+    assert!(!rete.working_memory.contains_key(&red));
+
+    let red = rete.add_wme(Wme::new(W3).with_lifetime(10));
+    clock.advance(10);
+    rete.begin_transaction();
+    assert_eq!(rete.expire(), vec![red]);
+    assert!(rete.expire().is_empty());
+    assert!(rete.commit_transaction());
+    assert!(!rete.working_memory.contains_key(&red));
+    assert!(rete.expirations.is_empty());
+
+    // With set semantics, every addition expiring at the same time retracts the WME once
+    let mut rete = Rete::new(ReteConfig {
+        set_semantics: true,
+    });
+    rete.set_clock(clock.clone());
+    let first = rete.add_wme(Wme::new(W9).with_lifetime(10));
+    let second = rete.add_wme(Wme::new(W9).with_lifetime(10));
+    assert_eq!(first, second);
+    clock.advance(10);
+    assert_eq!(rete.expire(), vec![first, first]);
+    assert!(rete.working_memory.is_empty());
+    assert!(rete.wme_set.is_empty());
+
+    // Negated windows are satisfied again once the blocking WME gets too old
+    let mut rete = Rete::default();
+    rete.set_clock(clock.clone());
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(
+        &[
+            c3(),
+            Condition::new_negative([V_X, C_ON, V_Z])
+                .with_alpha_test(AlphaTest::Within { duration: 1000 }),
+        ],
+        tx,
+    ))
+    .unwrap();
+
+    rete.add_wme(Wme::new(W9));
+    assert_production_set_size(&rx, 1);
+    rete.add_wme(Wme::new(W2));
+    assert!(matches!(rx.try_recv(), Ok(Activation::Retracted { .. })));
+
+    clock.advance(1000);
+    rete.expire();
+    assert_production_set_size(&rx, 1);
+
+    let on = rete.add_wme(Wme::new(W1));
+    assert_eq!(rete.window_exits.len(), 1);
+    rete.remove_wme(on);
+    clock.advance(1000);
+    assert!(rete.expire().is_empty());
+    assert!(rete.window_exits.is_empty());
+    assert!(rx.try_recv().is_err());
+
+    reset();
+}
+
+#[test]
+fn temporal_join_tests() {
+    use std::rc::Rc;
+    use threte::rete::{item::TemporalRelation, time::PseudoClock};
+
+    const ALARM: usize = 30;
+    const SMOKE: usize = 31;
+    const MAINTENANCE: usize = 32;
+    const ROOM: usize = 33;
+
+    let clock = Rc::new(PseudoClock::new(0));
+    let mut rete = Rete::default();
+    rete.set_clock(clock.clone());
+
+    let alarm = || Condition::new_positive([V_X, ConditionTest::Constant(ALARM), V_Y]);
+    let smoke = |relation| {
+        Condition::new_positive([V_Z, ConditionTest::Constant(SMOKE), V_Y])
+            .with_temporal_test(relation, 0)
+    };
+    let within_10s = TemporalRelation::After {
+        min: 0,
+        max: 10_000,
+    };
+
+    let (tx, rx) = channel();
+    let p1 = rete
+        .add_production(Production::new(&[alarm(), smoke(within_10s)], tx.clone()))
+        .unwrap();
+    let p2 = rete
+        .add_production(Production::new(&[alarm(), smoke(within_10s)], tx.clone()))
+        .unwrap();
+    let p3 = rete
+        .add_production(Production::new(
+            &[alarm(), smoke(TemporalRelation::Overlaps)],
+            tx.clone(),
+        ))
+        .unwrap();
+
+    // Temporal tests take part in node sharing
+    let parent = |id: usize| rete.productions[&id].borrow().parent().unwrap();
+    assert!(Rc::ptr_eq(&parent(p1), &parent(p2)));
+    assert!(!Rc::ptr_eq(&parent(p1), &parent(p3)));
+
+    rete.add_wme(Wme::new([1, ALARM, ROOM]));
+    clock.advance(5_000);
+    rete.add_wme(Wme::new([2, SMOKE, ROOM]));
+    assert_production_set_size(&rx, 2);
+
+    clock.advance(15_000);
+    rete.add_wme(Wme::new([3, SMOKE, ROOM]));
+    assert_production_set_size(&rx, 0);
+
+    // Intervals are given by the lifetimes of WMEs
+    let mut rete = Rete::default();
+    rete.set_clock(clock.clone());
+    rete.add_production(Production::new(
+        &[
+            Condition::new_positive([V_X, ConditionTest::Constant(MAINTENANCE), V_Y]),
+            Condition::new_positive([V_Z, ConditionTest::Constant(ALARM), V_Y])
+                .with_temporal_test(TemporalRelation::During, 0),
+        ],
+        tx,
+    ))
+    .unwrap();
+
+    rete.add_wme(Wme::new([4, MAINTENANCE, ROOM]).with_lifetime(60_000));
+    clock.advance(30_000);
+    rete.add_wme(Wme::new([5, ALARM, ROOM]));
+    assert_production_set_size(&rx, 1);
+
+    rete.add_wme(Wme::new([6, ALARM, ROOM]).with_lifetime(60_000));
+    assert_production_set_size(&rx, 0);
+
+    reset();
+}
+
+#[test]
+fn rule_names() {
+    const FACT: [usize; 3] = [TABLE, 41, TABLE];
+
+    let mut engine = Engine::default();
+    let on_red = engine
+        .add_rule(Rule {
+            conditions: vec![c1(), c2(), c3()],
+            production: Box::new(|e, _| {
+                let activation = e.current_activation().unwrap();
+                assert_eq!(activation.name(), Some("on red"));
+                e.add_logical_wme(Wme::new(FACT));
+            }),
+            bindings: vec![],
+            metadata: RuleMetadata {
+                name: Some("on red".to_string()),
+                description: Some("A block is on a block left of a red one".to_string()),
+                tags: vec!["colors".to_string()],
+            },
+        })
+        .unwrap();
+    let on_maize = engine
+        .add_rule(Rule {
+            conditions: vec![c1(), c2(), c6()],
+            production: Box::new(|_, _| {}),
+            bindings: vec![],
+            metadata: RuleMetadata::named("on maize"),
+        })
+        .unwrap();
+
+    assert_eq!(engine.rule_id("on red"), Some(on_red));
+
+    // Rules that cannot be added leave the engine unchanged
+    let rule = |conditions, name| Rule {
+        conditions,
+        production: Box::new(|_, _| {}),
+        bindings: vec![],
+        metadata: RuleMetadata::named(name),
+    };
+    assert_eq!(
+        engine.add_rule(rule(vec![c4()], "on red")).unwrap_err(),
+        RuleError::DuplicateName("on red".into())
+    );
+    let not_on = Condition::new_negative([V_X, C_ON, V_Y]);
+    assert!(matches!(
+        engine.add_rule(rule(vec![not_on, c1()], "unbound")),
+        Err(RuleError::InvalidConditions(_))
+    ));
+    assert!(engine.rule_id("unbound").is_none());
+    assert_eq!(engine.production_map.len(), 2);
+    assert_eq!(engine.rete.productions.len(), 2);
+    assert_eq!(
+        engine
+            .rule_by_name("on maize")
+            .unwrap()
+            .metadata
+            .name
+            .as_deref(),
+        Some("on maize")
+    );
+    assert_eq!(
+        engine
+            .rules_with_tag("colors")
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>(),
+        vec![on_red]
+    );
+
+    let production = engine.rete.productions[&on_red].borrow().to_string();
+    assert!(production.contains("on red"), "{production}");
+
+    for wme in [W1, W5, W9] {
+        engine.rete.add_wme(Wme::new(wme));
+    }
+    engine.activate_productions();
+    assert!(engine.logical_facts.contains_key(FACT.as_slice()));
+
+    let removed = engine.remove_rule_by_name("on red").unwrap();
+    assert_eq!(removed.metadata.tags, vec!["colors"]);
+    assert!(engine.rule_id("on red").is_none());
+    assert!(!engine.rete.productions.contains_key(&on_red));
+
+    // Retracting the removed rule's matches retracts its logical facts
+    engine.activate_productions();
+    assert!(engine.logical_facts.is_empty());
+
+    assert!(engine.remove_rule(on_maize).is_some());
+    assert!(engine.remove_rule(on_maize).is_none());
+
+    // Activations carry the names of their productions
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+    rete.add_production(Production::new(&[c1()], tx).with_name("on"))
+        .unwrap();
+    rete.add_wme(Wme::new(W1));
+    let activation = rx.try_recv().unwrap();
+    assert_eq!(activation.name(), Some("on"));
+    assert!(activation.to_string().starts_with("Fired on"));
+
+    reset();
+}
+
+#[test]
+fn replace_rules() {
+    use std::cell::Cell;
+
+    let counter = |count: &Rc<Cell<usize>>| -> threte::engine::ProductionAction {
+        let count = Rc::clone(count);
+        Box::new(move |_, _| count.set(count.get() + 1))
+    };
+    let rule = |conditions, count: &Rc<Cell<usize>>, metadata| Rule {
+        conditions,
+        production: counter(count),
+        bindings: vec![],
+        metadata,
+    };
+
+    let old = Rc::new(Cell::new(0));
+    let mut engine = Engine::default();
+    let a = engine
+        .add_rule(rule(vec![c1(), c2(), c3()], &old, RuleMetadata::named("a")))
+        .unwrap();
+    let b = engine
+        .add_rule(rule(vec![c1(), c2(), c6()], &old, RuleMetadata::named("b")))
+        .unwrap();
+    let c = engine
+        .add_rule(rule(vec![c4()], &old, RuleMetadata::default()))
+        .unwrap();
+    let unnamed = engine
+        .add_rule(rule(vec![c5()], &old, RuleMetadata::default()))
+        .unwrap();
+
+    engine.rete.add_wme(Wme::new(W1));
+    engine.rete.add_wme(Wme::new(W5));
+    engine.activate_productions();
+
+    // Pending activation of a
+    engine.rete.add_wme(Wme::new(W9));
+    let a_parent = engine.rete.productions[&a].borrow().parent().unwrap();
+
+    let (new_a, new_b, new_d) = (
+        Rc::new(Cell::new(0)),
+        Rc::new(Cell::new(0)),
+        Rc::new(Cell::new(0)),
+    );
+
+    // Invalid sets of rules are rejected before anything gets replaced
+    assert_eq!(
+        engine
+            .replace_rules(vec![
+                rule(vec![c1()], &new_d, RuleMetadata::named("d")),
+                rule(vec![c4()], &new_d, RuleMetadata::named("d")),
+            ])
+            .unwrap_err(),
+        RuleError::DuplicateName("d".into())
+    );
+    assert!(matches!(
+        engine.replace_rules(vec![
+            rule(vec![c1()], &new_d, RuleMetadata::named("d")),
+            rule(
+                vec![Condition::new_negative([V_X, C_ON, V_Y]), c1()],
+                &new_d,
+                RuleMetadata::named("e"),
+            ),
+        ]),
+        Err(RuleError::InvalidConditions(_))
+    ));
+    assert_eq!(engine.production_map.len(), 4);
+    assert!(engine.rule_id("d").is_none());
+    assert_eq!(engine.rule_id("b"), Some(b));
+
+    let reload = engine
+        .replace_rules(vec![
+            rule(vec![c1(), c2(), c3()], &new_a, RuleMetadata::named("a")),
+            rule(vec![c1(), c2(), c3()], &new_b, RuleMetadata::named("b")),
+            rule(vec![c1()], &new_d, RuleMetadata::named("d")),
+            rule(vec![c5()], &old, RuleMetadata::default()),
+        ])
+        .unwrap();
+
+    assert_eq!(reload.unchanged.len(), 2);
+    assert!(reload.unchanged.contains(&a) && reload.unchanged.contains(&unnamed));
+    assert_eq!(reload.changed, vec![b]);
+    assert_eq!(reload.removed, vec![c]);
+    assert_eq!(reload.added.len(), 2);
+
+    assert!(!engine.rete.productions.contains_key(&b));
+    assert!(!engine.rete.productions.contains_key(&c));
+    assert_ne!(engine.rule_id("b"), Some(b));
+
+    // The unchanged rule keeps its nodes and its pending activation, which runs the new action
+    assert!(Rc::ptr_eq(
+        &engine.rete.productions[&a].borrow().parent().unwrap(),
+        &a_parent
+    ));
+
+    engine.activate_productions();
+    assert_eq!(old.get(), 0);
+    assert_eq!(new_a.get(), 1);
+    assert_eq!(new_b.get(), 1);
+    assert_eq!(new_d.get(), 1);
+
+    reset();
+}
+
+/// Returns the WME IDs of every match of the production, sorted.
+#[cfg(feature = "serde")]
+fn production_matches(rete: &Rete, production: usize) -> Vec<Vec<usize>> {
+    let tokens = rete.productions[&production]
+        .borrow()
+        .tokens()
+        .iter()
+        .map(|token| token.borrow().id())
+        .collect::<Vec<_>>();
+
+    let mut matches = tokens
+        .into_iter()
+        .map(|token| {
+            let explanation = rete
+                .explain(&Activation::Fired {
+                    production,
+                    token,
+                    name: None,
+                })
+                .unwrap();
+            explanation
+                .conditions
+                .into_iter()
+                .filter_map(|condition| match condition {
+                    ConditionExplanation::Matched { wme_id, .. } => Some(wme_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snapshot_restore() {
+    use threte::rete::snapshot::{RestoreError, Snapshot};
+
+    let smaller = Predicate::new(|values| values[0] < values[1]);
+
+    let mut rete = Rete::new(ReteConfig {
+        set_semantics: true,
+    });
+    let (tx, rx) = channel();
+    let productions = productions(tx.clone());
+    let ids = productions
+        .into_iter()
+        .map(|production| rete.add_production(production).unwrap())
+        .collect::<Vec<_>>();
+    let with_test = rete
+        .add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_SIZE, V_A]),
+                Condition::new_positive([V_Y, C_SIZE, V_B]),
+                Condition::new_test(smaller.clone(), vec![3, 4]),
+            ],
+            tx,
+        ))
+        .unwrap();
+
+    for wme in [W1, W2, W3, W4, W5, W6, W7, W8, W9, W1] {
+        rete.add_wme(Wme::new(wme));
+    }
+    rete.add_wme(Wme::new([B1, SIZE, 1]));
+    rete.add_wme(Wme::new([B2, SIZE, 2]));
+    assert_production_set_size(&rx, 2);
+
+    let json = serde_json::to_string(&rete.snapshot()).unwrap();
+    let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(snapshot, rete.snapshot());
+
+    assert_eq!(
+        Rete::restore(snapshot.clone(), channel().0, &[]).unwrap_err(),
+        RestoreError::UnresolvedPredicate {
+            production: with_test,
+            predicate: smaller.clone(),
+        }
+    );
+
+    let (tx, rx) = channel();
+    let restored = Rete::restore(snapshot, tx, &[smaller]).unwrap();
+
+    // Matches get re-derived without firing again
+    assert!(rx.try_recv().is_err());
+
+    assert_eq!(restored.working_memory.len(), rete.working_memory.len());
+    for id in ids.into_iter().chain([with_test]) {
+        assert_eq!(
+            production_matches(&restored, id),
+            production_matches(&rete, id)
+        );
+    }
+
+    // W1 was added twice
+    let w1 = restored.wme_set[W1.as_slice()];
+    assert_eq!(w1.1, 2);
+
+    // New IDs do not collide with restored ones
+    let mut restored = restored;
+    let id = restored.add_wme(Wme::new([B4, COLOR, RED]));
+    assert!(!rete.working_memory.contains_key(&id));
+
+    // Named predicates are stored by name, so predicates created anew resolve them no matter
+    // how many predicates were created before
+    let named = Predicate::named("smaller", |values| values[0] < values[1]);
+    let mut rete = Rete::default();
+    let with_named = rete
+        .add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_SIZE, V_A]),
+                Condition::new_positive([V_Y, C_SIZE, V_B]),
+                Condition::new_test(named, vec![3, 4]),
+            ],
+            channel().0,
+        ))
+        .unwrap();
+    rete.add_wme(Wme::new([B1, SIZE, 1]));
+    rete.add_wme(Wme::new([B2, SIZE, 2]));
+    let json = serde_json::to_string(&rete.snapshot()).unwrap();
+    assert!(json.contains("\"smaller\""), "{json}");
+
+    for _ in 0..3 {
+        Predicate::new(|_| false);
+    }
+    let fresh = Predicate::named("smaller", |values| values[0] < values[1]);
+    let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+    let restored = Rete::restore(snapshot, channel().0, &[fresh]).unwrap();
+    assert_eq!(
+        production_matches(&restored, with_named),
+        production_matches(&rete, with_named)
+    );
+
+    reset();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn engine_snapshot_restore() {
+    use std::collections::HashMap;
+    use threte::engine::{EngineSnapshot, ProductionAction};
+
+    const UNSTABLE: usize = 40;
+    const FACT: [usize; 3] = [TABLE, UNSTABLE, TABLE];
+
+    let action = || -> ProductionAction {
+        Box::new(|e, _| {
+            e.add_logical_wme(Wme::new(FACT));
+        })
+    };
+
+    let mut engine = Engine::default();
+    let rule = engine
+        .add_rule(Rule {
+            conditions: vec![
+                Condition::new_positive([V_X, C_ON, V_Y]),
+                Condition::new_positive([V_Y, C_COLOR, C_BLUE]),
+            ],
+            production: action(),
+            bindings: vec![0],
+            metadata: RuleMetadata::default(),
+        })
+        .unwrap();
+
+    let b1 = 1 << (usize::BITS / 2);
+    engine.add_element(Block {
+        rete_id: b1,
+        id: 1,
+        positions: vec![Position::Table],
+        color: Color::Blue,
+    });
+    engine.add_element(Block {
+        rete_id: 2 << (usize::BITS / 2),
+        id: 2,
+        positions: vec![Position::On(b1)],
+        color: Color::Red,
+    });
+    engine.activate_productions();
+    assert_eq!(engine.logical_facts.len(), 1);
+
+    let json = serde_json::to_string(&engine.snapshot()).unwrap();
+    let snapshot: EngineSnapshot = serde_json::from_str(&json).unwrap();
+
+    // The logical WME is persisted along with its support
+    assert_eq!(snapshot.rete.wmes.len(), engine.rete.working_memory.len());
+    assert_eq!(snapshot.logical_facts.len(), 1);
+    assert_eq!(snapshot.logical_facts[0].support.len(), 1);
+
+    assert!(Engine::restore(snapshot.clone(), HashMap::new(), &[]).is_err());
+
+    let mut restored = Engine::restore(snapshot, HashMap::from([(rule, action())]), &[]).unwrap();
+    assert_eq!(restored.elements, engine.elements);
+    assert_eq!(restored.production_map[&rule].bindings, vec![0]);
+
+    // The match does not fire again, the logical fact is restored as is
+    assert!(restored.production_queue.try_recv().is_err());
+    assert!(restored.logical_facts.contains_key(FACT.as_slice()));
+    assert_eq!(
+        restored.rete.working_memory.len(),
+        engine.rete.working_memory.len()
+    );
+
+    restored.remove_element(2);
+    restored.activate_productions();
+    assert!(restored.logical_facts.is_empty());
+
+    reset();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn fact_log_replay() {
+    use std::io::Write;
+    use threte::rete::{
+        time::{PseudoClock, SystemClock},
+        wal::{FactLog, LogEntry, ReplayError},
+    };
+
+    let path = std::env::temp_dir().join(format!("threte_fact_log_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let smaller = Predicate::named("smaller", |values| values[0] < values[1]);
+
+    let mut rete = Rete::default();
+    rete.set_log(FactLog::open(&path).unwrap());
+
+    let (tx, rx) = channel();
+    let mut ids = productions(tx.clone())
+        .into_iter()
+        .map(|production| rete.add_production(production).unwrap())
+        .collect::<Vec<_>>();
+    ids.push(
+        rete.add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_SIZE, V_A]),
+                Condition::new_positive([V_Y, C_SIZE, V_B]),
+                Condition::new_test(smaller.clone(), vec![3, 4]),
+            ],
+            tx,
+        ))
+        .unwrap(),
+    );
+
+    let wme_ids = [W1, W2, W3, W4, W5, W6, W7, W8, W9]
+        .into_iter()
+        .map(|wme| rete.add_wme(Wme::new(wme)))
+        .collect::<Vec<_>>();
+    rete.add_wme(Wme::new([B1, SIZE, 1]));
+    rete.add_wme(Wme::new([B2, SIZE, 2]));
+    rete.remove_wme(wme_ids[0]);
+
+    let removed = ids.remove(0);
+    assert!(rete.remove_production(removed));
+    assert_production_set_size(&rx, 2);
+
+    // Removing what does not exist is not recorded
+    assert!(!rete.remove_production(removed));
+    rete.remove_wme(wme_ids[0]);
+
+    let log = rete.take_log().unwrap();
+    let entries = FactLog::read(log.path()).unwrap();
+    assert_eq!(entries.len(), ids.len() + 1 + wme_ids.len() + 2 + 2);
+    assert_eq!(
+        entries.last(),
+        Some(&LogEntry::RemoveProduction { id: removed })
+    );
+
+    assert!(matches!(
+        Rete::replay(
+            &path,
+            ReteConfig::default(),
+            Rc::new(SystemClock),
+            channel().0,
+            &[]
+        ),
+        Err(ReplayError::Restore(_))
+    ));
+
+    // The named predicate gets resolved by a new one with the same name
+    Predicate::new(|_| false);
+    let smaller = Predicate::named("smaller", |values| values[0] < values[1]);
+
+    let (tx, _rx) = channel();
+    let replayed = Rete::replay(
+        &path,
+        ReteConfig::default(),
+        Rc::new(SystemClock),
+        tx,
+        &[smaller],
+    )
+    .unwrap();
+
+    assert!(replayed.log.is_none());
+    assert!(!replayed.productions.contains_key(&removed));
+    assert!(!replayed.working_memory.contains_key(&wme_ids[0]));
+
+    let mut wmes = replayed.working_memory.keys().copied().collect::<Vec<_>>();
+    let mut expected = rete.working_memory.keys().copied().collect::<Vec<_>>();
+    wmes.sort();
+    expected.sort();
+    assert_eq!(wmes, expected);
+
+    for id in ids {
+        assert_eq!(
+            production_matches(&replayed, id),
+            production_matches(&rete, id)
+        );
+    }
+
+    // Corrupted entries are reported with their line
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    writeln!(file, "{{\"AddWme\":").unwrap();
+    let Err(ReplayError::Parse { line, .. }) = Rete::replay(
+        &path,
+        ReteConfig::default(),
+        Rc::new(SystemClock),
+        channel().0,
+        &[],
+    ) else {
+        panic!("Expected a parse error")
+    };
+    assert_eq!(line, entries.len() + 1);
+    std::fs::remove_file(&path).unwrap();
+
+    // WMEs expired since the log was written are retracted after replaying it
+    let clock = Rc::new(PseudoClock::new(0));
+    let mut rete = Rete::default();
+    rete.set_clock(clock.clone());
+    rete.set_log(FactLog::open(&path).unwrap());
+    let short = rete.add_wme(Wme::new(W1).with_lifetime(10));
+    clock.advance(5);
+    let long = rete.add_wme(Wme::new(W2).with_lifetime(100));
+    rete.take_log();
+
+    let mut replayed = Rete::replay(
+        &path,
+        ReteConfig::default(),
+        Rc::new(PseudoClock::new(50)),
+        channel().0,
+        &[],
+    )
+    .unwrap();
+    assert!(!replayed.working_memory.contains_key(&short));
+    assert!(replayed.working_memory.contains_key(&long));
+    assert!(replayed.expire().is_empty());
+    std::fs::remove_file(&path).unwrap();
+
+    // Failing writes are kept instead of panicking, and stop the log
+    #[cfg(target_os = "linux")]
+    {
+        let mut rete = Rete::default();
+        rete.set_log(FactLog::open("/dev/full").unwrap());
+        assert!(rete.log_error().is_none());
+        let id = rete.add_wme(Wme::new(W1));
+        assert!(rete.working_memory.contains_key(&id));
+        assert!(rete.log_error().is_some());
+        rete.remove_wme(id);
+        assert!(rete.working_memory.is_empty());
+    }
+
+    reset();
+}
+
+#[test]
+fn load_rules_from_path() {
+    use std::cell::RefCell;
+    use threte::engine::loader::{ActionRegistry, LoadError};
+    use threte::parser::SymbolTable;
+
+    let dir = std::env::temp_dir().join(format!("threte_rules_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut symbols = SymbolTable::default();
+    for (name, value) in [
+        ("b1", B1),
+        ("b2", B2),
+        ("b3", B3),
+        ("on", ON),
+        ("color", COLOR),
+        ("left-of", LEFT_OF),
+        ("red", RED),
+        ("blue", BLUE),
+        ("table", TABLE),
+    ] {
+        symbols.define(name, value);
+    }
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    let mut actions = ActionRegistry::default();
+    let log = Rc::clone(&calls);
+    actions.register("log", move |_, values| {
+        log.borrow_mut().push(values.to_vec())
+    });
+    actions.register("paint", |engine, values| {
+        engine.rete.add_wme(Wme::new([values[0], COLOR, values[1]]));
+    });
+
+    std::fs::write(
+        dir.join("a.rules"),
+        "(defrule left-of-red \"A block left of a red one\"
+            (?x ^left-of ?y)
+            (?y ^color red)
+            => (log ?x ?y 7) (paint ?x red))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("b.rules"),
+        "(defrule red-unsupported
+            (?x ^color red)
+            -{ (?x ^on ?y) -(?y ^color blue) }
+            => (log ?x))",
+    )
+    .unwrap();
+    std::fs::write(dir.join("ignored.txt"), "not a rule").unwrap();
+
+    let mut engine = Engine::default();
+    let ids = engine
+        .load_rules_from_path(&dir, &actions, &mut symbols)
+        .unwrap();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(
+        engine
+            .rule_by_name("left-of-red")
+            .unwrap()
+            .metadata
+            .description
+            .as_deref(),
+        Some("A block left of a red one")
+    );
+
+    for wme in [W1, W2, W3, W5, W6, W7, W9] {
+        engine.rete.add_wme(Wme::new(wme));
+    }
+    engine.activate_productions();
+
+    // b2 only turns red through the first rule, b1 is on b3 which is not blue
+    let mut calls = calls.borrow().clone();
+    calls.sort();
+    assert_eq!(calls, vec![vec![B2], vec![B2, B3, 7], vec![B3]]);
+
+    // Loading the same rules again fails without adding anything
+    let errors = engine
+        .load_rules_from_path(dir.join("a.rules"), &actions, &mut symbols)
+        .unwrap_err();
+    assert!(matches!(errors[..], [LoadError::DuplicateRule { .. }]));
+
+    // All errors of all files are reported at once
+    std::fs::write(
+        dir.join("a.rules"),
+        "(defrule a (?x on ?y) => (unknown ?x))\n(defrule b (?x on) => (log ?z))",
+    )
+    .unwrap();
+    std::fs::write(dir.join("b.rules"), "(defrule c (?x on ?y) => (log ?x)").unwrap();
+
+    let mut engine = Engine::default();
+    let errors = engine
+        .load_rules_from_path(&dir, &actions, &mut symbols)
+        .unwrap_err();
+    assert_eq!(errors.len(), 3, "{errors:?}");
+    assert!(engine.production_map.is_empty());
+
+    // Unknown actions of the rules that parsed are reported along with the parse errors
+    let messages = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert!(
+        messages[0].ends_with("a.rules:1:26: rule a calls unknown action `unknown`"),
+        "{}",
+        messages[0]
+    );
+    assert!(
+        messages[1].ends_with(
+            "a.rules:2:28: variable `?z` is not bound by a positive condition of the rule"
+        ),
+        "{}",
+        messages[1]
+    );
+    assert!(
+        messages[2].ends_with("b.rules:1:34: expected `)`, found end of input"),
+        "{}",
+        messages[2]
+    );
+
+    std::fs::write(dir.join("b.rules"), "").unwrap();
+    std::fs::write(
+        dir.join("a.rules"),
+        "(defrule a (?x on ?y) => (unknown ?x))",
+    )
+    .unwrap();
+    let errors = engine
+        .load_rules_from_path(&dir, &actions, &mut symbols)
+        .unwrap_err();
+    assert_eq!(
+        errors[0].to_string(),
+        format!(
+            "{}:1:26: rule a calls unknown action `unknown`",
+            dir.join("a.rules").display()
+        )
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    reset();
+}
+
+#[test]
+fn batch_runner() {
+    let dir = std::env::temp_dir().join(format!("threte_batch_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    std::fs::write(
+        dir.join("facts"),
+        "(b1 ^on b2) (b2 ^color red) (b3 ^on b2) (b3 ^color blue)",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("paint.rules"),
+        "(defrule paint (?x ^on ?y) (?y ^color red) -(?x ^color blue)
+            => (assert ?x color blue))",
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_threte"))
+        .arg("run")
+        .arg(dir.join("facts"))
+        .arg(dir.join("paint.rules"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        output["fired"],
+        serde_json::json!([{ "rule": "paint", "bindings": { "x": "b1", "y": "b2" } }])
+    );
+    assert_eq!(
+        output["working_memory"][4],
+        serde_json::json!({ "id": 4, "fields": ["b1", "color", "blue"] })
+    );
+
+    std::fs::write(
+        dir.join("paint.rules"),
+        "(defrule paint (?x on) => (print ?y))",
+    )
+    .unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_threte"))
+        .arg("run")
+        .arg(dir.join("facts"))
+        .arg(dir.join("paint.rules"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("paint.rules:1:"));
+
+    // Rules that keep activating each other stop at the fire limit
+    std::fs::write(
+        dir.join("paint.rules"),
+        "(defrule again (?x ^on ?y) => (assert ?x on ?y))",
+    )
+    .unwrap();
+    let run = |max_fires: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_threte"))
+            .arg("run")
+            .arg("--max-fires")
+            .arg(max_fires)
+            .arg(dir.join("facts"))
+            .arg(dir.join("paint.rules"))
+            .output()
+            .unwrap()
+    };
+    let output = run("5");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Stopped after firing 5 activations"));
+
+    let output = run("many");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--max-fires"));
+
+    // Rules firing exactly as often as allowed succeed
+    std::fs::write(
+        dir.join("paint.rules"),
+        "(defrule on (?x ^on ?y) => (print ?x))",
+    )
+    .unwrap();
+    let output = run("2");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let output: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(output["fired"].as_array().unwrap().len(), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_facts() {
+    use serde_json::json;
+    use threte::engine::json::{JsonElement, JsonError};
+    use threte::parser::SymbolTable;
+
+    let mut symbols = SymbolTable::default();
+    symbols.define("on", ON);
+    symbols.define("color", COLOR);
+    symbols.define("red", RED);
+
+    let document = json!({
+        "name": "b1",
+        "color": "red",
+        "on": { "name": "b2", "on": { "name": "table" } },
+        "heavy": true,
+        "weight": 1.5,
+        "tags": ["a", ["b"], { "name": "c" }],
+        "missing": null,
+    });
+
+    let element = JsonElement::from_json(&document, &mut symbols).unwrap();
+    let symbol = |name: &str| symbols.get(name).unwrap();
+    let (b1, b2, table, tag) = (
+        element.id,
+        element.facts[3][2],
+        element.facts[5][2],
+        element.facts[9][2],
+    );
+
+    assert_eq!(symbols.name(b1), Some("#gen1"));
+    assert_eq!(
+        element.facts,
+        vec![
+            [b1, COLOR, RED],
+            [b1, symbol("heavy"), symbol("true")],
+            [b1, symbol("name"), symbol("b1")],
+            [b1, ON, b2],
+            [b2, symbol("name"), symbol("b2")],
+            [b2, ON, table],
+            [table, symbol("name"), symbol("table")],
+            [b1, symbol("tags"), symbol("a")],
+            [b1, symbol("tags"), symbol("b")],
+            [b1, symbol("tags"), tag],
+            [tag, symbol("name"), symbol("c")],
+            [b1, symbol("weight"), symbol("1.5")],
+        ]
+    );
+
+    // Generated identifiers do not collide with symbols interned from data
+    #[cfg(feature = "serde")]
+    {
+        #[derive(serde::Serialize)]
+        struct Block {
+            name: &'static str,
+            color: Option<&'static str>,
+            size: usize,
+        }
+
+        let element = JsonElement::from_serialize(
+            &Block {
+                name: "#gen5",
+                color: None,
+                size: 3,
+            },
+            &mut symbols,
+        )
+        .unwrap();
+        assert_eq!(symbols.name(element.id), Some("#gen5"));
+        assert_ne!(symbols.get("#gen5"), Some(element.id));
+        assert_eq!(
+            element.facts,
+            vec![
+                [
+                    element.id,
+                    symbols.get("name").unwrap(),
+                    symbols.get("#gen5").unwrap()
+                ],
+                [element.id, symbols.get("size").unwrap(), 3],
+            ]
+        );
+    }
+
+    assert!(matches!(
+        JsonElement::from_json(&json!([1, 2]), &mut symbols),
+        Err(JsonError::NotAnObject)
+    ));
+
+    // Elements get added and removed as a whole
+    let (tx, rx) = channel();
+    let mut engine = Engine::default();
+    engine
+        .rete
+        .add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_ON, V_Y]),
+                Condition::new_positive([V_Y, C_ON, V_Z]),
+            ],
+            tx,
+        ))
+        .unwrap();
+
+    let element = JsonElement::from_json(&document, &mut symbols).unwrap();
+    let id = element.id;
+    engine.add_element(element);
+    assert_eq!(engine.elements[&id].len(), 12);
+    assert_production_set_size(&rx, 1);
+
+    engine.remove_element(id);
+    assert!(engine.rete.working_memory.is_empty());
+    assert!(matches!(rx.try_recv(), Ok(Activation::Retracted { .. })));
+
+    reset();
+}
+
+#[test]
+fn queries() {
+    use threte::rete::query::QueryError;
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+
+    let red_on_table = [
+        Condition::new_positive([V_X, C_ON, C_TABLE]),
+        Condition::new_positive([V_X, C_COLOR, C_RED]),
+    ];
+    let production = rete
+        .add_production(Production::new(&red_on_table, tx))
+        .unwrap();
+    rete.add_query("red-on-table", &red_on_table).unwrap();
+    rete.add_query(
+        "left-of-unstacked",
+        &[
+            Condition::new_positive([V_X, C_LEFT_OF, V_Y]),
+            Condition::new_negative([V_A, C_ON, V_X]),
+        ],
+    )
+    .unwrap();
+
+    // Identical conditions share the whole path
+    assert!(Rc::ptr_eq(
+        &rete.productions[&production].borrow().parent().unwrap(),
+        &rete.queries["red-on-table"].borrow().parent().unwrap()
+    ));
+
+    // Queries that cannot be added leave the network unchanged
+    assert_eq!(
+        rete.add_query("red-on-table", &red_on_table),
+        Err(QueryError::DuplicateName("red-on-table".into()))
+    );
+    assert!(matches!(
+        rete.add_query(
+            "unbound",
+            &[
+                Condition::new_negative([V_A, C_ON, V_X]),
+                Condition::new_positive([V_X, C_LEFT_OF, V_Y]),
+            ]
+        ),
+        Err(QueryError::InvalidConditions(_))
+    ));
+    assert_eq!(rete.queries.len(), 2);
+
+    let ids = [W1, W2, W3, W4, W5, W6, W7, W8, W9]
+        .into_iter()
+        .map(|wme| rete.add_wme(Wme::new(wme)))
+        .collect::<Vec<_>>();
+
+    let matches = rete.query("red-on-table").unwrap().collect::<Vec<_>>();
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].get(0), Some(B3));
+    assert_eq!(matches[0].wmes, vec![ids[7], ids[8]]);
+
+    // Queries do not cause activations, only the production does
+    assert_production_set_size(&rx, 1);
+
+    let unstacked = |rete: &Rete| {
+        let mut blocks = rete
+            .query("left-of-unstacked")
+            .unwrap()
+            .map(|m| (m.get(0).unwrap(), m.get(1).unwrap()))
+            .collect::<Vec<_>>();
+        blocks.sort();
+        blocks
+    };
+    assert_eq!(unstacked(&rete), vec![]);
+
+    // Matches are kept up to date as WMEs change
+    rete.remove_wme(ids[0]);
+    assert_eq!(unstacked(&rete), vec![(B2, B3)]);
+    rete.remove_wme(ids[1]);
+    assert_eq!(unstacked(&rete), vec![(B2, B3), (B3, B4)]);
+    rete.remove_wme(ids[8]);
+    assert_eq!(rete.query("red-on-table").unwrap().count(), 0);
+
+    assert!(rete.query("missing").is_none());
+
+    assert!(rete.remove_query("red-on-table"));
+    assert!(!rete.remove_query("red-on-table"));
+    assert!(rete.productions[&production].borrow().parent().is_some());
+
+    rete.add_wme(Wme::new(W9));
+    assert_production_set_size(&rx, 1);
+
+    // Forks keep the queries along with their matches, which then change independently
+    let (fork_tx, fork_rx) = channel();
+    let mut fork = rete.fork(fork_tx);
+    assert_eq!(unstacked(&fork.rete), vec![(B2, B3), (B3, B4)]);
+    fork.rete.add_wme(Wme::new(W2));
+    assert_eq!(unstacked(&fork.rete), vec![(B2, B3)]);
+    assert_eq!(unstacked(&rete), vec![(B2, B3), (B3, B4)]);
+    assert!(fork_rx.try_recv().is_err());
+    assert!(fork.rete.remove_query("left-of-unstacked"));
+    assert!(rete.query("left-of-unstacked").is_some());
+
+    // Snapshots keep the queries too
+    #[cfg(feature = "serde")]
+    {
+        use threte::rete::snapshot::Snapshot;
+
+        let json = serde_json::to_string(&rete.snapshot()).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.queries.len(), 1);
+
+        let (tx, rx) = channel();
+        let mut restored = Rete::restore(snapshot, tx, &[]).unwrap();
+        assert_eq!(unstacked(&restored), unstacked(&rete));
+        assert_eq!(
+            restored.queries["left-of-unstacked"].borrow().id(),
+            rete.queries["left-of-unstacked"].borrow().id()
+        );
+        assert_eq!(
+            restored.add_query("left-of-unstacked", &red_on_table),
+            Err(QueryError::DuplicateName("left-of-unstacked".into()))
+        );
+
+        restored.add_wme(Wme::new(W2));
+        assert_eq!(unstacked(&restored), vec![(B2, B3)]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    reset();
+}
+
+#[test]
+fn find_by_pattern() {
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+    rete.add_production(Production::new(&[c1()], tx)).unwrap();
+
+    let ids = [W1, W2, W3, W4, W5, W6, W7, W8, W9]
+        .into_iter()
+        .map(|wme| rete.add_wme(Wme::new(wme)))
+        .collect::<Vec<_>>();
+    let size = rete.add_wme(Wme::new([B1, SIZE, 2, 3]));
+
+    // Served by the memory of c1
+    assert_eq!(
+        rete.find([None, Some(ON), None]),
+        vec![ids[0], ids[1], ids[3], ids[7]]
+    );
+
+    // Served by the index
+    assert_eq!(
+        rete.find([None, Some(COLOR), Some(RED)]),
+        vec![ids[2], ids[8]]
+    );
+    assert_eq!(
+        rete.find([Some(B3), None, None]),
+        vec![ids[6], ids[7], ids[8]]
+    );
+    assert_eq!(rete.find([Some(B1), Some(SIZE), None, None]), vec![size]);
+    assert!(rete.find([Some(B1), Some(SIZE), None]).is_empty());
+    assert!(rete.find([Some(B6), None, None]).is_empty());
+    assert_eq!(rete.find([None, None, None]).len(), 9);
+    assert_eq!(rete.find(vec![None; 4]), vec![size]);
+
+    rete.remove_wme(ids[0]);
+    rete.remove_wme(ids[2]);
+    assert_eq!(
+        rete.find([None, Some(ON), None]),
+        vec![ids[1], ids[3], ids[7]]
+    );
+    assert_eq!(rete.find([None, Some(COLOR), Some(RED)]), vec![ids[8]]);
+    assert_eq!(rete.find([None, None, Some(RED)]), vec![ids[8]]);
+
+    rete.remove_wme(size);
+    assert!(!rete.wme_index.contains_key(&(3, 3)));
+
+    reset();
+}
+
+#[test]
+fn network_validation() {
+    use threte::rete::{explain::NodeRef, node::Node};
+
+    let mut rete = Rete::default();
+    let (tx, rx) = channel();
+
+    let stacked_left_of = rete
+        .add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_ON, V_Y]),
+                Condition::new_positive([V_Y, C_LEFT_OF, V_Z]),
+            ],
+            tx.clone(),
+        ))
+        .unwrap();
+    rete.assert_valid();
+
+    // Emptying the alpha memory left unlinks the join, it must get relinked once
+    // the memory fills up again so later tokens reach it
+    rete.add_wme(Wme::new(W1));
+    let left_of = rete.add_wme(Wme::new(W5));
+    rete.remove_wme(left_of);
+    rete.assert_valid();
+    rete.add_wme(Wme::new(W5));
+    rete.assert_valid();
+    rete.add_wme(Wme::new([B4, ON, B2]));
+    rete.assert_valid();
+    assert_eq!(
+        rete.productions[&stacked_left_of].borrow().tokens().len(),
+        2
+    );
+
+    // A negative node built with tokens stays right linked as long as it holds any
+    let w7 = rete.add_wme(Wme::new(W7));
+    let unstacked = rete
+        .add_production(Production::new(
+            &[
+                Condition::new_positive([V_X, C_LEFT_OF, V_Y]),
+                Condition::new_negative([V_A, C_ON, V_X]),
+            ],
+            tx,
+        ))
+        .unwrap();
+    rete.assert_valid();
+    assert_eq!(rete.productions[&unstacked].borrow().tokens().len(), 1);
+
+    rete.remove_wme(w7);
+    rete.assert_valid();
+    rete.add_wme(Wme::new(W7));
+    rete.add_wme(Wme::new(W2));
+    rete.assert_valid();
+    assert!(rete.productions[&unstacked].borrow().tokens().is_empty());
+
+    drop(rx);
+
+    // Violations get reported rather than panicking, naming nodes by kind and ID
+    let join = rete.productions[&stacked_left_of]
+        .borrow()
+        .parent()
+        .unwrap();
+    let flip = |join: &threte::rete::ReteNode| {
+        let Node::Join(ref mut join) = *join.borrow_mut() else {
+            panic!("Expected a join node")
+        };
+        join.right_linked = !join.right_linked;
+    };
+    flip(&join);
+    let violations = rete.validate().unwrap_err();
+    let expected = NodeRef {
+        kind: "join",
+        id: join.borrow().id(),
+    };
+    assert!(
+        matches!(violations[..], [Violation::RightLinkMismatch { node, .. }] if node == expected),
+        "{violations:?}"
+    );
+    assert!(violations[0]
+        .to_string()
+        .starts_with(&format!("join {} is right", expected.id)));
+    flip(&join);
+
+    rete.wme_index.clear();
+    let violations = rete.validate().unwrap_err();
+    assert!(violations
+        .iter()
+        .all(|violation| matches!(violation, Violation::IndexMismatch { .. })));
+    assert_eq!(violations.len(), rete.working_memory.len());
+
+    reset();
+}
+
+/// Xorshift generator so the randomized tests are reproducible without extra dependencies.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())].clone()
+    }
+}
+
+const BLOCKS: [usize; 4] = [B1, B2, B3, B4];
+
+fn random_wme(random: &mut Random) -> [usize; 3] {
+    let attribute = random.pick(&[ON, ON, COLOR, LEFT_OF]);
+    let value = match attribute {
+        COLOR => random.pick(&[RED, BLUE]),
+        _ => random.pick(&[B1, B2, B3, B4, TABLE]),
+    };
+    [random.pick(&BLOCKS), attribute, value]
+}
+
+fn random_pattern(random: &mut Random) -> Vec<ConditionTest> {
+    let variables = [V_X, V_Y, V_Z];
+    let attribute = random.pick(&[C_ON, C_COLOR, C_LEFT_OF]);
+    let id = if random.below(4) == 0 {
+        ConditionTest::Constant(random.pick(&BLOCKS))
+    } else {
+        random.pick(&variables)
+    };
+    let value = match (attribute, random.below(3)) {
+        (C_COLOR, _) => random.pick(&[C_RED, C_BLUE, V_Z]),
+        (_, 0) => random.pick(&[C_TABLE, ConditionTest::Constant(B2)]),
+        _ => random.pick(&variables),
+    };
+    vec![id, attribute, value]
+}
+
+/// Generates conditions until they are valid, mixing in negations and NCCs.
+fn random_conditions(random: &mut Random) -> Vec<Condition> {
+    loop {
+        let mut conditions = vec![];
+        for _ in 0..1 + random.below(3) {
+            let condition = match random.below(6) {
+                0 => Condition::new_negative(random_pattern(random)),
+                1 => Condition::new_ncc(
+                    (0..1 + random.below(2))
+                        .map(|_| Condition::new_positive(random_pattern(random)))
+                        .collect(),
+                ),
+                _ => Condition::new_positive(random_pattern(random)),
+            };
+            conditions.push(condition);
+        }
+        if validate_conditions(&conditions).is_ok() {
+            return conditions;
+        }
+    }
+}
+
+fn differential_conditions() -> Vec<Vec<Condition>> {
+    let lower = Predicate::new(|values| values[0] < values[1]);
+    vec![
+        vec![
+            Condition::new_positive([V_X, C_ON, V_Y]),
+            Condition::new_positive([V_Y, C_LEFT_OF, V_Z]),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_ON, V_Y]),
+            Condition::new_negative([V_Z, C_ON, V_X]),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_COLOR, C_RED]),
+            Condition::new_negative([V_X, C_ON, C_TABLE]),
+            Condition::new_positive([V_X, C_LEFT_OF, V_Y]),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_ON, V_Y]),
+            Condition::new_ncc(vec![
+                Condition::new_positive([V_Y, C_COLOR, C_RED]),
+                Condition::new_positive([V_Y, C_LEFT_OF, V_Z]),
+            ]),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_ON, V_Y]),
+            Condition::new_ncc(vec![
+                Condition::new_positive([V_Y, C_ON, V_Z]),
+                Condition::new_ncc(vec![Condition::new_positive([V_Z, C_COLOR, C_RED])]),
+            ]),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_COLOR, C_BLUE]),
+            Condition::new_forall(
+                [V_X, C_ON, V_Y],
+                vec![Condition::new_positive([V_Y, C_COLOR, C_BLUE])],
+            ),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_LEFT_OF, V_Y]),
+            Condition::new_test(lower, vec![0, 1]),
+            Condition::new_negative([V_X, C_ON, V_Y]),
+        ],
+        vec![
+            Condition::new_positive([V_X, C_ON, V_Y]).with_alpha_test(AlphaTest::NotEqual {
+                field: 2,
+                value: TABLE,
+            }),
+            Condition::new_positive([V_Y, C_ON, V_X]),
+        ],
+        vec![Condition::new_positive([V_X, C_ON, V_X])],
+        vec![
+            Condition::new_negative([C_ID_B1, C_ON, C_TABLE]),
+            Condition::new_positive([V_X, C_COLOR, V_Y]),
+        ],
+    ]
+}
+
+const C_ID_B1: ConditionTest = ConditionTest::Constant(B1);
+
+fn assert_matches_reference(rete: &Rete, productions: &[(usize, Vec<Condition>)], step: usize) {
+    rete.assert_valid();
+    for (id, conditions) in productions {
+        assert_eq!(
+            rete.production_matches(*id).unwrap(),
+            rete.reference_matches(conditions),
+            "production {id} with {conditions:?} diverged at step {step}"
+        );
+    }
+}
+
+#[test]
+fn reference_matcher() {
+    let mut rete = Rete::default();
+    let (tx, _rx) = channel();
+
+    let conditions = [
+        Condition::new_positive([V_X, C_ON, V_Y]),
+        Condition::new_negative([V_Y, C_ON, V_Z]),
+        Condition::new_ncc(vec![Condition::new_positive([V_X, C_COLOR, C_BLUE])]),
+    ];
+    let ids = [W1, W2, W3, W4, W5, W6, W7, W8, W9]
+        .into_iter()
+        .map(|wme| rete.add_wme(Wme::new(wme)))
+        .collect::<Vec<_>>();
+
+    // Only B2 and B3 are on something that is on nothing, and B2 is blue
+    let matches = rete.reference_matches(&conditions);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].wmes, vec![ids[7]]);
+    assert_eq!(matches[0].bindings[&0], B3);
+    assert_eq!(matches[0].bindings[&1], TABLE);
+
+    let production = rete
+        .add_production(Production::new(&conditions, tx))
+        .unwrap();
+    assert_eq!(rete.production_matches(production).unwrap(), matches);
+    assert!(rete.production_matches(production + 1).is_none());
+
+    reset();
+}
+
+#[test]
+fn differential_matching() {
+    // Thousands of operations, tracing them would drown the output
+    threte::set_tracing(false);
+    for seed in 1..=8u64 {
+        let mut random = Random(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let mut rete = Rete::new(ReteConfig {
+            set_semantics: seed % 2 == 0,
+        });
+        let (tx, _rx) = channel();
+
+        let mut conditions = differential_conditions();
+        conditions.extend((0..6).map(|_| random_conditions(&mut random)));
+
+        // Half of the productions exist from the start, the rest join an already filled memory
+        let late = conditions.split_off(conditions.len() / 2);
+        let mut productions = conditions
+            .into_iter()
+            .map(|conditions| {
+                let id = rete
+                    .add_production(Production::new(&conditions, tx.clone()))
+                    .unwrap();
+                (id, conditions)
+            })
+            .collect::<Vec<_>>();
+
+        let mut wmes = vec![];
+        for step in 0..250 {
+            if step == 100 {
+                for conditions in late.iter() {
+                    let id = rete
+                        .add_production(Production::new(conditions, tx.clone()))
+                        .unwrap();
+                    productions.push((id, conditions.clone()));
+                }
+            }
+
+            if step % 75 == 74 {
+                let (id, conditions) = productions.remove(random.below(productions.len()));
+                rete.remove_production(id);
+                let id = rete
+                    .add_production(Production::new(&conditions, tx.clone()))
+                    .unwrap();
+                productions.push((id, conditions));
+            }
+
+            // Forks continue matching where the Rete they were forked from left off
+            if step % 60 == 59 {
+                rete = rete.fork(tx.clone()).rete;
+                assert_matches_reference(&rete, &productions, step);
+            }
+
+            if wmes.is_empty() || random.below(5) < 3 {
+                wmes.push(rete.add_wme(Wme::new(random_wme(&mut random))));
+            } else {
+                let id = wmes.swap_remove(random.below(wmes.len()));
+                rete.remove_wme(id);
+            }
+
+            assert_matches_reference(&rete, &productions, step);
+        }
+
+        // Draining working memory leaves only the matches of pure negations
+        while let Some(id) = wmes.pop() {
+            rete.remove_wme(id);
+        }
+        assert_matches_reference(&rete, &productions, usize::MAX);
+    }
 
     reset();
 }